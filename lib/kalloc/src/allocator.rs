@@ -1,6 +1,6 @@
 use mem_utils::{VirtAddr, Address, PGSIZE, PHYSTOP, end};
+use core::cmp::max;
 use core::ptr::Unique;
-use core::mem;
 
 pub struct Allocator {
     pub start: Range,
@@ -34,30 +34,14 @@ impl Range {
         unsafe { self.next.as_mut().unwrap().as_mut() }
     }
 
-    // allocate some subset of the range, or possibly the entire range,
-    // but leave the Range intact other than updating its slice
-    fn allocate_from_range(&mut self, num_pages: usize) -> *mut u8 {
-        assert!(self.size >= num_pages);
-
-        trace!("Allocating from within range {:?} {}",
-               &self as *const _,
-               self.size);
-
-        let s = self.size + 1;
-        // get start address
-        let allocation = unsafe { self.offset((s - num_pages) as isize) };
-        self.size -= num_pages;
-        allocation
-    }
-
-    // consume the entire range's pages (and also the Range struct itself)
-    fn allocate_entire_range(mut range: Unique<Range>) -> *mut u8 {
-        unsafe {
-            trace!("Allocating entire range {:?} {}",
-                   range.as_ref() as *const _,
-                   range.as_ref().size);
-        }
-        unsafe { range.as_mut() as *mut Range as *mut u8 }
+    // The highest address in the range where `num_pages` pages aligned to `align` would fit, if
+    // there is one.  Allocating from the end means that page-aligned allocations leave the Range
+    // struct where it is.
+    fn aligned_start(&mut self, num_pages: usize, align: usize) -> Option<usize> {
+        let (base, top) = unsafe { (self.base_addr() as usize, self.end_addr() as usize) };
+        top.checked_sub(num_pages * PGSIZE)
+            .map(|start| start & !(align - 1))
+            .and_then(|start| if start >= base { Some(start) } else { None })
     }
 }
 
@@ -122,34 +106,107 @@ impl Allocator {
         (PGSIZE + size - 1) / PGSIZE
     }
 
-    pub fn allocate(&mut self, size: usize) -> Result<*mut u8, &'static str> {
+    /// Allocates enough pages for `size` bytes, starting at a multiple of `align`.  Alignments
+    /// smaller than a page get a page anyway.
+    pub fn allocate(&mut self, size: usize, align: usize) -> Result<*mut u8, &'static str> {
         self.verify();
 
         let mut prev: &mut Range = &mut self.start;
         let requested_pages = Self::size_to_pages(size);
+        let align = max(align, PGSIZE);
 
         // this code inspired by Phillip Oppermann's Linked List Allocator
         // https://github.com/phil-opp/linked-list-allocator/blob/master/src/hole.rs
         // available under the terms of the MIT License
         loop {
-            let next_size = prev.next.map(|ref mut n| unsafe { n.as_ref().size });
-            match next_size {
-                Some(s) if s >= requested_pages => {
-                    let allocation = prev.unwrap_next().allocate_from_range(requested_pages);
-                    self.length -= requested_pages;
-                    return Ok(allocation);
-                }
+            let start = match prev.next {
+                Some(mut n) => unsafe { n.as_mut().aligned_start(requested_pages, align) },
+                None => return Err("Could not find large enough contiguous area"),
+            };
 
-                Some(s) if s + 1 == requested_pages => {
-                    // Update the linked list so that prev's next points to current's next
-                    let next_next = prev.unwrap_next().next.take();
-                    let next = mem::replace(&mut prev.next, next_next);
-                    self.length -= requested_pages;
-                    return Ok(Range::allocate_entire_range(next.unwrap()));
+            if let Some(start) = start {
+                unsafe { Self::carve(prev, start, requested_pages) };
+                self.length -= requested_pages;
+                return Ok(start as *mut u8);
+            }
+            prev = Self::move_helper(prev).unwrap_next();
+        }
+    }
+
+    // Takes `num_pages` pages at `start` out of the range after `prev`.  Whatever is left after
+    // them becomes a range of its own, and whatever is left before them stays in the old one; if
+    // nothing is, the old range is unlinked, since its struct is in the pages being handed out.
+    unsafe fn carve(prev: &mut Range, start: usize, num_pages: usize) {
+        let mut range = prev.next.unwrap();
+        let base = range.as_mut().base_addr() as usize;
+        let top = range.as_mut().end_addr() as usize;
+        let tail = start + num_pages * PGSIZE;
+        trace!("Allocating {} pages at {:#x} from range {:#x}", num_pages, start, base);
+
+        if tail < top {
+            let mut rest = Unique::new_unchecked(tail as *mut Range);
+            *rest.as_mut() = Range {
+                next: range.as_mut().next.take(),
+                size: (top - tail) / PGSIZE - 1,
+            };
+            range.as_mut().next = Some(rest);
+        }
+
+        if start == base {
+            prev.next = range.as_mut().next.take();
+        } else {
+            range.as_mut().size = (start - base) / PGSIZE - 1;
+        }
+    }
+
+    /// Grows or shrinks the allocation of `old_pages` pages at `ptr` to `new_pages` without
+    /// moving it.  Shrinking always works.  Growing only works if the pages right after the
+    /// allocation are free, and returns false otherwise.
+    pub unsafe fn resize_in_place(&mut self,
+                                  ptr: *mut u8,
+                                  old_pages: usize,
+                                  new_pages: usize)
+                                  -> bool {
+        let old_end = ptr as usize + old_pages * PGSIZE;
+        if new_pages <= old_pages {
+            if new_pages < old_pages {
+                let new_end = ptr as usize + new_pages * PGSIZE;
+                self.free_range(VirtAddr::new(new_end), VirtAddr::new(old_end));
+            }
+            return true;
+        }
+
+        self.verify();
+        let extra = new_pages - old_pages;
+        let mut prev: &mut Range = &mut self.start;
+        loop {
+            let (base, pages) = match prev.next {
+                Some(n) => (n.as_ref() as *const Range as usize, n.as_ref().size + 1),
+                None => return false,
+            };
+
+            if base == old_end {
+                if pages < extra {
+                    return false;
                 }
+                Self::carve(prev, old_end, extra);
+                self.length -= extra;
+                return true;
+            } else if base > old_end {
+                // the list is sorted, so there's no free range right after the allocation
+                return false;
+            }
+            prev = Self::move_helper(prev).unwrap_next();
+        }
+    }
 
-                Some(_) => prev = Self::move_helper(prev).unwrap_next(),
-                None => return Err("Could not find large enough contiguous area"),
+    /// Calls `f` with the address and length in pages of each free range, in address order
+    pub fn for_each_free_range<F: FnMut(usize, usize)>(&self, mut f: F) {
+        let mut next = self.start.next;
+        while let Some(n) = next {
+            unsafe {
+                f(n.as_ref() as *const Range as usize, n.as_ref().size + 1);
+                next = n.as_ref().next;
             }
         }
     }
@@ -167,8 +224,11 @@ impl Allocator {
         while let Some(mut n) = next {
             unsafe {
                 let addr = VirtAddr::new(n.as_ref() as *const _ as usize);
-                assert!(addr > kernel_start);
-                assert!(addr.to_phys() < PHYSTOP);
+                // tests on the host hand out memory from wherever the host put it
+                if cfg!(not(test)) {
+                    assert!(addr > kernel_start);
+                    assert!(addr.to_phys() < PHYSTOP);
+                }
                 size += n.as_ref().size + 1;
                 next = n.as_ref().next;
                 if let Some(s) = n.as_ref().next {
@@ -182,3 +242,125 @@ impl Allocator {
         assert_eq!(size, self.length);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::ptr;
+    use std::vec::Vec;
+
+    const P: usize = PGSIZE;
+
+    // Pages for an allocator to hand out, starting on a 64-page boundary so that large
+    // alignments land in known places.  The allocator lives two pages before them, which keeps
+    // its list head below every free range, as it is in the kernel, without the head looking
+    // like a range that ends where they start.
+    struct Heap {
+        memory: Vec<u8>,
+    }
+
+    impl Heap {
+        fn new(pages: usize) -> Heap {
+            let mut heap = Heap { memory: vec![0; (pages + 66) * P] };
+            let base = heap.base();
+            unsafe {
+                ptr::write(heap.allocator(),
+                           Allocator {
+                               start: Range {
+                                   next: None,
+                                   size: 0,
+                               },
+                               length: 0,
+                           });
+                heap.allocator().free_range(VirtAddr::new(base), VirtAddr::new(base + pages * P));
+            }
+            heap
+        }
+
+        fn base(&self) -> usize {
+            let start = self.memory.as_ptr() as usize + 2 * P;
+            (start + 64 * P - 1) & !(64 * P - 1)
+        }
+
+        fn allocator(&mut self) -> &mut Allocator {
+            unsafe { &mut *((self.base() - 2 * P) as *mut Allocator) }
+        }
+
+        // the free ranges, as (pages from the base, length in pages)
+        fn free_ranges(&mut self) -> Vec<(usize, usize)> {
+            let base = self.base();
+            let mut ranges = Vec::new();
+            self.allocator()
+                .for_each_free_range(|addr, pages| ranges.push(((addr - base) / P, pages)));
+            ranges
+        }
+    }
+
+    #[test]
+    fn page_aligned_allocations_come_from_the_top() {
+        let mut heap = Heap::new(64);
+        let base = heap.base();
+        assert_eq!(heap.allocator().allocate(100, 8), Ok((base + 63 * P) as *mut u8));
+        assert_eq!(heap.allocator().allocate(3 * P, P), Ok((base + 60 * P) as *mut u8));
+        assert_eq!(heap.free_ranges(), vec![(0, 60)]);
+        assert_eq!(heap.allocator().length, 60);
+    }
+
+    #[test]
+    fn alignments_above_a_page_split_the_range() {
+        let mut heap = Heap::new(64);
+        let base = heap.base();
+        let p = heap.allocator().allocate(4 * P, 16 * P).unwrap() as usize;
+        assert_eq!(p, base + 48 * P);
+        assert_eq!(heap.free_ranges(), vec![(0, 48), (52, 12)]);
+        assert_eq!(heap.allocator().length, 60);
+
+        // the only 64-page boundary is the start of the range, so its header is handed out too
+        let q = heap.allocator().allocate(2 * P, 64 * P).unwrap() as usize;
+        assert_eq!(q, base);
+        assert_eq!(heap.free_ranges(), vec![(2, 46), (52, 12)]);
+        assert!(heap.allocator().allocate(P, 128 * P).is_err());
+    }
+
+    #[test]
+    fn an_exact_fit_uses_up_the_range() {
+        let mut heap = Heap::new(8);
+        let base = heap.base();
+        assert_eq!(heap.allocator().allocate(8 * P, P), Ok(base as *mut u8));
+        assert_eq!(heap.free_ranges(), vec![]);
+        assert_eq!(heap.allocator().length, 0);
+        assert!(heap.allocator().allocate(1, 1).is_err());
+    }
+
+    #[test]
+    fn growing_in_place_needs_a_big_enough_free_neighbour() {
+        let mut heap = Heap::new(16);
+        let a = heap.allocator().allocate(4 * P, P).unwrap();
+        let b = heap.allocator().allocate(4 * P, P).unwrap();
+        unsafe {
+            assert!(!heap.allocator().resize_in_place(b, 4, 5));
+
+            let a = a as usize;
+            heap.allocator().free_range(VirtAddr::new(a), VirtAddr::new(a + 4 * P));
+            assert!(!heap.allocator().resize_in_place(b, 4, 9));
+            assert!(heap.allocator().resize_in_place(b, 4, 6));
+            assert_eq!(heap.free_ranges(), vec![(0, 8), (14, 2)]);
+            assert!(heap.allocator().resize_in_place(b, 6, 8));
+            assert_eq!(heap.free_ranges(), vec![(0, 8)]);
+        }
+        assert_eq!(heap.allocator().length, 8);
+    }
+
+    #[test]
+    fn shrinking_frees_the_tail() {
+        let mut heap = Heap::new(16);
+        heap.allocator().allocate(8 * P, P).unwrap();
+        let b = heap.allocator().allocate(8 * P, P).unwrap();
+        assert_eq!(b as usize, heap.base());
+        unsafe {
+            assert!(heap.allocator().resize_in_place(b, 8, 3));
+        }
+        assert_eq!(heap.free_ranges(), vec![(3, 5)]);
+        assert_eq!(heap.allocator().length, 5);
+    }
+}
@@ -5,47 +5,143 @@
 #![feature(const_fn)]
 
 extern crate alloc;
+#[cfg(test)]
+#[macro_use]
+extern crate std;
 extern crate mem_utils;
 extern crate spinlock;
 #[macro_use]
 extern crate log;
 mod allocator;
+mod slab;
+mod stats;
 
 use mem_utils::{VirtAddr, Address, PGSIZE};
 use spinlock::Mutex;
 use allocator::{Allocator, Range}; // our system allocator
-use alloc::allocator::{Alloc, Layout, AllocErr}; // Rust allocator trait
+use slab::{Slabs, SLABS_INIT}; // size classes for small allocations
+use stats::{Counters, COUNTERS_INIT};
+pub use stats::HeapStats;
+use alloc::allocator::{Alloc, Layout, AllocErr, CannotReallocInPlace}; // Rust allocator trait
+use core::cmp::{max, min};
+use core::ptr;
 
-pub struct RangeAlloc(Mutex<Allocator>);
+/// The kernel heap.  Allocations of up to `slab::MAX_SIZE` bytes share pages in slabs, and
+/// anything bigger gets whole pages of its own.
+pub struct RangeAlloc {
+    pages: Mutex<Allocator>,
+    slabs: Mutex<Slabs>,
+    counters: Mutex<Counters>,
+}
 
 impl RangeAlloc {
     pub unsafe fn init(&self, vstart: VirtAddr, vend: VirtAddr) {
-        self.0.lock().free_range(vstart, vend);
+        self.pages.lock().free_range(vstart, vend);
+        self.counters.lock().total_pages += vend.pageno() - vstart.pageno();
+    }
+
+    /// A snapshot of the heap's counters and of its free ranges, which shows how much memory is
+    /// in use and how broken up what's left is
+    pub fn stats(&self) -> HeapStats {
+        let counters = *self.counters.lock();
+        let mut free_pages = 0;
+        let mut free_ranges = 0;
+        let mut largest_free_range = 0;
+        self.pages.lock().for_each_free_range(|_, pages| {
+            free_pages += pages;
+            free_ranges += 1;
+            largest_free_range = max(largest_free_range, pages);
+        });
+        HeapStats {
+            counters: counters,
+            free_pages: free_pages,
+            free_ranges: free_ranges,
+            largest_free_range: largest_free_range,
+        }
+    }
+
+    // Whether an allocation can be resized without moving it, doing it if so.  Slab objects can
+    // stay put while they're in the same size class; page allocations can shrink, or grow into
+    // free pages right after them.
+    unsafe fn resize_in_place(&self, ptr: *mut u8, layout: &Layout, new_layout: &Layout) -> bool {
+        if ptr as usize % new_layout.align() != 0 {
+            return false;
+        }
+        let class = Slabs::class(layout.size(), layout.align());
+        let new_class = Slabs::class(new_layout.size(), new_layout.align());
+        match (class, new_class) {
+            (None, None) => {
+                let pages = Allocator::size_to_pages(layout.size());
+                let new_pages = Allocator::size_to_pages(new_layout.size());
+                if !self.pages.lock().resize_in_place(ptr, pages, new_pages) {
+                    return false;
+                }
+                self.counters
+                    .lock()
+                    .resized(layout.size(), new_layout.size(), pages, new_pages);
+                true
+            }
+            (class, new_class) if class == new_class => {
+                self.counters.lock().resized(layout.size(), new_layout.size(), 0, 0);
+                true
+            }
+            _ => false,
+        }
     }
 }
 
-pub const RANGE_ALLOC_INIT: RangeAlloc = RangeAlloc(Mutex::new(Allocator {
-    start: Range {
-        next: None,
-        size: 0,
-    },
-    length: 0,
-}));
+pub const RANGE_ALLOC_INIT: RangeAlloc = RangeAlloc {
+    pages: Mutex::new(Allocator {
+        start: Range {
+            next: None,
+            size: 0,
+        },
+        length: 0,
+    }),
+    slabs: Mutex::new(SLABS_INIT),
+    counters: Mutex::new(COUNTERS_INIT),
+};
 
 unsafe impl<'a> Alloc for &'a RangeAlloc {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocErr> {
-        assert!(layout.align() <= PGSIZE);
-        let mut kalloc = self.0.lock();
         let size = layout.size();
-        kalloc.allocate(size)
-            .map_err(|_| AllocErr::Exhausted { request: layout })
+        let mut num_pages = 0; // taken from the range allocator for this allocation
+        let mut new_slab_page = false; // or taken for its size class to keep
+        let result = match Slabs::class(size, layout.align()) {
+            Some(class) => {
+                let pages = &self.pages;
+                self.slabs.lock().allocate(class, || {
+                    new_slab_page = true;
+                    pages.lock().allocate(PGSIZE, PGSIZE)
+                })
+            }
+            None => {
+                num_pages = Allocator::size_to_pages(size);
+                self.pages.lock().allocate(size, layout.align())
+            }
+        };
+
+        if result.is_ok() {
+            let mut counters = self.counters.lock();
+            if new_slab_page {
+                counters.slab_page();
+            }
+            counters.allocated(size, num_pages);
+        }
+        result.map_err(|_| AllocErr::Exhausted { request: layout })
     }
 
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        let mut kalloc = self.0.lock();
-
         let size = layout.size();
+        if let Some(class) = Slabs::class(size, layout.align()) {
+            self.slabs.lock().free(class, ptr);
+            self.counters.lock().freed(size, 0);
+            return;
+        }
+
         let num_pages = Allocator::size_to_pages(size);
+        self.counters.lock().freed(size, num_pages);
+        let mut kalloc = self.pages.lock();
 
         let start_addr = VirtAddr::new(ptr as usize);
         let end_addr = VirtAddr::new(ptr.offset((num_pages * PGSIZE) as isize) as usize);
@@ -56,8 +152,50 @@ unsafe impl<'a> Alloc for &'a RangeAlloc {
         kalloc.free_range(start_addr, end_addr);
     }
 
+    unsafe fn realloc(&mut self,
+                      ptr: *mut u8,
+                      layout: Layout,
+                      new_layout: Layout)
+                      -> Result<*mut u8, AllocErr> {
+        if self.resize_in_place(ptr, &layout, &new_layout) {
+            return Ok(ptr);
+        }
+
+        let new_ptr = self.alloc(new_layout.clone())?;
+        ptr::copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_layout.size()));
+        self.dealloc(ptr, layout);
+        Ok(new_ptr)
+    }
+
+    unsafe fn grow_in_place(&mut self,
+                            ptr: *mut u8,
+                            layout: Layout,
+                            new_layout: Layout)
+                            -> Result<(), CannotReallocInPlace> {
+        if self.resize_in_place(ptr, &layout, &new_layout) {
+            Ok(())
+        } else {
+            Err(CannotReallocInPlace)
+        }
+    }
+
+    unsafe fn shrink_in_place(&mut self,
+                              ptr: *mut u8,
+                              layout: Layout,
+                              new_layout: Layout)
+                              -> Result<(), CannotReallocInPlace> {
+        if self.resize_in_place(ptr, &layout, &new_layout) {
+            Ok(())
+        } else {
+            Err(CannotReallocInPlace)
+        }
+    }
+
     fn usable_size(&self, layout: &Layout) -> (usize, usize) {
         let size = layout.size();
-        (size, Allocator::size_to_pages(size) * PGSIZE)
+        match Slabs::class(size, layout.align()) {
+            Some(class) => (size, Slabs::class_size(class)),
+            None => (size, Allocator::size_to_pages(size) * PGSIZE),
+        }
     }
 }
@@ -0,0 +1,188 @@
+use mem_utils::PGSIZE;
+use core::cmp::max;
+use core::ptr::Unique;
+
+// Small allocations come out of slabs instead of taking a whole page each.  There's a size class
+// for every power of two from MIN_SIZE to MAX_SIZE, and each class keeps a list of its free
+// objects, threaded through the objects themselves.  When a class runs out it takes a page from
+// the range allocator and splits the page into objects.  Objects start at multiples of their size
+// within a page-aligned page, so every object is aligned to its own size.
+//
+// Pages stay with their size class once they've been split up, and are never given back to the
+// range allocator, even when every object on them is free.  Freed objects go back on the class's
+// list for reuse, so a class only ever holds as many pages as it needed at its busiest.  That's on
+// purpose: finding out whether a page is entirely free would take a count per page, and the
+// kernel's small allocations settle into a steady working set soon after boot.  The heap's
+// counters say how many pages the slabs are holding on to.
+
+pub const MIN_SIZE: usize = 16;
+pub const MAX_SIZE: usize = 2048;
+const NCLASSES: usize = 8; // MIN_SIZE, 2 * MIN_SIZE, ... MAX_SIZE
+
+#[repr(C)]
+struct Object {
+    next: Option<Unique<Object>>, // the next free object in the same size class
+}
+
+pub struct Slabs {
+    free: [Option<Unique<Object>>; NCLASSES],
+}
+
+pub const SLABS_INIT: Slabs = Slabs { free: [None; NCLASSES] };
+
+impl Slabs {
+    /// The size class for an allocation, or None if it's too big to come from a slab
+    pub fn class(size: usize, align: usize) -> Option<usize> {
+        let size = max(max(size, align), MIN_SIZE).next_power_of_two();
+        if size > MAX_SIZE {
+            return None;
+        }
+        Some((size.trailing_zeros() - MIN_SIZE.trailing_zeros()) as usize)
+    }
+
+    /// How big the objects in a size class are
+    pub fn class_size(class: usize) -> usize {
+        MIN_SIZE << class
+    }
+
+    /// Takes an object from a size class.  `new_page` is only called if the class has no free
+    /// objects left, and should return a fresh page for it.
+    pub unsafe fn allocate<F>(&mut self, class: usize, new_page: F) -> Result<*mut u8, &'static str>
+        where F: FnOnce() -> Result<*mut u8, &'static str>
+    {
+        if self.free[class].is_none() {
+            let page = new_page()?;
+            let size = Self::class_size(class);
+            // push them backwards so they're handed out in address order
+            for i in (0..PGSIZE / size).rev() {
+                self.free(class, page.offset((i * size) as isize));
+            }
+        }
+
+        let mut object = self.free[class].take().unwrap();
+        self.free[class] = object.as_mut().next.take();
+        Ok(object.as_mut() as *mut Object as *mut u8)
+    }
+
+    /// Returns an object to the size class it was allocated from
+    pub unsafe fn free(&mut self, class: usize, ptr: *mut u8) {
+        let mut object = Unique::new_unchecked(ptr as *mut Object);
+        *object.as_mut() = Object { next: self.free[class].take() };
+        self.free[class] = Some(object);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    // somewhere to cut pages from, which has to outlive the objects handed out of it
+    struct Pages {
+        memory: Vec<u8>,
+        used: usize,
+    }
+
+    impl Pages {
+        fn new(count: usize) -> Pages {
+            Pages {
+                memory: vec![0; (count + 1) * PGSIZE],
+                used: 0,
+            }
+        }
+
+        fn next(&mut self) -> Result<*mut u8, &'static str> {
+            let base = self.memory.as_mut_ptr() as usize;
+            let first = (base + PGSIZE - 1) & !(PGSIZE - 1);
+            if first + (self.used + 1) * PGSIZE > base + self.memory.len() {
+                return Err("out of pages");
+            }
+            self.used += 1;
+            Ok((first + (self.used - 1) * PGSIZE) as *mut u8)
+        }
+    }
+
+    #[test]
+    fn classes_round_up_to_powers_of_two() {
+        assert_eq!(Slabs::class(1, 1), Some(0));
+        assert_eq!(Slabs::class(16, 8), Some(0));
+        assert_eq!(Slabs::class(17, 8), Some(1));
+        assert_eq!(Slabs::class(100, 4), Some(3));
+        assert_eq!(Slabs::class(MAX_SIZE, 8), Some(NCLASSES - 1));
+        assert_eq!(Slabs::class(MAX_SIZE + 1, 8), None);
+        assert_eq!(Slabs::class_size(3), 128);
+    }
+
+    #[test]
+    fn classes_cover_the_alignment() {
+        assert_eq!(Slabs::class(8, 256), Some(4));
+        assert_eq!(Slabs::class(8, PGSIZE), None);
+    }
+
+    #[test]
+    fn pages_are_split_in_address_order() {
+        let mut pages = Pages::new(2);
+        let mut slabs = SLABS_INIT;
+        let class = Slabs::class(512, 8).unwrap();
+
+        let mut objects = Vec::new();
+        for _ in 0..PGSIZE / 512 {
+            objects.push(unsafe { slabs.allocate(class, || pages.next()) }.unwrap() as usize);
+        }
+        assert_eq!(pages.used, 1);
+        for (i, &object) in objects.iter().enumerate() {
+            assert_eq!(object, objects[0] + i * 512);
+        }
+        assert_eq!(objects[0] % PGSIZE, 0);
+
+        // the class is out of objects, so the next one comes from a fresh page
+        let next = unsafe { slabs.allocate(class, || pages.next()) }.unwrap() as usize;
+        assert_eq!(pages.used, 2);
+        assert_eq!(next % PGSIZE, 0);
+        assert!(!objects.contains(&next));
+    }
+
+    #[test]
+    fn freed_objects_are_reused_before_new_pages() {
+        let mut pages = Pages::new(1);
+        let mut slabs = SLABS_INIT;
+        let class = Slabs::class(64, 8).unwrap();
+
+        let a = unsafe { slabs.allocate(class, || pages.next()) }.unwrap();
+        let b = unsafe { slabs.allocate(class, || pages.next()) }.unwrap();
+        unsafe {
+            slabs.free(class, a);
+            assert_eq!(slabs.allocate(class, || Err("no refill expected")), Ok(a));
+            slabs.free(class, b);
+            slabs.free(class, a);
+            assert_eq!(slabs.allocate(class, || Err("no refill expected")), Ok(a));
+            assert_eq!(slabs.allocate(class, || Err("no refill expected")), Ok(b));
+        }
+        assert_eq!(pages.used, 1);
+    }
+
+    #[test]
+    fn classes_keep_their_own_objects() {
+        let mut pages = Pages::new(2);
+        let mut slabs = SLABS_INIT;
+        let small = Slabs::class(16, 8).unwrap();
+        let large = Slabs::class(2048, 8).unwrap();
+
+        let a = unsafe { slabs.allocate(small, || pages.next()) }.unwrap();
+        let b = unsafe { slabs.allocate(large, || pages.next()) }.unwrap();
+        assert_eq!(pages.used, 2);
+        unsafe {
+            slabs.free(small, a);
+            // a large object never comes out of a small class's page
+            let c = slabs.allocate(large, || Err("no refill expected")).unwrap();
+            assert_eq!(c as usize / PGSIZE, b as usize / PGSIZE);
+            assert_eq!(slabs.allocate(small, || Err("no refill expected")), Ok(a));
+        }
+    }
+
+    #[test]
+    fn a_failed_refill_is_an_error() {
+        let mut slabs = SLABS_INIT;
+        assert_eq!(unsafe { slabs.allocate(0, || Err("out of pages")) }, Err("out of pages"));
+    }
+}
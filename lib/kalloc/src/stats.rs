@@ -0,0 +1,191 @@
+use core::cmp::max;
+use core::fmt;
+
+/// Running totals of what the heap has handed out since boot.  Bytes are what callers asked
+/// for; pages are what came out of the range allocator, including the pages slabs are cut from.
+#[derive(Clone, Copy, Debug)]
+pub struct Counters {
+    pub total_pages: usize, // given to the heap by `init`
+    pub allocations: usize,
+    pub frees: usize,
+    pub bytes_allocated: usize,
+    pub bytes_freed: usize,
+    pub peak_bytes: usize, // the most bytes in use at once
+    pub pages_allocated: usize,
+    pub pages_freed: usize,
+    pub peak_pages: usize, // the most pages in use at once
+    pub slab_pages: usize, // of the pages allocated, how many went to slabs, which keep them
+}
+
+pub const COUNTERS_INIT: Counters = Counters {
+    total_pages: 0,
+    allocations: 0,
+    frees: 0,
+    bytes_allocated: 0,
+    bytes_freed: 0,
+    peak_bytes: 0,
+    pages_allocated: 0,
+    pages_freed: 0,
+    peak_pages: 0,
+    slab_pages: 0,
+};
+
+impl Counters {
+    pub fn bytes_in_use(&self) -> usize {
+        self.bytes_allocated - self.bytes_freed
+    }
+
+    pub fn pages_in_use(&self) -> usize {
+        self.pages_allocated - self.pages_freed
+    }
+
+    pub fn allocated(&mut self, bytes: usize, pages: usize) {
+        self.allocations += 1;
+        self.grow(bytes, pages);
+    }
+
+    pub fn freed(&mut self, bytes: usize, pages: usize) {
+        self.frees += 1;
+        self.shrink(bytes, pages);
+    }
+
+    /// Records a page going to a slab.  It isn't an allocation of its own, and it's never freed.
+    pub fn slab_page(&mut self) {
+        self.slab_pages += 1;
+        self.grow(0, 1);
+    }
+
+    /// Records an allocation changing size without moving, which isn't a new allocation
+    pub fn resized(&mut self,
+                   old_bytes: usize,
+                   new_bytes: usize,
+                   old_pages: usize,
+                   new_pages: usize) {
+        self.grow(new_bytes.saturating_sub(old_bytes), new_pages.saturating_sub(old_pages));
+        self.shrink(old_bytes.saturating_sub(new_bytes), old_pages.saturating_sub(new_pages));
+    }
+
+    fn grow(&mut self, bytes: usize, pages: usize) {
+        self.bytes_allocated += bytes;
+        self.pages_allocated += pages;
+        self.peak_bytes = max(self.peak_bytes, self.bytes_in_use());
+        self.peak_pages = max(self.peak_pages, self.pages_in_use());
+    }
+
+    fn shrink(&mut self, bytes: usize, pages: usize) {
+        self.bytes_freed += bytes;
+        self.pages_freed += pages;
+    }
+}
+
+/// A snapshot of the heap, from `RangeAlloc::stats`
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    pub counters: Counters,
+    pub free_pages: usize,
+    pub free_ranges: usize, // how many pieces the free pages are in
+    pub largest_free_range: usize, // in pages; nothing bigger can be allocated
+}
+
+impl HeapStats {
+    /// The percentage of free pages outside the largest free range.  0 means all the free
+    /// memory is in one piece.
+    pub fn fragmentation(&self) -> usize {
+        if self.free_pages == 0 {
+            0
+        } else {
+            100 - self.largest_free_range * 100 / self.free_pages
+        }
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let c = &self.counters;
+        writeln!(f, "total_pages {}", c.total_pages)?;
+        writeln!(f, "free_pages {}", self.free_pages)?;
+        writeln!(f, "free_ranges {}", self.free_ranges)?;
+        writeln!(f, "largest_free_range {}", self.largest_free_range)?;
+        writeln!(f, "fragmentation {}%", self.fragmentation())?;
+        writeln!(f, "allocations {}", c.allocations)?;
+        writeln!(f, "frees {}", c.frees)?;
+        writeln!(f, "bytes_allocated {}", c.bytes_allocated)?;
+        writeln!(f, "bytes_freed {}", c.bytes_freed)?;
+        writeln!(f, "bytes_in_use {}", c.bytes_in_use())?;
+        writeln!(f, "peak_bytes {}", c.peak_bytes)?;
+        writeln!(f, "pages_allocated {}", c.pages_allocated)?;
+        writeln!(f, "pages_freed {}", c.pages_freed)?;
+        writeln!(f, "pages_in_use {}", c.pages_in_use())?;
+        writeln!(f, "peak_pages {}", c.peak_pages)?;
+        writeln!(f, "slab_pages {}", c.slab_pages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_and_frees_balance() {
+        let mut c = COUNTERS_INIT;
+        c.allocated(100, 0);
+        c.allocated(5000, 2);
+        c.freed(100, 0);
+        assert_eq!((c.allocations, c.frees), (2, 1));
+        assert_eq!((c.bytes_in_use(), c.pages_in_use()), (5000, 2));
+        assert_eq!((c.peak_bytes, c.peak_pages), (5100, 2));
+
+        c.freed(5000, 2);
+        assert_eq!((c.bytes_in_use(), c.pages_in_use()), (0, 0));
+        assert_eq!((c.bytes_allocated, c.bytes_freed), (5100, 5100));
+    }
+
+    #[test]
+    fn resizing_isnt_an_allocation() {
+        let mut c = COUNTERS_INIT;
+        c.allocated(8192, 2);
+        c.resized(8192, 20000, 2, 5);
+        assert_eq!((c.allocations, c.frees), (1, 0));
+        assert_eq!((c.bytes_in_use(), c.pages_in_use()), (20000, 5));
+
+        c.resized(20000, 4096, 5, 1);
+        assert_eq!((c.bytes_in_use(), c.pages_in_use()), (4096, 1));
+        assert_eq!((c.peak_bytes, c.peak_pages), (20000, 5));
+
+        c.freed(4096, 1);
+        assert_eq!((c.bytes_in_use(), c.pages_in_use()), (0, 0));
+        assert_eq!((c.allocations, c.frees), (1, 1));
+    }
+
+    #[test]
+    fn slab_pages_stay_in_use() {
+        let mut c = COUNTERS_INIT;
+        c.slab_page();
+        c.allocated(16, 0);
+        c.allocated(16, 0);
+        c.freed(16, 0);
+        c.freed(16, 0);
+        assert_eq!(c.bytes_in_use(), 0);
+        assert_eq!((c.pages_in_use(), c.slab_pages, c.allocations), (1, 1, 2));
+    }
+
+    #[test]
+    fn fragmentation_counts_pages_outside_the_largest_range() {
+        let mut stats = HeapStats {
+            counters: COUNTERS_INIT,
+            free_pages: 0,
+            free_ranges: 0,
+            largest_free_range: 0,
+        };
+        assert_eq!(stats.fragmentation(), 0);
+        stats.free_pages = 40;
+        stats.free_ranges = 3;
+        stats.largest_free_range = 30;
+        assert_eq!(stats.fragmentation(), 25);
+
+        stats.counters.slab_page();
+        let text = format!("{}", stats);
+        assert!(text.contains("fragmentation 25%\n"));
+        assert!(text.contains("slab_pages 1\n"));
+    }
+}
@@ -1,185 +1,242 @@
 extern crate simple_fs as fs;
-extern crate slice_cast;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::io::SeekFrom;
 use std::env;
-use std::cell::RefCell;
-use std::mem::size_of;
-
-// size in blocks
-const FS_SIZE: u32 = 1000;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod disk_file;
+use disk_file::DiskFile;
+
+// Formats a simple_fs image and copies files from the host into it:
+//
+//     mkfs [--size blocks] [--inodes n] [--bitmap] [--root dir] fs.img [paths...]
+//
+// The image is created (or overwritten) at the requested size.  Free blocks are kept on a free
+// list unless --bitmap asks for a block bitmap, which lets large files be laid out in contiguous
+// extents.  With --root, the contents of a host directory become the root directory of the
+// image.  Each other path is copied into the root directory under its file name, and directories
+// are copied along with everything under them.  Symbolic links are copied as links, not followed.
+
+// default size in blocks
+const DEFAULT_SIZE: u32 = 1000;
+
+const USAGE: &'static str = "usage: mkfs [--size blocks] [--inodes n] [--bitmap] [--root dir] \
+                             <image> [paths...]";
+
+struct Options {
+    image: String,
+    nblocks: u32,
+    ninodes: u32,
+    allocator: fs::BlockAllocator,
+    root: Option<String>, // host directory to use as the root directory
+    paths: Vec<String>,
+}
 
 fn main() {
-    let path = env::args().nth(1).expect("You must pass a path!");
-    let mut fs = fs::FileSystem::new(DiskFile::new(path));
-    match mkfs(&mut fs) {
+    let opts = parse_args();
+    create_image(&opts.image, opts.nblocks);
+
+    let mut fs = fs::FileSystem::new(fs::BufferCache::new(DiskFile::new(opts.image.clone())));
+    fs.set_clock(host_time);
+    match mkfs(&mut fs, opts.nblocks, opts.ninodes, opts.allocator) {
         Ok(_) => println!("The disk was successfully formatted!"),
         Err(e) => panic!("An {:?} error occurred while formatting", e),
     }
-    // for each specified file, copy it into the new file system
-    for arg in env::args().skip(2) {
-        println!("Writing {}", arg);
-        write_file(&mut fs, &arg).unwrap();
+
+    if let Some(ref root) = opts.root {
+        import_dir(&mut fs, Path::new(root), b"/").unwrap();
+    }
+    // for each specified file or directory, copy it into the root of the new file system
+    for arg in &opts.paths {
+        let path = Path::new(arg);
+        let name = path.file_name().expect("Can't copy a path without a file name");
+        let dest = format!("/{}", name.to_string_lossy());
+        import(&mut fs, path, dest.as_bytes()).unwrap();
     }
+    fs.sync().expect("Could not write back the block cache");
 }
 
-fn write_file<T>(fs: &mut fs::FileSystem<T>, path: &str) -> Result<(), fs::FsError>
-    where T: fs::Disk
-{
-    let mut f = File::open(path).expect("Could not open file");
-    let mut inode = fs::Inode {
-        type_: fs::InodeType::File,
-        device: fs::ROOT_DEV,
-        major: 0,
-        minor: 0,
-        size: 0,
-        blocks: [0; fs::NDIRECT],
+fn parse_args() -> Options {
+    let mut opts = Options {
+        image: String::new(),
+        nblocks: DEFAULT_SIZE,
+        ninodes: fs::NUM_INODES,
+        allocator: fs::BlockAllocator::FreeList,
+        root: None,
+        paths: vec![],
     };
-    let inum = fs.alloc_inode(fs::ROOT_DEV, inode).unwrap();
-    assert_ne!(inum, fs::ROOT_INUM);
-    let mut buf = vec![];
-    f.read_to_end(&mut buf).unwrap();
-    fs.write(&mut inode, &buf, 0).unwrap();
-    fs.update_inode(inum, &inode).unwrap();
 
-    let new_inode = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
-    let mut buf2 = Vec::with_capacity(new_inode.size as usize);
-    fs.read(&new_inode, buf2.as_mut_slice(), 0).unwrap();
-    assert_eq!(buf.len(), new_inode.size as usize);
-    for (i, b) in buf.iter().enumerate() {
-        let mut b2 = [0u8; 1];
-        fs.read(&new_inode, &mut b2[0..1], i as u32).unwrap();
-        assert_eq!(*b, b2[0]);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--size" || arg == "-s" {
+            opts.nblocks = number(args.next());
+        } else if arg == "--inodes" || arg == "-i" {
+            opts.ninodes = number(args.next());
+        } else if arg == "--bitmap" || arg == "-b" {
+            opts.allocator = fs::BlockAllocator::Bitmap;
+        } else if arg == "--root" || arg == "-r" {
+            opts.root = Some(args.next().unwrap_or_else(|| usage()));
+        } else if arg.starts_with('-') {
+            usage();
+        } else if opts.image.is_empty() {
+            opts.image = arg;
+        } else {
+            opts.paths.push(arg);
+        }
     }
-    println!("File writeback was successful!");
 
-    let name = path.bytes().take(fs::DIRNAME_SIZE).collect::<Vec<_>>();
-    let mut root = fs.read_inode(fs::ROOT_DEV, fs::ROOT_INUM).unwrap();
-    fs.dir_add(&mut root, name.as_slice(), inum).unwrap();
-    fs.update_inode(fs::ROOT_INUM, &root)?;
+    if opts.image.is_empty() || opts.ninodes == 0 {
+        usage();
+    }
+    opts
+}
+
+fn number(arg: Option<String>) -> u32 {
+    arg.and_then(|a| a.parse().ok()).unwrap_or_else(|| usage())
+}
 
-    Ok(())
+fn usage() -> ! {
+    println!("{}", USAGE);
+    process::exit(1);
 }
 
-fn mkfs<T>(fs: &mut fs::FileSystem<T>) -> Result<(), fs::FsError>
+// make a zero-filled image of the requested size, so nothing from an old image survives
+fn create_image(path: &str, nblocks: u32) {
+    let f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .expect("Could not create image");
+    f.set_len(nblocks as u64 * fs::BLOCKSIZE as u64).expect("Could not size image");
+}
+
+// copy a host file or directory tree to `dest`, a path in the image
+fn import<T>(fs: &mut fs::FileSystem<T>, path: &Path, dest: &[u8]) -> Result<(), fs::FsError>
     where T: fs::Disk
 {
-    // Write an unused inode for each of the inodes
-    for i in 0..fs::NUM_INODES {
-        fs.update_inode(i, &fs::UNUSED_INODE)?;
+    let is_symlink = path.symlink_metadata().map(|m| m.file_type().is_symlink()).unwrap_or(false);
+    if is_symlink {
+        let target = path.read_link().expect("Could not read symlink");
+        println!("Linking {} -> {}", path.display(), target.display());
+        fs.symlink(target.to_string_lossy().as_bytes(), dest).map(|_| ())
+    } else if path.is_dir() {
+        println!("Creating {}", path.display());
+        let inum = fs.mkdir(dest)?;
+        import_dir(fs, path, dest)?;
+        copy_attributes(fs, inum, path)
+    } else {
+        println!("Writing {}", path.display());
+        write_file(fs, path, dest)
     }
+}
 
-    // create the freelist
-    let inode_size = size_of::<fs::Inode>();
-    let datablocks_start = 1 + (fs::NUM_INODES as u32) / ((fs::BLOCKSIZE / inode_size) as u32);
-
-    let tmp_sb = fs::SuperBlock {
-        size: 0,
-        nblocks: FS_SIZE,
-        ninodes: fs::NUM_INODES,
-        inode_start: 1,
-        freelist_start: fs::UNUSED_BLOCKADDR,
-    };
-    let mut buf = [0u8; fs::BLOCKSIZE];
-    {
-        let sb: &mut fs::SuperBlock =
-            unsafe { &mut slice_cast::cast_mut(&mut buf[..size_of::<fs::SuperBlock>()])[0] };
-
-        *sb = tmp_sb;
-    }
-    // write the new superblock
-    fs.disk.write(&buf, 0, fs::SUPERBLOCK_ADDR)?;
-
-    // sanity check: read the superblock back to verify it was written properly
-    {
-        let sb2: fs::SuperBlock =
-            unsafe { *slice_cast::cast_to_mut(&mut buf[..size_of::<fs::SuperBlock>()]) };
-        fs.disk.read(&mut buf, 0, fs::SUPERBLOCK_ADDR)?;
-        assert_eq!(sb2, tmp_sb);
+// copy everything in a host directory into `dest`, a directory in the image
+fn import_dir<T>(fs: &mut fs::FileSystem<T>, path: &Path, dest: &[u8]) -> Result<(), fs::FsError>
+    where T: fs::Disk
+{
+    // sort the entries, so that images come out the same every time
+    let mut entries = path.read_dir()
+        .expect("Could not read directory")
+        .map(|e| e.expect("Could not read directory").path())
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    for entry in entries {
+        let name = entry.file_name().unwrap().to_string_lossy().into_owned();
+        let mut child = dest.to_vec();
+        if !child.ends_with(b"/") {
+            child.push(b'/');
+        }
+        child.extend_from_slice(name.as_bytes());
+        import(fs, &entry, &child)?;
     }
+    Ok(())
+}
 
-    // add every data block to the freelist
-    for blockno in datablocks_start..FS_SIZE {
-        fs.free_block(0, blockno).unwrap();
+fn write_file<T>(fs: &mut fs::FileSystem<T>, path: &Path, dest: &[u8]) -> Result<(), fs::FsError>
+    where T: fs::Disk
+{
+    let mut f = File::open(path).expect("Could not open file");
+    let mut buf = vec![];
+    f.read_to_end(&mut buf).unwrap();
+    if buf.len() > fs::MAXFILE * fs::BLOCKSIZE {
+        println!("{} is larger than the maximum file size of {} bytes",
+                 path.display(),
+                 fs::MAXFILE * fs::BLOCKSIZE);
+        return Err(fs::FsError::WriteTooLarge);
     }
-    println!("Successfully created block free list");
-
-
-    // finally, create root dir
-    let mut inode = fs::Inode {
-        type_: fs::InodeType::Directory,
-        device: fs::ROOT_DEV,
-        major: 0,
-        minor: 0,
-        size: 0,
-        blocks: [0; fs::NDIRECT],
-    };
 
-    let dirent_size = size_of::<fs::DirEntry>();
+    let mut inode = fs.new_inode(fs::InodeType::File, fs::ROOT_DEV);
+    let inum = fs.alloc_inode(fs::ROOT_DEV, inode).unwrap();
+    assert_ne!(inum, fs::ROOT_INUM);
+    fs.write(&mut inode, &buf, 0)?;
+    fs.update_inode(inum, &inode).unwrap();
+    copy_attributes(fs, inum, path)?;
 
-    assert_eq!(fs.alloc_inode(0, inode)?, fs::ROOT_INUM);
-    println!("Writing root directory");
-    fs.dir_add(&mut inode, b".", fs::ROOT_INUM)?;
-    assert_eq!(inode.size as usize, dirent_size);
-    fs.dir_add(&mut inode, b"..", fs::ROOT_INUM)?;
-    assert_eq!(inode.size as usize, 2 * dirent_size);
-    fs.update_inode(fs::ROOT_INUM, &inode)?;
+    let new_inode = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
+    let mut buf2 = vec![0; new_inode.size as usize];
+    fs.read(&new_inode, buf2.as_mut_slice(), 0).unwrap();
+    assert_eq!(buf.len(), new_inode.size as usize);
+    assert!(buf == buf2, "file contents differ after writeback");
+    println!("File writeback was successful!");
 
-    let inum2 = fs.read_inode(fs::ROOT_DEV, fs::ROOT_INUM)?;
-    assert_eq!(inode.type_, inum2.type_);
-    assert_eq!(inode.size, inum2.size);
-    assert_eq!(inode.blocks[0], inum2.blocks[0]);
+    let (parent_inum, name) = fs.nameiparent(dest)?;
+    let mut parent = fs.read_inode(fs::ROOT_DEV, parent_inum)?;
+    fs.dir_add(&mut parent, name, inum)?;
+    fs.update_inode(parent_inum, &parent)?;
 
-    assert_eq!(fs.dir_lookup(&inode, b"."), Ok((0, 0)));
-    assert_eq!(fs.dir_lookup(&inode, b".."),
-               Ok((0, size_of::<fs::DirEntry>())));
     Ok(())
 }
 
-struct DiskFile {
-    file: RefCell<File>,
+// give an inode the permission bits and modification time of the host file it was copied from
+fn copy_attributes<T>(fs: &mut fs::FileSystem<T>,
+                      inum: u32,
+                      path: &Path)
+                      -> Result<(), fs::FsError>
+    where T: fs::Disk
+{
+    let meta = path.metadata().expect("Could not read file attributes");
+    let mut inode = fs.read_inode(fs::ROOT_DEV, inum)?;
+    inode.mode = (meta.permissions().mode() & 0o7777) as u16;
+    if let Ok(mtime) = meta.modified() {
+        inode.mtime = unix_time(mtime);
+    }
+    fs.update_inode(inum, &inode)
 }
 
-impl DiskFile {
-    fn new(path: std::string::String) -> DiskFile {
-        DiskFile {
-            file: RefCell::new(OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(path)
-                .expect("Could not create file")),
-        }
-    }
+fn unix_time(t: SystemTime) -> u32 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0)
 }
 
-impl fs::Disk for DiskFile {
-    fn read(&self, mut buffer: &mut [u8], _: u32, sector: u32) -> Result<(), fs::DiskError> {
-        // seek to the sector
-        assert!(buffer.len() <= 512);
-        let _ = self.file
-            .borrow_mut()
-            .seek(SeekFrom::Start((sector as u64) * (Self::sector_size()) as u64));
-        let _ = self.file.borrow_mut().read_exact(&mut buffer);
-        Ok(())
-    }
+fn host_time() -> u32 {
+    unix_time(SystemTime::now())
+}
 
-    fn write(&mut self, buffer: &[u8], _: u32, sector: u32) -> Result<usize, fs::DiskError> {
-        assert!(buffer.len() <= 512, "length was {}", buffer.len());
-        let _ = self.file
-            .borrow_mut()
-            .seek(SeekFrom::Start((sector as u64) * (Self::sector_size()) as u64));
-        let written = self.file.borrow_mut().write_all(buffer);
-        self.file.borrow_mut().flush().unwrap();
-        if written.is_ok() {
-            Ok(buffer.len())
-        } else {
-            Err(fs::DiskError::IoError)
+fn mkfs<T>(fs: &mut fs::FileSystem<T>,
+           nblocks: u32,
+           ninodes: u32,
+           allocator: fs::BlockAllocator)
+           -> Result<(), fs::FsError>
+    where T: fs::Disk
+{
+    if let Err(e) = fs.format(nblocks, ninodes, allocator) {
+        if e == fs::FsError::BadGeometry {
+            println!("{} blocks isn't enough room for {} inodes", nblocks, ninodes);
         }
+        return Err(e);
     }
 
-    fn sector_size() -> usize {
-        512
-    }
+    // sanity check: the new file system should mount, and hold just the root directory
+    let sb = fs.superblock(fs::ROOT_DEV)?;
+    sb.validate()?;
+    let root = fs.read_inode(fs::ROOT_DEV, fs::ROOT_INUM)?;
+    assert_eq!(root.type_, fs::InodeType::Directory);
+    assert_eq!(root.size as usize, 2 * fs::DIRENT_SIZE);
+    assert_eq!(fs.dir_lookup(&root, b"."), Ok((0, 0)));
+    assert_eq!(fs.dir_lookup(&root, b".."), Ok((0, fs::DIRENT_SIZE)));
+    Ok(())
 }
@@ -0,0 +1,146 @@
+use core::cell::RefCell;
+use core::cmp::min;
+use {Disk, DiskError, BLOCKSIZE};
+
+/// Number of blocks kept in the cache (NBUF in param.h)
+pub const NBUF: usize = 30;
+
+#[derive(Copy, Clone)]
+struct Buf {
+    valid: bool, // has this buffer been loaded with a block?
+    dirty: bool, // does this buffer need to be written back to disk?
+    device: u32,
+    blockno: u32,
+    last_used: u32, // cache clock value at last access, for LRU eviction
+    data: [u8; BLOCKSIZE],
+}
+
+const EMPTY_BUF: Buf = Buf {
+    valid: false,
+    dirty: false,
+    device: 0,
+    blockno: 0,
+    last_used: 0,
+    data: [0; BLOCKSIZE],
+};
+
+struct Inner<T: Disk> {
+    disk: T,
+    bufs: [Buf; NBUF],
+    clock: u32,
+}
+
+/// A write-back LRU cache of disk blocks that wraps any `Disk`.  Writes only touch the cached
+/// copy of a block; dirty blocks reach the underlying disk when they're evicted or when `sync` is
+/// called.
+pub struct BufferCache<T: Disk> {
+    // reads can evict a dirty buffer, which needs to write to the disk, so everything lives
+    // behind a RefCell
+    inner: RefCell<Inner<T>>,
+}
+
+impl<T: Disk> BufferCache<T> {
+    pub fn new(disk: T) -> BufferCache<T> {
+        BufferCache {
+            inner: RefCell::new(Inner {
+                disk: disk,
+                bufs: [EMPTY_BUF; NBUF],
+                clock: 0,
+            }),
+        }
+    }
+}
+
+impl<T: Disk> Inner<T> {
+    /// Finds the buffer holding a block, loading it from disk (and evicting the least recently
+    /// used buffer) if it isn't cached.  If `fill` is false the caller is about to overwrite the
+    /// whole block, so we skip reading it in.
+    fn get(&mut self, device: u32, blockno: u32, fill: bool) -> Result<usize, DiskError> {
+        self.clock = self.clock.wrapping_add(1);
+        let clock = self.clock;
+
+        if let Some(i) = self.bufs
+            .iter()
+            .position(|b| b.valid && b.device == device && b.blockno == blockno) {
+            self.bufs[i].last_used = clock;
+            return Ok(i);
+        }
+
+        // prefer an empty buffer, otherwise recycle the least recently used one
+        let victim = match self.bufs.iter().position(|b| !b.valid) {
+            Some(i) => i,
+            None => {
+                self.bufs
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|&(_, b)| clock.wrapping_sub(b.last_used))
+                    .map(|(i, _)| i)
+                    .unwrap()
+            }
+        };
+
+        self.writeback(victim)?;
+
+        let buf = &mut self.bufs[victim];
+        buf.valid = false;
+        if fill {
+            self.disk.read(&mut buf.data, device, blockno)?;
+        }
+        buf.valid = true;
+        buf.device = device;
+        buf.blockno = blockno;
+        buf.last_used = clock;
+        Ok(victim)
+    }
+
+    fn writeback(&mut self, i: usize) -> Result<(), DiskError> {
+        let buf = &mut self.bufs[i];
+        if buf.valid && buf.dirty {
+            self.disk.write(&buf.data, buf.device, buf.blockno)?;
+            buf.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Disk> Disk for BufferCache<T> {
+    fn read(&self, buffer: &mut [u8], device: u32, sector: u32) -> Result<(), DiskError> {
+        let mut inner = self.inner.borrow_mut();
+        let i = inner.get(device, sector, true)?;
+
+        let n = min(buffer.len(), BLOCKSIZE);
+        buffer[..n].copy_from_slice(&inner.bufs[i].data[..n]);
+        Ok(())
+    }
+
+    fn write(&mut self, buffer: &[u8], device: u32, sector: u32) -> Result<usize, DiskError> {
+        let mut inner = self.inner.borrow_mut();
+        // a partial write keeps the rest of the block, so it has to be read in first
+        let i = inner.get(device, sector, buffer.len() < BLOCKSIZE)?;
+
+        let n = min(buffer.len(), BLOCKSIZE);
+        let buf = &mut inner.bufs[i];
+        buf.data[..n].copy_from_slice(&buffer[..n]);
+        buf.dirty = true;
+        Ok(n)
+    }
+
+    fn sync(&mut self) -> Result<(), DiskError> {
+        let mut inner = self.inner.borrow_mut();
+        for i in 0..NBUF {
+            inner.writeback(i)?;
+        }
+        inner.disk.sync()
+    }
+
+    fn sector_size() -> usize {
+        T::sector_size()
+    }
+}
+
+impl<T: Disk> Drop for BufferCache<T> {
+    fn drop(&mut self) {
+        // nobody is around to hear about an error at this point, so this is best effort
+        let _ = self.sync();
+    }
+}
@@ -0,0 +1,55 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use fs;
+
+/// A disk image on the host, for the tools that build and inspect file system images
+pub struct DiskFile {
+    file: RefCell<File>,
+}
+
+impl DiskFile {
+    pub fn new(path: String) -> DiskFile {
+        DiskFile {
+            file: RefCell::new(OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .expect("Could not create file")),
+        }
+    }
+}
+
+impl fs::Disk for DiskFile {
+    fn read(&self, mut buffer: &mut [u8], _: u32, sector: u32) -> Result<(), fs::DiskError> {
+        // seek to the sector
+        assert!(buffer.len() <= 512);
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start((sector as u64) * (Self::sector_size()) as u64))
+            .map_err(|_| fs::DiskError::IoError)?;
+        // a sector past the end of the image can't be read
+        file.read_exact(&mut buffer).map_err(|_| fs::DiskError::IoError)
+    }
+
+    fn write(&mut self, buffer: &[u8], _: u32, sector: u32) -> Result<usize, fs::DiskError> {
+        assert!(buffer.len() <= 512, "length was {}", buffer.len());
+        self.file
+            .borrow_mut()
+            .seek(SeekFrom::Start((sector as u64) * (Self::sector_size()) as u64))
+            .map_err(|_| fs::DiskError::IoError)?;
+        let written = self.file.borrow_mut().write_all(buffer);
+        self.file.borrow_mut().flush().unwrap();
+        if written.is_ok() {
+            Ok(buffer.len())
+        } else {
+            Err(fs::DiskError::IoError)
+        }
+    }
+
+    fn sector_size() -> usize {
+        512
+    }
+}
@@ -0,0 +1,270 @@
+// Explicit little-endian encodings of the on-disk structures.  Nothing here depends on how the
+// compiler lays out the in-memory types, so an image written on any host reads back the same in
+// the kernel.
+//
+// The layouts started out as what the i686 kernel's repr(C) structs looked like, padding included,
+// so images from before these routines existed still read correctly.  Fields added since then
+// took over padding or went at the end, where older images have zeros.
+
+use {DirEntry, FsError, Inode, InodeType, SuperBlock};
+use {BLOCKADDR_SIZE, DIRENT_SIZE, DIRNAME_SIZE, INODE_SIZE, NDIRECT, SUPERBLOCK_SIZE};
+
+pub fn get_u16(buf: &[u8], offset: usize) -> u16 {
+    buf[offset] as u16 | (buf[offset + 1] as u16) << 8
+}
+
+pub fn get_u32(buf: &[u8], offset: usize) -> u32 {
+    buf[offset] as u32 | (buf[offset + 1] as u32) << 8 | (buf[offset + 2] as u32) << 16 |
+    (buf[offset + 3] as u32) << 24
+}
+
+pub fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset] = value as u8;
+    buf[offset + 1] = (value >> 8) as u8;
+}
+
+pub fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    for i in 0..4 {
+        buf[offset + i] = (value >> (8 * i)) as u8;
+    }
+}
+
+/// Reads entry `index` of a block full of block addresses, like an indirect block or a block of
+/// the free list
+pub fn block_addr(block: &[u8], index: usize) -> u32 {
+    get_u32(block, index * BLOCKADDR_SIZE)
+}
+
+/// Stores `addr` as entry `index` of a block full of block addresses
+pub fn set_block_addr(block: &mut [u8], index: usize, addr: u32) {
+    put_u32(block, index * BLOCKADDR_SIZE, addr)
+}
+
+/// Whether bit `bit` of a bitmap block is set
+pub fn bit_is_set(block: &[u8], bit: u32) -> bool {
+    block[bit as usize / 8] & (1 << (bit % 8)) != 0
+}
+
+/// Sets or clears bit `bit` of a bitmap block
+pub fn set_bit(block: &mut [u8], bit: u32, value: bool) {
+    if value {
+        block[bit as usize / 8] |= 1 << (bit % 8);
+    } else {
+        block[bit as usize / 8] &= !(1 << (bit % 8));
+    }
+}
+
+// Reads the fields of a structure one after another.  `finish` checks that every byte of the
+// structure's on-disk size was accounted for.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8], size: usize) -> Reader<'a> {
+        assert!(buf.len() >= size, "buffer holds {} bytes, need {}", buf.len(), size);
+        Reader {
+            buf: &buf[..size],
+            pos: 0,
+        }
+    }
+
+    fn u16(&mut self) -> u16 {
+        self.pos += 2;
+        get_u16(self.buf, self.pos - 2)
+    }
+
+    fn u32(&mut self) -> u32 {
+        self.pos += 4;
+        get_u32(self.buf, self.pos - 4)
+    }
+
+    fn bytes(&mut self, dst: &mut [u8]) {
+        dst.copy_from_slice(&self.buf[self.pos..self.pos + dst.len()]);
+        self.pos += dst.len();
+    }
+
+    fn skip(&mut self, n: usize) {
+        self.pos += n;
+    }
+
+    fn finish(self) {
+        assert_eq!(self.pos, self.buf.len());
+    }
+}
+
+// Writes the fields of a structure one after another, the counterpart of Reader
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8], size: usize) -> Writer<'a> {
+        assert!(buf.len() >= size, "buffer holds {} bytes, need {}", buf.len(), size);
+        Writer {
+            buf: &mut buf[..size],
+            pos: 0,
+        }
+    }
+
+    fn u16(&mut self, value: u16) {
+        put_u16(self.buf, self.pos, value);
+        self.pos += 2;
+    }
+
+    fn u32(&mut self, value: u32) {
+        put_u32(self.buf, self.pos, value);
+        self.pos += 4;
+    }
+
+    fn bytes(&mut self, src: &[u8]) {
+        self.buf[self.pos..self.pos + src.len()].copy_from_slice(src);
+        self.pos += src.len();
+    }
+
+    // padding is always written as zeros
+    fn skip(&mut self, n: usize) {
+        for b in &mut self.buf[self.pos..self.pos + n] {
+            *b = 0;
+        }
+        self.pos += n;
+    }
+
+    fn finish(self) {
+        assert_eq!(self.pos, self.buf.len());
+    }
+}
+
+impl SuperBlock {
+    pub fn decode(buf: &[u8]) -> SuperBlock {
+        let mut r = Reader::new(buf, SUPERBLOCK_SIZE);
+        let sb = SuperBlock {
+            magic: r.u32(),
+            version: r.u32(),
+            size: r.u32(),
+            nblocks: r.u32(),
+            ninodes: r.u32(),
+            inode_start: r.u32(),
+            freelist_start: r.u32(),
+            log_start: r.u32(),
+            nlog: r.u32(),
+            bitmap_start: r.u32(),
+        };
+        r.finish();
+        sb
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        let mut w = Writer::new(buf, SUPERBLOCK_SIZE);
+        w.u32(self.magic);
+        w.u32(self.version);
+        w.u32(self.size);
+        w.u32(self.nblocks);
+        w.u32(self.ninodes);
+        w.u32(self.inode_start);
+        w.u32(self.freelist_start);
+        w.u32(self.log_start);
+        w.u32(self.nlog);
+        w.u32(self.bitmap_start);
+        w.finish();
+    }
+}
+
+impl InodeType {
+    fn decode(value: u16) -> Result<InodeType, FsError> {
+        match value {
+            0 => Ok(InodeType::Unused),
+            1 => Ok(InodeType::File),
+            2 => Ok(InodeType::Directory),
+            3 => Ok(InodeType::Symlink),
+            4 => Ok(InodeType::Device),
+            _ => Err(FsError::BadInodeType(value)),
+        }
+    }
+}
+
+impl Inode {
+    /// Fails if the inode's type field doesn't hold a known type, which means the ilist is
+    /// corrupt
+    pub fn decode(buf: &[u8]) -> Result<Inode, FsError> {
+        let mut r = Reader::new(buf, INODE_SIZE);
+        let type_ = InodeType::decode(r.u16())?;
+        let mode = r.u16();
+        let device = r.u32();
+        let major = r.u16();
+        let minor = r.u16();
+        let size = r.u32();
+        let mut blocks = [0; NDIRECT];
+        for b in blocks.iter_mut() {
+            *b = r.u32();
+        }
+        let indirect = r.u32();
+        let double_indirect = r.u32();
+        let nlink = r.u16();
+        r.skip(2);
+        let ctime = r.u32();
+        let mtime = r.u32();
+        r.finish();
+
+        Ok(Inode {
+            type_: type_,
+            mode: mode,
+            device: device,
+            major: major,
+            minor: minor,
+            size: size,
+            blocks: blocks,
+            indirect: indirect,
+            double_indirect: double_indirect,
+            nlink: nlink,
+            ctime: ctime,
+            mtime: mtime,
+        })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        let mut w = Writer::new(buf, INODE_SIZE);
+        w.u16(self.type_ as u16);
+        w.u16(self.mode);
+        w.u32(self.device);
+        w.u16(self.major);
+        w.u16(self.minor);
+        w.u32(self.size);
+        for &b in self.blocks.iter() {
+            w.u32(b);
+        }
+        w.u32(self.indirect);
+        w.u32(self.double_indirect);
+        w.u16(self.nlink);
+        w.skip(2);
+        w.u32(self.ctime);
+        w.u32(self.mtime);
+        w.finish();
+    }
+}
+
+impl DirEntry {
+    pub fn decode(buf: &[u8]) -> DirEntry {
+        let mut r = Reader::new(buf, DIRENT_SIZE);
+        let inumber = r.u32();
+        let mut name = [0; DIRNAME_SIZE];
+        r.bytes(&mut name);
+        r.skip(2);
+        r.finish();
+
+        DirEntry {
+            inumber: inumber,
+            name: name,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        let mut w = Writer::new(buf, DIRENT_SIZE);
+        w.u32(self.inumber);
+        w.bytes(&self.name);
+        w.skip(2);
+        w.finish();
+    }
+}
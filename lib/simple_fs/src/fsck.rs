@@ -0,0 +1,619 @@
+extern crate simple_fs as fs;
+use std::env;
+use std::process;
+
+mod disk_file;
+use disk_file::DiskFile;
+
+// Checks a simple_fs image for consistency:
+//
+//     fsck [--repair] fs.img
+//
+// Without --repair the image is only read.  With it, a committed transaction left in the log is
+// replayed first, then every problem found is fixed.  Files that can't be salvaged are cleared,
+// and the free list (or block bitmap) is rebuilt from the blocks that no inode uses.
+//
+// Exits with 0 if the image was clean, 1 if problems were found and repaired, and 4 if problems
+// were left uncorrected (the same convention as e2fsck).
+
+const EXIT_REPAIRED: i32 = 1;
+const EXIT_UNCORRECTED: i32 = 4;
+
+// what we've learned about each block of the image
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Block {
+    Unclaimed,
+    Metadata, // the superblock, log, or ilist
+    Free,
+    Owned(u32), // mapped by the given inode
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let repair = args.iter().any(|a| a == "-r" || a == "--repair");
+    let path = match args.iter().find(|a| !a.starts_with('-')) {
+        Some(path) => path.clone(),
+        None => {
+            println!("usage: fsck [--repair] <image>");
+            process::exit(EXIT_UNCORRECTED);
+        }
+    };
+
+    // DiskFile creates missing images, which we definitely don't want here
+    let image_size = match std::fs::metadata(&path) {
+        Ok(m) => m.len(),
+        Err(e) => {
+            println!("Could not open {}: {}", path, e);
+            process::exit(EXIT_UNCORRECTED);
+        }
+    };
+
+    let mut fs = fs::FileSystem::new(DiskFile::new(path));
+    let mut sb = fs.superblock(fs::ROOT_DEV).expect("Could not read the superblock");
+
+    let problems = check_superblock(&sb, (image_size / fs::BLOCKSIZE as u64) as u32);
+    if !problems.is_empty() {
+        for p in problems {
+            println!("{}", p);
+        }
+        println!("The superblock is damaged, so the rest of the image can't be checked");
+        process::exit(EXIT_UNCORRECTED);
+    }
+
+    if fs.log_pending(fs::ROOT_DEV).expect("Could not read the log header") != 0 {
+        if repair {
+            println!("Replaying the transaction left in the log");
+            fs = fs::FileSystem::new(fs::FileSystem::mount(fs.disk)
+                .expect("Could not replay the log")
+                .disk);
+            // the transaction may well have changed the superblock
+            sb = fs.superblock(fs::ROOT_DEV).expect("Could not read the superblock");
+        } else {
+            println!("The log holds a committed transaction that hasn't been installed yet.  \
+                      Mounting the image (or running with --repair) will replay it, and may fix \
+                      some of the problems below.");
+        }
+    }
+
+    let mut fsck = Fsck::new(fs, sb, repair);
+    fsck.check_inodes();
+    fsck.check_directories();
+    if sb.bitmap_start == fs::UNUSED_BLOCKADDR {
+        fsck.check_free_list();
+    } else {
+        fsck.check_bitmap();
+    }
+    fsck.fs.sync().expect("Could not write back the repaired image");
+
+    if fsck.found == 0 {
+        println!("The file system is clean");
+    } else if fsck.repair {
+        println!("Repaired {} problem(s)", fsck.found);
+        process::exit(EXIT_REPAIRED);
+    } else {
+        println!("Found {} problem(s).  Run with --repair to fix them.", fsck.found);
+        process::exit(EXIT_UNCORRECTED);
+    }
+}
+
+fn check_superblock(sb: &fs::SuperBlock, image_blocks: u32) -> Vec<String> {
+    let mut problems = vec![];
+
+    if sb.magic != fs::FSMAGIC {
+        // nothing else in here means anything
+        problems.push(format!("The image doesn't hold a simple_fs file system (magic number {:#x} \
+                               instead of {:#x})",
+                              sb.magic,
+                              fs::FSMAGIC));
+        return problems;
+    }
+    if sb.version < fs::FSVERSION_MIN || sb.version > fs::FSVERSION {
+        problems.push(format!("The file system is format version {}, but fsck only understands \
+                               versions {} to {}",
+                              sb.version,
+                              fs::FSVERSION_MIN,
+                              fs::FSVERSION));
+        return problems;
+    }
+
+    if sb.nblocks == 0 || sb.nblocks > image_blocks {
+        problems.push(format!("The superblock says the image has {} blocks, but it holds {}",
+                              sb.nblocks,
+                              image_blocks));
+    }
+    if sb.ninodes == 0 {
+        problems.push("The superblock says there are no inodes".to_string());
+    }
+    if sb.log_start != 0 &&
+       (sb.nlog == 0 || sb.nlog as usize > fs::LOGSIZE ||
+        sb.log_start + 1 + sb.nlog > sb.inode_start) {
+        problems.push(format!("The log ({} blocks at {}) doesn't fit before the ilist at {}",
+                              sb.nlog,
+                              sb.log_start,
+                              sb.inode_start));
+    }
+    if sb.inode_start == fs::SUPERBLOCK_ADDR || sb.data_start() >= sb.nblocks {
+        problems.push(format!("The ilist (starting at {}) doesn't fit between the superblock \
+                               and the end of the image",
+                              sb.inode_start));
+    }
+    if sb.bitmap_start != fs::UNUSED_BLOCKADDR &&
+       sb.bitmap_start != sb.inode_start + sb.ilist_blocks() {
+        problems.push(format!("The block bitmap starts at {}, instead of right after the ilist",
+                              sb.bitmap_start));
+    }
+    if sb.bitmap_start == fs::UNUSED_BLOCKADDR && sb.freelist_start != fs::UNUSED_BLOCKADDR &&
+       (sb.freelist_start < sb.data_start() || sb.freelist_start >= sb.nblocks) {
+        problems.push(format!("The free list starts at block {}, outside the data area",
+                              sb.freelist_start));
+    }
+
+    problems
+}
+
+struct Fsck<T: fs::Disk> {
+    fs: fs::FileSystem<T>,
+    sb: fs::SuperBlock,
+    data_start: u32,
+    repair: bool,
+    found: usize, // number of problems found
+    blocks: Vec<Block>,
+    inodes: Vec<Option<fs::Inode>>, // None for unused inodes
+}
+
+impl<T: fs::Disk> Fsck<T> {
+    fn new(fs: fs::FileSystem<T>, sb: fs::SuperBlock, repair: bool) -> Fsck<T> {
+        let data_start = sb.data_start();
+        let mut blocks = vec![Block::Unclaimed; sb.nblocks as usize];
+        for b in blocks.iter_mut().take(data_start as usize) {
+            *b = Block::Metadata;
+        }
+
+        Fsck {
+            fs: fs,
+            sb: sb,
+            data_start: data_start,
+            repair: repair,
+            found: 0,
+            blocks: blocks,
+            inodes: vec![None; sb.ninodes as usize],
+        }
+    }
+
+    fn problem(&mut self, msg: String) {
+        self.found += 1;
+        println!("{}", msg);
+    }
+
+    /// Checks every allocated inode's block map and size, and records which blocks it uses
+    fn check_inodes(&mut self) {
+        for inum in 0..self.sb.ninodes {
+            let inode = match self.fs.read_inode(fs::ROOT_DEV, inum) {
+                Ok(inode) => inode,
+                Err(fs::FsError::BadInodeType(t)) => {
+                    self.problem(format!("Inode {} has unknown type {}", inum, t));
+                    if self.repair {
+                        self.clear_inode(inum);
+                        println!("  -> cleared inode {}", inum);
+                    }
+                    continue;
+                }
+                Err(e) => panic!("Could not read inode {}: {:?}", inum, e),
+            };
+            if inode.type_ == fs::InodeType::Unused {
+                continue;
+            }
+
+            let problems = self.claim(inum, &inode);
+            let damaged = !problems.is_empty();
+            for p in problems {
+                self.problem(p);
+            }
+            if damaged && self.repair {
+                // the block map can't be trusted, so there's nothing to salvage
+                self.clear_inode(inum);
+                println!("  -> cleared inode {}", inum);
+                continue;
+            }
+
+            self.inodes[inum as usize] = Some(inode);
+            if !damaged {
+                self.check_size(inum);
+            }
+        }
+    }
+
+    // Marks the blocks an inode maps as in use, and describes any that are outside the data area
+    // or already used by another inode.  We don't look inside a bad indirect block.
+    fn claim(&mut self, inum: u32, inode: &fs::Inode) -> Vec<String> {
+        let mut problems = vec![];
+        let data_start = self.data_start;
+        let blocks = &mut self.blocks;
+
+        self.fs
+            .inode_blocks(inode, |addr, _| {
+                if addr < data_start || addr as usize >= blocks.len() {
+                    problems.push(format!("Inode {} maps block {}, outside the data area",
+                                          inum,
+                                          addr));
+                    return false;
+                }
+                match blocks[addr as usize] {
+                    Block::Owned(other) => {
+                        problems.push(format!("Inode {} maps block {}, which inode {} already \
+                                               uses",
+                                              inum,
+                                              addr,
+                                              other));
+                        false
+                    }
+                    _ => {
+                        blocks[addr as usize] = Block::Owned(inum);
+                        true
+                    }
+                }
+            })
+            .expect("Could not read an indirect block");
+
+        problems
+    }
+
+    // forget which blocks an inode uses
+    fn release(&mut self, inum: u32) {
+        for b in self.blocks.iter_mut().filter(|b| **b == Block::Owned(inum)) {
+            *b = Block::Unclaimed;
+        }
+    }
+
+    // Marks an inode unused without freeing its blocks.  The free list is rebuilt at the end, and
+    // picks up any blocks nobody uses anymore.
+    fn clear_inode(&mut self, inum: u32) {
+        self.release(inum);
+        self.inodes[inum as usize] = None;
+        let unused = fs::Inode { device: fs::ROOT_DEV, ..fs::UNUSED_INODE };
+        self.fs.update_inode(inum, &unused).expect("Could not clear inode");
+    }
+
+    /// Checks that an inode's size agrees with the blocks it maps: every block below the size
+    /// should be mapped, and none past it
+    fn check_size(&mut self, inum: u32) {
+        let mut inode = self.inodes[inum as usize].unwrap();
+
+        let mut mapped = vec![];
+        self.fs
+            .inode_blocks(&inode, |_, usage| {
+                if let fs::BlockUse::Data(bn) = usage {
+                    mapped.push(bn as usize);
+                }
+                true
+            })
+            .expect("Could not read an indirect block");
+        mapped.sort();
+
+        // the file is only good up to its first unmapped block
+        let needed = (inode.size as usize + fs::BLOCKSIZE - 1) / fs::BLOCKSIZE;
+        let contiguous = mapped.iter().enumerate().take_while(|&(i, &bn)| i == bn).count();
+        let mut good_size = std::cmp::min(inode.size as usize, contiguous * fs::BLOCKSIZE);
+
+        if inode.size as usize > fs::MAXFILE * fs::BLOCKSIZE {
+            self.problem(format!("Inode {} is {} bytes, larger than the maximum file size",
+                                 inum,
+                                 inode.size));
+        } else if contiguous < needed {
+            self.problem(format!("Inode {} is {} bytes, but file block {} isn't mapped",
+                                 inum,
+                                 inode.size,
+                                 contiguous));
+        } else if mapped.len() > needed {
+            self.problem(format!("Inode {} is {} bytes, but maps {} blocks past its end",
+                                 inum,
+                                 inode.size,
+                                 mapped.len() - needed));
+        }
+
+        // a directory cut short by a missing block mustn't be left ending partway into an entry
+        let dirent_size = fs::DIRENT_SIZE;
+        if inode.type_ == fs::InodeType::Directory {
+            if inode.size as usize % dirent_size != 0 {
+                self.problem(format!("Directory {} is {} bytes, which isn't a whole number of \
+                                      entries",
+                                     inum,
+                                     inode.size));
+            }
+            good_size -= good_size % dirent_size;
+        }
+
+        if !self.repair || (good_size == inode.size as usize && mapped.len() <= needed) {
+            return;
+        }
+
+        // truncating frees everything past the new end, so the blocks the inode still maps
+        // have to be worked out again afterwards
+        self.release(inum);
+        self.fs.truncate(&mut inode, good_size as u32).expect("Could not truncate inode");
+        self.fs.update_inode(inum, &inode).expect("Could not write back inode");
+        self.claim(inum, &inode);
+        self.inodes[inum as usize] = Some(inode);
+        println!("  -> truncated inode {} to {} bytes", inum, good_size);
+    }
+
+    /// Walks the directory tree from the root, checking that every entry points at an allocated
+    /// inode, then looks for allocated inodes that no directory reaches
+    fn check_directories(&mut self) {
+        let root = self.inodes[fs::ROOT_INUM as usize];
+        if !root.map_or(false, |root| root.type_ == fs::InodeType::Directory) {
+            self.problem(format!("The root directory (inode {}) is missing", fs::ROOT_INUM));
+            println!("The directory tree can't be checked without it");
+            // there's no sensible way to repair this, so make sure we report failure
+            self.repair = false;
+            return;
+        }
+
+        let mut reached = vec![false; self.sb.ninodes as usize];
+        let mut links = vec![0u32; self.sb.ninodes as usize];
+        reached[fs::ROOT_INUM as usize] = true;
+        let mut dirs = vec![fs::ROOT_INUM];
+
+        while let Some(dir_inum) = dirs.pop() {
+            let mut dir = self.inodes[dir_inum as usize].unwrap();
+            let mut entries = vec![];
+            if let Err(e) = self.dir_entries(&dir, &mut entries) {
+                // check_size has already reported (or truncated) a directory with a hole in it,
+                // so just check the entries before the part we couldn't read
+                self.problem(format!("Directory {} can't be read to its end ({:?}); only the \
+                                      entries before that are checked",
+                                     dir_inum,
+                                     e));
+            }
+            for (offset, inum, name) in entries {
+                let allocated = (inum as usize) < self.inodes.len() &&
+                                self.inodes[inum as usize].is_some();
+                if !allocated {
+                    self.problem(format!("Entry {:?} in directory {} points at unallocated \
+                                          inode {}",
+                                         String::from_utf8_lossy(&name),
+                                         dir_inum,
+                                         inum));
+                    if self.repair {
+                        self.fs.dir_remove(&mut dir, offset).expect("Could not remove entry");
+                        println!("  -> removed the entry");
+                    }
+                    continue;
+                }
+
+                if name == b"." || name == b".." {
+                    continue;
+                }
+                links[inum as usize] += 1;
+                if reached[inum as usize] {
+                    continue;
+                }
+                reached[inum as usize] = true;
+                if self.inodes[inum as usize].unwrap().type_ == fs::InodeType::Directory {
+                    dirs.push(inum);
+                }
+            }
+        }
+
+        for inum in 0..self.sb.ninodes {
+            if self.inodes[inum as usize].is_none() || reached[inum as usize] {
+                continue;
+            }
+            self.problem(format!("Inode {} is allocated, but no directory refers to it", inum));
+            if self.repair {
+                self.clear_inode(inum);
+                println!("  -> cleared inode {}", inum);
+            }
+        }
+
+        // images from before link counts don't keep them up to date
+        if self.sb.version >= 3 {
+            self.check_links(&links);
+        }
+    }
+
+    // Checks that each inode's link count matches the number of directory entries naming it.  No
+    // entry names the root directory, but it still has its one link.
+    fn check_links(&mut self, links: &[u32]) {
+        for inum in 0..self.sb.ninodes {
+            let mut inode = match self.inodes[inum as usize] {
+                Some(inode) => inode,
+                None => continue,
+            };
+            let expected = if inum == fs::ROOT_INUM {
+                1
+            } else {
+                links[inum as usize]
+            };
+            if inode.nlink as u32 == expected {
+                continue;
+            }
+
+            self.problem(format!("Inode {} has {} link(s), but {} directory entries name it",
+                                 inum,
+                                 inode.nlink,
+                                 expected));
+            if self.repair {
+                inode.nlink = expected as u16;
+                self.fs.update_inode(inum, &inode).expect("Could not write inode");
+                self.inodes[inum as usize] = Some(inode);
+                println!("  -> set the link count to {}", expected);
+            }
+        }
+    }
+
+    // read every used entry of a directory, as (offset, inumber, name), into `entries`.  If part
+    // of the directory can't be read, the entries before it are still there.
+    fn dir_entries(&self,
+                   dir: &fs::Inode,
+                   entries: &mut Vec<(u32, u32, Vec<u8>)>)
+                   -> Result<(), fs::FsError> {
+        let dirent_size = fs::DIRENT_SIZE;
+        let mut buf = [0u8; fs::BLOCKSIZE];
+
+        let mut offset = 0;
+        while offset + dirent_size <= dir.size as usize {
+            self.fs.read(dir, &mut buf[..dirent_size], offset as u32)?;
+            let entry = fs::DirEntry::decode(&buf);
+            if entry.inumber != fs::UNUSED_INUM {
+                entries.push((offset as u32, entry.inumber, entry.name().to_vec()));
+            }
+            offset += dirent_size;
+        }
+        Ok(())
+    }
+
+    /// Walks the free list, checking that every block on it is in the data area, appears once,
+    /// and isn't used by an inode.  Every other data block should be used by an inode.
+    fn check_free_list(&mut self) {
+        let mut problems = vec![];
+        let mut head = self.sb.freelist_start;
+        let mut buf = [0u8; fs::BLOCKSIZE];
+
+        while head != fs::UNUSED_BLOCKADDR {
+            if !self.mark_free(head, &mut problems) {
+                break; // don't follow a bad link
+            }
+            self.fs.disk.read(&mut buf, fs::ROOT_DEV, head).expect("Could not read free list");
+
+            // the first entry links to the next block of the list, and the rest are free blocks
+            for i in 1..fs::INDIRECT_PER_BLOCK {
+                let addr = fs::block_addr(&buf, i);
+                if addr != fs::UNUSED_BLOCKADDR {
+                    self.mark_free(addr, &mut problems);
+                }
+            }
+            head = fs::block_addr(&buf, 0);
+        }
+
+        let lost = self.blocks.iter().filter(|&&b| b == Block::Unclaimed).count();
+        if lost > 0 {
+            problems.push(format!("{} data block(s) are neither free nor used by an inode", lost));
+        }
+
+        if problems.is_empty() {
+            return;
+        }
+        for p in problems {
+            self.problem(p);
+        }
+        if self.repair {
+            self.rebuild_free_list();
+            println!("  -> rebuilt the free list");
+        }
+    }
+
+    /// Checks the block bitmap against what the inodes use: the metadata blocks and every block
+    /// an inode uses should be marked used, and every other data block should be marked free
+    fn check_bitmap(&mut self) {
+        let mut problems = vec![];
+        let mut lost = 0;
+        let mut buf = [0u8; fs::BLOCKSIZE];
+
+        for addr in 0..self.sb.nblocks {
+            if addr % fs::BITS_PER_BLOCK == 0 {
+                let bitmap_block = self.sb.bitmap_start + addr / fs::BITS_PER_BLOCK;
+                self.fs
+                    .disk
+                    .read(&mut buf, fs::ROOT_DEV, bitmap_block)
+                    .expect("Could not read bitmap");
+            }
+            let used = fs::bit_is_set(&buf, addr % fs::BITS_PER_BLOCK);
+
+            match (self.blocks[addr as usize], used) {
+                (Block::Metadata, false) => {
+                    problems.push(format!("Block {} holds file system metadata, but the bitmap \
+                                           says it's free",
+                                          addr));
+                }
+                (Block::Owned(inum), false) => {
+                    problems.push(format!("Block {} is free in the bitmap, but inode {} uses it",
+                                          addr,
+                                          inum));
+                }
+                (Block::Unclaimed, true) => lost += 1,
+                (Block::Unclaimed, false) => self.blocks[addr as usize] = Block::Free,
+                _ => {}
+            }
+        }
+        if lost > 0 {
+            problems.push(format!("{} data block(s) are used in the bitmap, but not by any inode",
+                                  lost));
+        }
+
+        if problems.is_empty() {
+            return;
+        }
+        for p in problems {
+            self.problem(p);
+        }
+        if self.repair {
+            self.rebuild_bitmap();
+            println!("  -> rebuilt the bitmap");
+        }
+    }
+
+    // Records that a block is on the free list, describing any problem with that.  Returns false
+    // if the block shouldn't be there at all.
+    fn mark_free(&mut self, addr: u32, problems: &mut Vec<String>) -> bool {
+        if addr < self.data_start || addr >= self.sb.nblocks {
+            problems.push(format!("Block {} on the free list is outside the data area", addr));
+            return false;
+        }
+
+        match self.blocks[addr as usize] {
+            Block::Owned(inum) => {
+                problems.push(format!("Block {} is on the free list, but inode {} uses it",
+                                      addr,
+                                      inum));
+                false
+            }
+            Block::Free => {
+                problems.push(format!("Block {} is on the free list more than once", addr));
+                false
+            }
+            _ => {
+                self.blocks[addr as usize] = Block::Free;
+                true
+            }
+        }
+    }
+
+    // write a bitmap marking just the metadata and the blocks inodes use
+    fn rebuild_bitmap(&mut self) {
+        for i in 0..self.sb.bitmap_blocks() {
+            let mut buf = [0u8; fs::BLOCKSIZE];
+            for bit in 0..fs::BITS_PER_BLOCK {
+                let addr = i * fs::BITS_PER_BLOCK + bit;
+                if addr >= self.sb.nblocks {
+                    break;
+                }
+                match self.blocks[addr as usize] {
+                    Block::Metadata | Block::Owned(_) => fs::set_bit(&mut buf, bit, true),
+                    _ => self.blocks[addr as usize] = Block::Free,
+                }
+            }
+            self.fs
+                .disk
+                .write(&buf, fs::ROOT_DEV, self.sb.bitmap_start + i)
+                .expect("Could not write bitmap");
+        }
+    }
+
+    // put every data block that no inode uses on a brand new free list
+    fn rebuild_free_list(&mut self) {
+        let mut sb = self.fs.superblock(fs::ROOT_DEV).expect("Could not read the superblock");
+        sb.freelist_start = fs::UNUSED_BLOCKADDR;
+        self.fs.write_superblock(fs::ROOT_DEV, &sb).expect("Could not write the superblock");
+
+        for addr in self.data_start..self.sb.nblocks {
+            match self.blocks[addr as usize] {
+                Block::Owned(_) => {}
+                _ => {
+                    self.fs.free_block(fs::ROOT_DEV, addr).expect("Could not free block");
+                    self.blocks[addr as usize] = Block::Free;
+                }
+            }
+        }
+    }
+}
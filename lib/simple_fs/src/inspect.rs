@@ -0,0 +1,321 @@
+extern crate simple_fs as fs;
+use std::env;
+use std::io::prelude::*;
+use std::path::Path;
+use std::process;
+
+mod disk_file;
+use disk_file::DiskFile;
+
+// Looks around inside a simple_fs image without changing it:
+//
+//     inspect fs.img ls [path]              list a directory
+//     inspect fs.img cat <path>             print a file
+//     inspect fs.img stat <path>            dump a file's inode and block map
+//     inspect fs.img super                  dump the superblock and the free list
+//     inspect fs.img extract <path> <dest>  copy a file or directory tree out to the host
+//
+// The image is read as-is, so anything still sitting in the log isn't visible.
+
+const USAGE: &'static str = "usage: inspect <image> ls [path]
+       inspect <image> cat <path>
+       inspect <image> stat <path>
+       inspect <image> super
+       inspect <image> extract <path> <dest>";
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.len() < 2 {
+        usage();
+    }
+
+    // DiskFile creates missing images, which we definitely don't want here
+    if let Err(e) = std::fs::metadata(&args[0]) {
+        println!("Could not open {}: {}", args[0], e);
+        process::exit(1);
+    }
+    let fs = fs::FileSystem::new(DiskFile::new(args[0].clone()));
+
+    let path = args.get(2).map_or(&b"/"[..], |p| p.as_bytes());
+    let result = match (args[1].as_str(), args.len()) {
+        ("ls", 2) | ("ls", 3) => ls(&fs, path),
+        ("cat", 3) => cat(&fs, path),
+        ("stat", 3) => stat(&fs, path),
+        ("super", 2) => superblock(&fs),
+        ("extract", 4) => extract(&fs, path, Path::new(&args[3])),
+        _ => usage(),
+    };
+
+    if let Err(e) = result {
+        println!("{}: {:?}", String::from_utf8_lossy(path), e);
+        process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    println!("{}", USAGE);
+    process::exit(1);
+}
+
+fn ls<T: fs::Disk>(fs: &fs::FileSystem<T>, path: &[u8]) -> Result<(), fs::FsError> {
+    let inum = fs.namex(path)?;
+    let dir = fs.read_inode(fs::ROOT_DEV, inum)?;
+    if dir.type_ != fs::InodeType::Directory {
+        println!("{:>5}  {:<9} {:04o} {:>3} {:>8}  {}",
+                 inum,
+                 format!("{:?}", dir.type_),
+                 dir.mode,
+                 dir.nlink,
+                 dir.size,
+                 String::from_utf8_lossy(path));
+        return Ok(());
+    }
+
+    for item in fs.readdir(&dir) {
+        let item = item?;
+        let inum = item.inumber;
+        let inode = fs.read_inode(fs::ROOT_DEV, inum)?;
+        let mut name = String::from_utf8_lossy(item.name()).into_owned();
+        if item.type_ == fs::InodeType::Symlink {
+            name = format!("{} -> {}", name, String::from_utf8_lossy(&read_file(fs, inum)?));
+        } else if item.type_ == fs::InodeType::Device {
+            name = format!("{} ({}, {})", name, inode.major, inode.minor);
+        }
+        println!("{:>5}  {:<9} {:04o} {:>3} {:>8}  {}",
+                 inum,
+                 format!("{:?}", inode.type_),
+                 inode.mode,
+                 inode.nlink,
+                 inode.size,
+                 name);
+    }
+    Ok(())
+}
+
+fn cat<T: fs::Disk>(fs: &fs::FileSystem<T>, path: &[u8]) -> Result<(), fs::FsError> {
+    let contents = read_file(fs, fs.namex(path)?)?;
+    std::io::stdout().write_all(&contents).expect("Could not write to stdout");
+    Ok(())
+}
+
+fn stat<T: fs::Disk>(fs: &fs::FileSystem<T>, path: &[u8]) -> Result<(), fs::FsError> {
+    // describe a symbolic link itself, not what it points to
+    let inum = fs.lookup(path, false)?;
+    let inode = fs.read_inode(fs::ROOT_DEV, inum)?;
+
+    println!("inode:   {}", inum);
+    println!("type:    {:?}", inode.type_);
+    if inode.type_ == fs::InodeType::Symlink {
+        println!("target:  {}", String::from_utf8_lossy(&read_file(fs, inum)?));
+    }
+    println!("mode:    {:04o}", inode.mode);
+    println!("links:   {}", inode.nlink);
+    println!("device:  {} ({}, {})", inode.device, inode.major, inode.minor);
+    println!("size:    {} bytes", inode.size);
+    println!("created: {} (seconds since the epoch)", inode.ctime);
+    println!("modified: {}", inode.mtime);
+
+    // collect the block map so it can be printed in order, a run of consecutive blocks at a time
+    let mut data = vec![];
+    let mut indirect = vec![];
+    fs.inode_blocks(&inode, |addr, usage| {
+        match usage {
+            fs::BlockUse::Data(bn) => data.push((bn, addr)),
+            fs::BlockUse::Indirect(depth) => indirect.push((depth, addr)),
+        }
+        true
+    })?;
+    data.sort();
+
+    println!("blocks:  {} data, {} indirect", data.len(), indirect.len());
+    for (first, start, end, len) in runs(&data) {
+        if len == 1 {
+            println!("  file block {} -> {}", first, start);
+        } else {
+            println!("  file blocks {}-{} -> {}-{}", first, first + len - 1, start, end);
+        }
+    }
+    for (depth, addr) in indirect {
+        println!("  indirect block (depth {}) -> {}", depth, addr);
+    }
+    Ok(())
+}
+
+fn superblock<T: fs::Disk>(fs: &fs::FileSystem<T>) -> Result<(), fs::FsError> {
+    let sb = fs.superblock(fs::ROOT_DEV)?;
+
+    println!("magic:          {:#x}{}",
+             sb.magic,
+             if sb.magic == fs::FSMAGIC { "" } else { " (bad)" });
+    println!("version:        {}", sb.version);
+    println!("size:           {}", sb.size);
+    println!("nblocks:        {}", sb.nblocks);
+    println!("ninodes:        {} ({} per block)", sb.ninodes, fs::INODES_PER_BLOCK);
+    println!("inode_start:    {}", sb.inode_start);
+    println!("data start:     {}", sb.data_start());
+    println!("freelist_start: {}", sb.freelist_start);
+    if sb.bitmap_start == fs::UNUSED_BLOCKADDR {
+        println!("bitmap:         none");
+    } else {
+        println!("bitmap:         {} blocks at {}", sb.bitmap_blocks(), sb.bitmap_start);
+    }
+    if sb.log_start == 0 {
+        println!("log:            none");
+    } else {
+        println!("log:            {} blocks at {}, {} pending",
+                 sb.nlog,
+                 sb.log_start,
+                 fs.log_pending(fs::ROOT_DEV)?);
+    }
+
+    if sb.bitmap_start == fs::UNUSED_BLOCKADDR {
+        free_list(fs, &sb)
+    } else {
+        bitmap(fs, &sb)
+    }
+}
+
+fn free_list<T: fs::Disk>(fs: &fs::FileSystem<T>, sb: &fs::SuperBlock) -> Result<(), fs::FsError> {
+    // each block of the free list is free itself, links to the next one, and lists more free
+    // blocks
+    println!("free list:");
+    let mut total = 0;
+    let mut links = 0;
+    let mut head = sb.freelist_start;
+    let mut buf = [0u8; fs::BLOCKSIZE];
+    while head != fs::UNUSED_BLOCKADDR {
+        if head >= sb.nblocks {
+            println!("  {} is past the end of the disk!", head);
+            break;
+        }
+        links += 1;
+        if links > sb.nblocks {
+            println!("  the free list loops back on itself!");
+            break;
+        }
+        fs.disk.read(&mut buf, fs::ROOT_DEV, head)?;
+        let next = fs::block_addr(&buf, 0);
+
+        let mut free = (1..fs::INDIRECT_PER_BLOCK)
+            .map(|i| fs::block_addr(&buf, i))
+            .filter(|&a| a != fs::UNUSED_BLOCKADDR)
+            .collect::<Vec<_>>();
+        free.sort();
+
+        let numbered = free.iter().enumerate().map(|(i, &a)| (i as u32, a)).collect::<Vec<_>>();
+        let addrs = runs(&numbered)
+            .iter()
+            .map(|&(_, start, end, len)| if len == 1 {
+                format!("{}", start)
+            } else {
+                format!("{}-{}", start, end)
+            })
+            .collect::<Vec<_>>();
+        println!("  {} -> next {}, {} free: {}",
+                 head,
+                 next,
+                 free.len(),
+                 addrs.join(", "));
+
+        total += 1 + free.len();
+        head = next;
+    }
+    println!("{} free blocks", total);
+    Ok(())
+}
+
+fn bitmap<T: fs::Disk>(fs: &fs::FileSystem<T>, sb: &fs::SuperBlock) -> Result<(), fs::FsError> {
+    let mut free = vec![];
+    let mut buf = [0u8; fs::BLOCKSIZE];
+    for addr in 0..sb.nblocks {
+        if addr % fs::BITS_PER_BLOCK == 0 {
+            fs.disk.read(&mut buf, fs::ROOT_DEV, sb.bitmap_start + addr / fs::BITS_PER_BLOCK)?;
+        }
+        if !fs::bit_is_set(&buf, addr % fs::BITS_PER_BLOCK) {
+            free.push(addr);
+        }
+    }
+
+    let numbered = free.iter().enumerate().map(|(i, &a)| (i as u32, a)).collect::<Vec<_>>();
+    println!("free blocks:");
+    for (_, start, end, len) in runs(&numbered) {
+        if len == 1 {
+            println!("  {}", start);
+        } else {
+            println!("  {}-{} ({} blocks)", start, end, len);
+        }
+    }
+    println!("{} free blocks", free.len());
+    Ok(())
+}
+
+fn extract<T: fs::Disk>(fs: &fs::FileSystem<T>,
+                        path: &[u8],
+                        dest: &Path)
+                        -> Result<(), fs::FsError> {
+    let inum = fs.lookup(path, false)?;
+    extract_inode(fs, inum, dest)
+}
+
+fn extract_inode<T: fs::Disk>(fs: &fs::FileSystem<T>,
+                              inum: u32,
+                              dest: &Path)
+                              -> Result<(), fs::FsError> {
+    let inode = fs.read_inode(fs::ROOT_DEV, inum)?;
+    match inode.type_ {
+        fs::InodeType::Directory => {
+            std::fs::create_dir_all(dest).expect("Could not create directory");
+            for item in fs.readdir(&inode) {
+                let item = item?;
+                if item.name() == b"." || item.name() == b".." {
+                    continue;
+                }
+                let name = String::from_utf8_lossy(item.name()).into_owned();
+                extract_inode(fs, item.inumber, &dest.join(name))?;
+            }
+        }
+        fs::InodeType::Symlink => {
+            let target = String::from_utf8_lossy(&read_file(fs, inum)?).into_owned();
+            std::os::unix::fs::symlink(target, dest).expect("Could not create symlink");
+        }
+        fs::InodeType::Device => {
+            // making device nodes on the host needs root, and they'd name the host's devices
+            println!("Skipping device file {}", dest.display());
+            return Ok(());
+        }
+        _ => {
+            let contents = read_file(fs, inum)?;
+            let mut f = std::fs::File::create(dest).expect("Could not create file");
+            f.write_all(&contents).expect("Could not write file");
+        }
+    }
+    println!("{}", dest.display());
+    Ok(())
+}
+
+fn read_file<T: fs::Disk>(fs: &fs::FileSystem<T>, inum: u32) -> Result<Vec<u8>, fs::FsError> {
+    let inode = fs.read_inode(fs::ROOT_DEV, inum)?;
+    let mut buf = vec![0; inode.size as usize];
+    fs.read(&inode, &mut buf, 0)?;
+    Ok(buf)
+}
+
+// Groups sorted (index, address) pairs into runs where the index counts up by one and the address
+// counts up or down by one, as (first index, first address, last address, length).  The free list
+// hands out blocks from the top down, so files usually end up in descending runs.
+fn runs(pairs: &[(u32, u32)]) -> Vec<(u32, u32, u32, u32)> {
+    let mut runs: Vec<(u32, u32, u32, u32)> = vec![];
+    for &(i, addr) in pairs {
+        if let Some(run) = runs.last_mut() {
+            let (first, start, end, len) = *run;
+            let up = addr == end + 1 && (len == 1 || end > start);
+            let down = addr + 1 == end && (len == 1 || end < start);
+            if first + len == i && (up || down) {
+                *run = (first, start, addr, len + 1);
+                continue;
+            }
+        }
+        runs.push((i, addr, addr, 1));
+    }
+    runs
+}
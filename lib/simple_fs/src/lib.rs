@@ -1,16 +1,30 @@
 #![no_std]
 #![feature(iterator_step_by)]
+#![feature(alloc)]
 #![allow(dead_code)]
 
-extern crate slice_cast;
+extern crate alloc;
+
+mod cache;
+mod encoding;
+mod log;
+mod ram_disk;
+pub use cache::{BufferCache, NBUF};
+pub use encoding::{bit_is_set, block_addr, set_bit, set_block_addr};
+pub use log::{LOGSIZE, MAXOPBLOCKS};
+pub use ram_disk::RamDisk;
+use log::Log;
+
+use alloc::vec::Vec;
 use core::num::Wrapping;
-use core::cmp::min;
-use core::mem::size_of;
+use core::cmp::{max, min};
 
 pub const NDIRECT: usize = 64;
-pub const NINDIRECT: usize = 0;
+pub const NINDIRECT: usize = 1; // number of singly-indirect blocks per inode
+pub const NDINDIRECT: usize = 1; // number of doubly-indirect blocks per inode
 // max file size, based on the number of blocks addressible (direct and indirect)
-pub const MAXFILE: usize = NDIRECT + INDIRECT_PER_BLOCK * NINDIRECT;
+pub const MAXFILE: usize = NDIRECT + INDIRECT_PER_BLOCK * NINDIRECT +
+                           INDIRECT_PER_BLOCK * INDIRECT_PER_BLOCK * NDINDIRECT;
 
 pub const BLOCKSIZE: usize = 512;
 
@@ -21,29 +35,51 @@ pub const BLOCKADDR_SIZE: usize = 4; // block address size in bytes.  32-bit
 
 pub const INDIRECT_PER_BLOCK: usize = BLOCKSIZE / BLOCKADDR_SIZE;
 
-// Rust doesn't support compile-time sizeof, so we manually compute this :(
-pub const INODE_SIZE: usize = 62;
-pub const NUM_INODES: u32 = 256;
+// sizes of the on-disk structures, as encoded by the routines in encoding.rs
+pub const SUPERBLOCK_SIZE: usize = 40;
+pub const INODE_SIZE: usize = 292;
+pub const DIRENT_SIZE: usize = 260;
+
+pub const INODES_PER_BLOCK: usize = BLOCKSIZE / INODE_SIZE;
+pub const BITS_PER_BLOCK: u32 = (BLOCKSIZE * 8) as u32; // blocks tracked per bitmap block
+pub const NUM_INODES: u32 = 256; // default number of inodes mkfs creates
 
 pub const DIRNAME_SIZE: usize = 254;
 
-pub const SUPERBLOCK_ADDR: u32 = 0;
-pub const UNUSED_BLOCKADDR: u32 = 0;
-pub const UNUSED_INUM: u32 = ::core::u32::MAX;
+pub const MAXSYMLINKS: u32 = 16; // symbolic links a single path lookup will follow
+pub const SYMLINK_MAX: usize = BLOCKSIZE; // longest path a symbolic link can hold
 
+// how many bytes of a file resize() releases per transaction.  One indirect block's worth
+// touches at most two indirect blocks and a couple of freelist blocks
+const TRUNCATE_CHUNK: u32 = (INDIRECT_PER_BLOCK * BLOCKSIZE) as u32;
+// how many bytes write_file() and resize() write per transaction, which is bounded the same way
+const WRITE_CHUNK: usize = INDIRECT_PER_BLOCK * BLOCKSIZE;
 
+pub const FSMAGIC: u32 = 0x53504653; // "SPFS", at the start of every superblock
+pub const FSVERSION: u32 = 3; // bumped whenever the on-disk format changes
+// Version 1 images predate the block bitmap, and versions 1 and 2 predate inode timestamps, link
+// counts and modes.  The fields they lack decode as zeros, which still read sensibly: a zero
+// bitmap_start means the free list is in use, and an nlink of zero is treated like one.
+pub const FSVERSION_MIN: u32 = 1;
 
-macro_rules! INODE_SIZE {
-    {} => { size_of::<Inode>() }
-}
+pub const DEFAULT_FILE_MODE: u16 = 0o644; // permission bits for new files
+pub const DEFAULT_DIR_MODE: u16 = 0o755; // permission bits for new directories
 
-macro_rules! INODES_PER_BLOCK {
-    {} => { BLOCKSIZE / INODE_SIZE!() }
-}
+pub const SUPERBLOCK_ADDR: u32 = 0;
+pub const UNUSED_BLOCKADDR: u32 = 0;
+pub const UNUSED_INUM: u32 = ::core::u32::MAX;
 
 // given an inumber, which block does the inode live in?
 macro_rules! IBLOCK {
-    {$i: expr, $sb: expr} => { $sb.inode_start + $i /((BLOCKSIZE / INODE_SIZE!()) as u32) }
+    {$i: expr, $sb: expr} => { $sb.inode_start + $i / (INODES_PER_BLOCK as u32) }
+}
+
+// and where in that block does it start?  This is `inum % INODES_PER_BLOCK * INODE_SIZE`, with
+// the remainder spelled out as a subtraction: only one inode fits in a block for now, and clippy
+// rejects `% 1` outright.
+fn inode_offset(inum: u32) -> usize {
+    let first = inum as usize / INODES_PER_BLOCK * INODES_PER_BLOCK;
+    (inum as usize - first) * INODE_SIZE
 }
 
 
@@ -51,294 +87,619 @@ pub trait Disk {
     fn read(&self, buffer: &mut [u8], device: u32, sector: u32) -> Result<(), DiskError>;
     fn write(&mut self, buffer: &[u8], device: u32, sector: u32) -> Result<usize, DiskError>;
     fn sector_size() -> usize;
+
+    /// Flushes any writes the disk is holding on to.  Disks that write straight through don't
+    /// need to do anything here.
+    fn sync(&mut self) -> Result<(), DiskError> {
+        Ok(())
+    }
 }
+
 pub struct FileSystem<T>
     where T: Disk
 {
     pub disk: T,
+    log: Log,
+    clock: fn() -> u32, // current time in seconds since the Unix epoch, for inode timestamps
+}
+
+// the clock until someone provides a real one, which stamps everything with the epoch
+fn no_clock() -> u32 {
+    0
 }
 
 impl<T> FileSystem<T>
     where T: Disk
 {
+    /// Wraps a disk without looking at what's on it.  Writes aren't logged until the file system
+    /// is mounted, which is what mkfs wants while it lays out a fresh image.
     pub fn new(driver: T) -> FileSystem<T> {
-        FileSystem { disk: driver }
+        FileSystem {
+            disk: driver,
+            log: Log::new(),
+            clock: no_clock,
+        }
     }
 
-    pub fn alloc_inode(&mut self, device: u32, inode: Inode) -> Result<u32, FsError> {
+    /// Sets where inode timestamps come from: a function returning the current time in seconds
+    /// since the Unix epoch
+    pub fn set_clock(&mut self, clock: fn() -> u32) {
+        self.clock = clock;
+    }
+
+    pub fn now(&self) -> u32 {
+        (self.clock)()
+    }
+
+    /// An empty inode of the given type, with one link, the default mode for its type, and
+    /// timestamps set to now.  It isn't on disk until it's passed to `alloc_inode`.
+    pub fn new_inode(&self, type_: InodeType, device: u32) -> Inode {
+        let now = self.now();
+        Inode {
+            type_: type_,
+            mode: if type_ == InodeType::Directory {
+                DEFAULT_DIR_MODE
+            } else {
+                DEFAULT_FILE_MODE
+            },
+            device: device,
+            nlink: 1,
+            ctime: now,
+            mtime: now,
+            ..UNUSED_INODE
+        }
+    }
 
-        assert_ne!(inode.type_, InodeType::Unused);
+    /// Opens the file system on a disk, replaying any transaction that was committed to the log
+    /// but not yet installed when we last went down.  Fails if the disk doesn't hold a file
+    /// system in a format we understand.
+    pub fn mount(driver: T) -> Result<FileSystem<T>, FsError> {
+        let mut fs = FileSystem::new(driver);
+        let sb = fs.superblock(ROOT_DEV)?;
+        sb.validate()?;
+
+        if sb.log_start != 0 {
+            fs.log.init(ROOT_DEV, sb.log_start, sb.nlog);
+            fs.log.recover(&mut fs.disk)?;
+        }
 
-        // Read superblock to get the ilist start
-        let mut sb_buf = [0; 512];
-        self.disk.read(&mut sb_buf, device, SUPERBLOCK_ADDR)?;
-        let sb = buffer_to_sb(&mut sb_buf);
+        Ok(fs)
+    }
 
-        let inodes_per_block: u32 = (BLOCKSIZE / INODE_SIZE!()) as u32;
-        let mut block: [u8; BLOCKSIZE] = [0; BLOCKSIZE];
+    /// Lays out an empty file system on the first `nblocks` blocks of the disk: the superblock,
+    /// then the log header and log blocks, then room for `ninodes` inodes, then the free block
+    /// bitmap if `allocator` asks for one, and finally the data blocks, all free except the ones
+    /// holding the root directory.
+    pub fn format(&mut self,
+                  nblocks: u32,
+                  ninodes: u32,
+                  allocator: BlockAllocator)
+                  -> Result<(), FsError> {
+        let log_start = 1;
+        let mut sb = SuperBlock {
+            magic: FSMAGIC,
+            version: FSVERSION,
+            size: 0,
+            nblocks: nblocks,
+            ninodes: ninodes,
+            inode_start: log_start + 1 + LOGSIZE as u32,
+            freelist_start: UNUSED_BLOCKADDR,
+            log_start: log_start,
+            nlog: LOGSIZE as u32,
+            bitmap_start: UNUSED_BLOCKADDR,
+        };
+        if allocator == BlockAllocator::Bitmap {
+            sb.bitmap_start = sb.inode_start + sb.ilist_blocks();
+        }
+        sb.validate()?;
 
-        for blockno in sb.inode_start..(NUM_INODES / inodes_per_block) + 1 {
-            self.disk.read(&mut block, device, blockno as u32)?;
-            // unsafe because we're playing a dangerous game with types and memory
+        let mut buf = [0; BLOCKSIZE];
+        sb.encode(&mut buf);
+        self.disk.write(&buf, ROOT_DEV, SUPERBLOCK_ADDR)?;
 
-            let inum;
-            {
-                let inodes: &mut [Inode] = unsafe {
-                    slice_cast::cast_mut(&mut block[..INODES_PER_BLOCK!() * INODE_SIZE!()])
-                };
-                inum = inodes.iter().position(|y| y.type_ == InodeType::Unused);
+        // start with an empty log, so mounting the disk doesn't replay garbage
+        self.disk.write(&[0; BLOCKSIZE], ROOT_DEV, log_start)?;
+
+        let unused = Inode { device: ROOT_DEV, ..UNUSED_INODE };
+        for inum in 0..ninodes {
+            self.update_inode(inum, &unused)?;
+        }
 
-                if let Some(i) = inum {
-                    inodes[i] = inode;
+        match allocator {
+            BlockAllocator::FreeList => {
+                for blockno in sb.data_start()..nblocks {
+                    self.free_block(ROOT_DEV, blockno)?;
                 }
             }
-
-            if let Some(i) = inum {
-                self.disk.write(&block, device, blockno as u32)?;
-                return Ok((blockno - sb.inode_start) * (INODES_PER_BLOCK!() as u32) + i as u32);
+            BlockAllocator::Bitmap => {
+                // everything before the data blocks is in use, and the rest is free
+                for i in 0..sb.bitmap_blocks() {
+                    self.disk.write(&[0; BLOCKSIZE], ROOT_DEV, sb.bitmap_start + i)?;
+                }
+                self.bitmap_mark(ROOT_DEV, &sb, 0, sb.data_start(), true)?;
             }
+        }
+
+        // the root directory is its own parent.  No entry names it, but it keeps its one link
+        let mut root = self.new_inode(InodeType::Directory, ROOT_DEV);
+        let inum = self.alloc_inode(ROOT_DEV, root)?;
+        assert_eq!(inum, ROOT_INUM);
+        self.dir_add(&mut root, b".", ROOT_INUM)?;
+        self.dir_add(&mut root, b"..", ROOT_INUM)?;
+        self.update_inode(ROOT_INUM, &root)
+    }
+
+    /// Writes back anything the underlying disk has cached
+    pub fn sync(&mut self) -> Result<(), FsError> {
+        self.disk.sync()?;
+        Ok(())
+    }
+
+    pub fn superblock(&self, device: u32) -> Result<SuperBlock, FsError> {
+        let mut sb_buf = [0; BLOCKSIZE];
+        self.bread(&mut sb_buf, device, SUPERBLOCK_ADDR)?;
+        Ok(SuperBlock::decode(&sb_buf))
+    }
 
+    pub fn write_superblock(&mut self, device: u32, sb: &SuperBlock) -> Result<(), FsError> {
+        self.transaction(|fs| {
+            let mut sb_buf = [0; BLOCKSIZE];
+            fs.bread(&mut sb_buf, device, SUPERBLOCK_ADDR)?;
+            sb.encode(&mut sb_buf);
+            fs.bwrite(&sb_buf, device, SUPERBLOCK_ADDR)
+        })
+    }
+
+    /// Number of blocks waiting in the log to be installed.  This is only nonzero if we went down
+    /// after committing a transaction, and mounting installs them.
+    pub fn log_pending(&self, device: u32) -> Result<u32, FsError> {
+        let sb = self.superblock(device)?;
+        if sb.log_start == 0 {
+            return Ok(0);
         }
 
-        Err(FsError::ExhaustedInodes)
+        let mut buf = [0; BLOCKSIZE];
+        self.disk.read(&mut buf, device, sb.log_start)?;
+        Ok(log::pending(&buf))
     }
 
-    pub fn read_inode(&self, device: u32, inum: u32) -> Result<Inode, FsError> {
+    /// Starts a file system operation.  Every metadata write until the matching `end_op` belongs
+    /// to the same transaction, and reaches the disk atomically.  Operations can nest, in which
+    /// case the transaction commits when the outermost one ends.
+    pub fn begin_op(&mut self) {
+        self.log.begin();
+    }
 
-        assert!(inum <= NUM_INODES);
-        // read superblock to get list start
-        let mut sb_buf = [0; 512];
-        self.disk.read(&mut sb_buf, device, SUPERBLOCK_ADDR)?;
-        let superblock = buffer_to_sb(&mut sb_buf);
+    /// Ends a file system operation, committing the transaction if this was the outermost one
+    pub fn end_op(&mut self) -> Result<(), FsError> {
+        if self.log.end() {
+            self.log.commit(&mut self.disk)?;
+        }
+        Ok(())
+    }
 
-        // read block containing the inode
-        let mut buf = [0; 512];
-        self.disk.read(&mut buf, device, IBLOCK!(inum, superblock) as u32)?;
+    // run an operation as (part of) a transaction
+    fn transaction<F, R>(&mut self, op: F) -> Result<R, FsError>
+        where F: FnOnce(&mut Self) -> Result<R, FsError>
+    {
+        self.begin_op();
+        let result = op(self);
+        let committed = self.end_op();
+
+        match (result, committed) {
+            (Err(e), _) | (Ok(_), Err(e)) => Err(e),
+            (Ok(r), Ok(())) => Ok(r),
+        }
+    }
 
-        {
-            let inodes: &mut [Inode] =
-                unsafe { slice_cast::cast_mut(&mut buf[..INODES_PER_BLOCK!() * INODE_SIZE!()]) };
-            let offset = (inum as usize) % INODES_PER_BLOCK!();
-            Ok(inodes[offset])
+    // read a block, including any changes the current transaction hasn't committed yet
+    fn bread(&self, buffer: &mut [u8], device: u32, blockno: u32) -> Result<(), FsError> {
+        if !self.log.read(buffer, device, blockno) {
+            self.disk.read(buffer, device, blockno)?;
         }
+        Ok(())
     }
 
-    pub fn update_inode(&mut self, inum: u32, inode: &Inode) -> Result<(), FsError> {
+    // write a metadata block through the log
+    fn bwrite(&mut self, buffer: &[u8], device: u32, blockno: u32) -> Result<(), FsError> {
+        if !self.log.write(&self.disk, buffer, device, blockno)? {
+            self.disk.write(buffer, device, blockno)?;
+        }
+        Ok(())
+    }
+
+    // write a block of file contents, which doesn't go through the log unless the transaction
+    // already holds the block
+    fn bwrite_data(&mut self, buffer: &[u8], device: u32, blockno: u32) -> Result<(), FsError> {
+        if !self.log.write_through(buffer, device, blockno) {
+            self.disk.write(buffer, device, blockno)?;
+        }
+        Ok(())
+    }
+
+    pub fn alloc_inode(&mut self, device: u32, inode: Inode) -> Result<u32, FsError> {
+        self.transaction(|fs| {
+            assert_ne!(inode.type_, InodeType::Unused);
+
+            // Read superblock to get the ilist start
+            let sb = fs.superblock(device)?;
+
+            let inodes_per_block = INODES_PER_BLOCK as u32;
+            let mut block: [u8; BLOCKSIZE] = [0; BLOCKSIZE];
+
+            for blockno in sb.inode_start..sb.data_start() {
+                fs.bread(&mut block, device, blockno as u32)?;
+
+                // the last block of the ilist can have room for more inodes than the superblock
+                // says there are
+                let first = (blockno - sb.inode_start) * inodes_per_block;
+                let count = min(inodes_per_block, sb.ninodes - first) as usize;
+
+                for i in 0..count {
+                    let slot = &mut block[i * INODE_SIZE..(i + 1) * INODE_SIZE];
+                    if Inode::decode(slot)?.type_ == InodeType::Unused {
+                        inode.encode(slot);
+                        fs.bwrite(&block, device, blockno as u32)?;
+                        return Ok(first + i as u32);
+                    }
+                }
+            }
+
+            Err(FsError::ExhaustedInodes)
+        })
+    }
+
+    pub fn read_inode(&self, device: u32, inum: u32) -> Result<Inode, FsError> {
         // read superblock to get list start
-        let mut sb_buf = [0; 512];
-        self.disk.read(&mut sb_buf, inode.device, SUPERBLOCK_ADDR)?;
-        let superblock = buffer_to_sb(&mut sb_buf);
+        let superblock = self.superblock(device)?;
+        // a corrupt directory entry can name an inode that doesn't exist
+        if inum >= superblock.ninodes {
+            return Err(FsError::BadGeometry);
+        }
 
         // read block containing the inode
         let mut buf = [0; 512];
-        self.disk.read(&mut buf, inode.device, IBLOCK!(inum, superblock) as u32)?;
+        self.bread(&mut buf, device, IBLOCK!(inum, superblock) as u32)?;
 
-        {
-            let inodes: &mut [Inode] =
-                unsafe { slice_cast::cast_mut(&mut buf[..INODES_PER_BLOCK!() * INODE_SIZE!()]) };
-            let offset = (inum as usize) % INODES_PER_BLOCK!();
-            inodes[offset] = *inode;
-        }
+        Inode::decode(&buf[inode_offset(inum)..])
+    }
 
-        // write back ilist block with the updated inode
-        self.disk.write(&buf, inode.device, IBLOCK!(inum, superblock) as u32)?;
+    pub fn update_inode(&mut self, inum: u32, inode: &Inode) -> Result<(), FsError> {
+        self.transaction(|fs| {
+            // read superblock to get list start
+            let superblock = fs.superblock(inode.device)?;
 
-        Ok(())
+            // read block containing the inode
+            let mut buf = [0; 512];
+            fs.bread(&mut buf, inode.device, IBLOCK!(inum, superblock) as u32)?;
+
+            inode.encode(&mut buf[inode_offset(inum)..]);
+
+            // write back ilist block with the updated inode
+            fs.bwrite(&buf, inode.device, IBLOCK!(inum, superblock) as u32)?;
+
+            Ok(())
+        })
     }
 
-    fn alloc_block(&mut self, device: u32) -> Result<u32, FsError> {
-        let mut block: [u8; 512] = [0; 512];
+    /// Takes a free block and zeroes it
+    pub fn alloc_block(&mut self, device: u32) -> Result<u32, FsError> {
+        self.transaction(|fs| {
+            let sb = fs.superblock(device)?;
+            if sb.bitmap_start == UNUSED_BLOCKADDR {
+                fs.freelist_alloc(device, sb)
+            } else {
+                fs.bitmap_alloc(device, &sb, 1)
+            }
+        })
+    }
 
-        // read superblock to get freelist head
-        self.disk.read(&mut block, device, SUPERBLOCK_ADDR)?;
+    /// Takes `len` free blocks that sit next to each other on the disk, zeroes them, and returns
+    /// the first one.  Only file systems with a block bitmap can find runs of free blocks.
+    pub fn alloc_extent(&mut self, device: u32, len: u32) -> Result<u32, FsError> {
+        assert!(len > 0);
+        self.transaction(|fs| {
+            let sb = fs.superblock(device)?;
+            if sb.bitmap_start == UNUSED_BLOCKADDR {
+                return Err(FsError::Unsupported);
+            }
+            fs.bitmap_alloc(device, &sb, len)
+        })
+    }
 
-        let head_addr = buffer_to_sb(&mut block).freelist_start;
+    pub fn free_block(&mut self, device: u32, blockno: u32) -> Result<(), FsError> {
+        self.transaction(|fs| {
+            let sb = fs.superblock(device)?;
+            if sb.bitmap_start == UNUSED_BLOCKADDR {
+                fs.freelist_free(device, sb, blockno)
+            } else {
+                fs.bitmap_mark(device, &sb, blockno, 1, false)
+            }
+        })
+    }
+
+    // take the last free block listed in the head block of the free list, or the head block
+    // itself once it lists nothing else
+    fn freelist_alloc(&mut self, device: u32, mut sb: SuperBlock) -> Result<u32, FsError> {
+        let mut block: [u8; 512] = [0; 512];
+
+        let head_addr = sb.freelist_start;
         if head_addr == UNUSED_BLOCKADDR {
             return Err(FsError::ExhaustedBlocks); // no more blocks we can allocate!!!
         }
 
-        self.disk.read(&mut block, device, head_addr)?; // read head of list
+        self.bread(&mut block, device, head_addr)?; // read head of list
 
-        let free_idx: Option<_> = {
-            let freelist: &mut [u32] = unsafe { slice_cast::cast_mut(&mut block) };
-            // free_idx is small by one, since we ignore first item of the list (points to next
-            // block)
-            freelist.iter().skip(1).rposition(|x| *x != UNUSED_BLOCKADDR)
-        };
+        // the first item of the list points to the next block, so it isn't a candidate
+        let free_idx = (1..INDIRECT_PER_BLOCK)
+            .rev()
+            .find(|&i| block_addr(&block, i) != UNUSED_BLOCKADDR);
 
         match free_idx {
             // The head of the freelist has a free block in it
             Some(index) => {
                 // Take our new free block addr and mark it as used
-                let new_blockno;
-                {
-                    let freelist: &mut [u32] = unsafe { slice_cast::cast_mut(&mut block) };
-                    new_blockno = freelist[index + 1];
-                    freelist[index + 1] = UNUSED_BLOCKADDR;
-                };
+                let new_blockno = block_addr(&block, index);
+                set_block_addr(&mut block, index, UNUSED_BLOCKADDR);
 
                 // Write out the freelist element with the removed address
-                self.disk.write(&block, device, head_addr)?;
-                self.disk.write(&[0; 512], device, new_blockno)?;
+                self.bwrite(&block, device, head_addr)?;
+                // the new block was free, so zeroing it doesn't need to be part of the
+                // transaction
+                self.bwrite_data(&[0; 512], device, new_blockno)?;
 
                 Ok(new_blockno)
             }
 
             // The head of the freelist *is* the new block we're allocating
             None => {
-                let freelist: &mut [u32] = unsafe { slice_cast::cast_mut(&mut block) };
-                let new_head = freelist[0];
-
-                let mut sb_buf = [0; 512];
-                self.disk.read(&mut sb_buf, device, SUPERBLOCK_ADDR)?; // read superblock to get freelist head
-                {
-                    // update superblock to point to the new head of the freelist
-                    let sb: &mut SuperBlock = buffer_to_sb(&mut sb_buf);
-                    sb.freelist_start = new_head;
-                }
+                // update superblock to point to the new head of the freelist
+                sb.freelist_start = block_addr(&block, 0);
 
-                // zero out the allocated block and write back the updated superblock
-                self.disk.write(&[0; 512], device, head_addr)?;
-                self.disk.write(&sb_buf, device, SUPERBLOCK_ADDR)?;
+                // zero out the allocated block and write back the updated superblock.  The old
+                // superblock still points at this block until the transaction commits, so the
+                // zeroing (and whatever gets written here next) has to go through the log too
+                self.bwrite(&[0; 512], device, head_addr)?;
+                self.write_superblock(device, &sb)?;
 
                 Ok(head_addr)
             }
         }
     }
 
-    fn free_inode(&mut self, _device: u32, _inumber: u32) -> Result<(), FsError> {
-        unimplemented!();
-    }
-
-    pub fn free_block(&mut self, device: u32, blockno: u32) -> Result<(), FsError> {
-        let mut sb_buf = [0u8; 512];
+    // list a block in the head block of the free list, or make it the new head block if the head
+    // is full
+    fn freelist_free(&mut self,
+                     device: u32,
+                     mut sb: SuperBlock,
+                     blockno: u32)
+                     -> Result<(), FsError> {
         let mut buffer = [0u8; 512];
 
-        // read superblock to get freelist head
-        self.disk.read(&mut sb_buf, device, SUPERBLOCK_ADDR)?;
-
-        let head_addr = buffer_to_sb(&mut sb_buf).freelist_start;
+        let head_addr = sb.freelist_start;
         if head_addr == UNUSED_BLOCKADDR {
             // update the superblock
-            buffer_to_sb(&mut sb_buf).freelist_start = blockno;
-            self.disk.write(&sb_buf, device, SUPERBLOCK_ADDR)?;
-
-            // and zero out our new head of the freelist
-            {
-                let freeblock: &mut [u32] = unsafe { slice_cast::cast_mut(&mut buffer) };
-                for i in freeblock.iter_mut() {
-                    *i = UNUSED_BLOCKADDR;
-                }
-            }
-
-            // write back the new link and exit
-            self.disk.write(&buffer, device, blockno)?;
-
+            sb.freelist_start = blockno;
+            self.write_superblock(device, &sb)?;
 
+            // and zero out our new head of the freelist, then write back the new link and
+            // exit
+            self.bwrite(&buffer, device, blockno)?;
             return Ok(());
         }
 
         // get the head block of the freelist
-        self.disk.read(&mut buffer, device, head_addr)?;
+        self.bread(&mut buffer, device, head_addr)?;
 
-        let pos = {
-            let freeblock: &mut [u32] = unsafe { slice_cast::cast_mut(&mut buffer) };
-            freeblock.iter_mut().skip(1).position(|&mut x| x == UNUSED_BLOCKADDR)
-        };
+        let pos = (1..INDIRECT_PER_BLOCK)
+            .find(|&i| block_addr(&buffer, i) == UNUSED_BLOCKADDR);
 
         if let Some(p) = pos {
-            // if there's a free space in this block, put ourselves in the free list and write back
-            {
-                let freeblock: &mut [u32] = unsafe { slice_cast::cast_mut(&mut buffer) };
-                freeblock[p + 1] = blockno;
-            }
-            self.disk.write(&buffer, device, head_addr)?;
+            // if there's a free space in this block, put ourselves in the free list and write
+            // back
+            set_block_addr(&mut buffer, p, blockno);
+            self.bwrite(&buffer, device, head_addr)?;
         } else {
             // else we *are* the new head of the free list
 
             // update the superblock to point to us
-            buffer_to_sb(&mut sb_buf).freelist_start = blockno;
-            self.disk.write(&sb_buf, device, SUPERBLOCK_ADDR)?;
-
-            // zero out our block
-            {
-                let freeblock: &mut [u32] = unsafe { slice_cast::cast_mut(&mut buffer) };
-                for i in freeblock.iter_mut().skip(1) {
-                    *i = UNUSED_BLOCKADDR;
-                }
-                // set our block's next addr to point to the old head
-                freeblock[0] = head_addr;
-            }
+            sb.freelist_start = blockno;
+            self.write_superblock(device, &sb)?;
+
+            // zero out our block, and set our block's next addr to point to the old head
+            let mut buffer = [0u8; 512];
+            set_block_addr(&mut buffer, 0, head_addr);
 
             // write back the new link and exit
-            self.disk.write(&buffer, device, blockno)?;
+            self.bwrite(&buffer, device, blockno)?;
         }
 
         Ok(())
     }
 
-    pub fn dir_add(&mut self, dir: &mut Inode, name: &[u8], target: u32) -> Result<(), FsError> {
-        // Don't add if it's already present
-        if self.dir_lookup(dir, name).is_ok() {
-            return Err(FsError::EntryExists);
-        }
-
-        let dirent_size = size_of::<DirEntry>();
-        let new_dirent = DirEntry {
-            inumber: target,
-            name: {
-                let mut n = [0; DIRNAME_SIZE];
-                n[..name.len()].copy_from_slice(name);
-                n
-            },
-        };
-
-        // search the dir for a free slot in the existing dir file
-        for offset in Iterator::step_by(0..dir.size, dirent_size) {
-            let mut tmp_buf = [0; BLOCKSIZE];
-            assert_eq!(self.read(dir, &mut tmp_buf[..dirent_size], offset)?,
-                       dirent_size);
+    // Finds the first run of `len` free blocks in the bitmap, marks them used, and zeroes them
+    fn bitmap_alloc(&mut self, device: u32, sb: &SuperBlock, len: u32) -> Result<u32, FsError> {
+        let mut block = [0; BLOCKSIZE];
+        let mut loaded = UNUSED_BLOCKADDR; // the bitmap block that's in `block`
+        let mut start = 0;
+        let mut run = 0;
+
+        for blockno in sb.data_start()..sb.nblocks {
+            let bitmap_block = sb.bitmap_start + blockno / BITS_PER_BLOCK;
+            if bitmap_block != loaded {
+                self.bread(&mut block, device, bitmap_block)?;
+                loaded = bitmap_block;
+            }
 
-            let found_empty = {
-                let dirent: &DirEntry = unsafe { slice_cast::cast_to(&tmp_buf[..dirent_size]) };
-                dirent.inumber == UNUSED_INUM
-            };
+            if bit_is_set(&block, blockno % BITS_PER_BLOCK) {
+                run = 0;
+                continue;
+            }
+            if run == 0 {
+                start = blockno;
+            }
+            run += 1;
 
-            if found_empty {
-                {
-                    let dirent: &mut DirEntry =
-                        unsafe { slice_cast::cast_to_mut(&mut tmp_buf[..dirent_size]) };
-                    *dirent = new_dirent;
+            if run == len {
+                self.bitmap_mark(device, sb, start, len, true)?;
+                // the blocks were free, so zeroing them doesn't need to be part of the transaction
+                for b in start..start + len {
+                    self.bwrite_data(&[0; BLOCKSIZE], device, b)?;
                 }
-                self.write(dir, &tmp_buf[..dirent_size], offset)?;
+                return Ok(start);
+            }
+        }
+
+        Err(FsError::ExhaustedBlocks)
+    }
+
+    // mark `len` blocks starting at `start` used or free in the bitmap
+    fn bitmap_mark(&mut self,
+                   device: u32,
+                   sb: &SuperBlock,
+                   start: u32,
+                   len: u32,
+                   used: bool)
+                   -> Result<(), FsError> {
+        let mut block = [0; BLOCKSIZE];
+        let mut blockno = start;
+        while blockno < start + len {
+            // update every bit that lives in this block of the bitmap before writing it back
+            let index = blockno / BITS_PER_BLOCK;
+            self.bread(&mut block, device, sb.bitmap_start + index)?;
+            while blockno < start + len && blockno / BITS_PER_BLOCK == index {
+                set_bit(&mut block, blockno % BITS_PER_BLOCK, used);
+                blockno += 1;
+            }
+            self.bwrite(&block, device, sb.bitmap_start + index)?;
+        }
+        Ok(())
+    }
+
+    /// Releases an inode and all of the blocks it maps, marking it unused in the ilist
+    pub fn free_inode(&mut self, device: u32, inumber: u32) -> Result<(), FsError> {
+        let mut inode = self.read_inode(device, inumber)?;
+        self.resize(inumber, &mut inode, 0)?;
+
+        let unused = Inode { device: device, ..UNUSED_INODE };
+        self.update_inode(inumber, &unused)
+    }
+
+    /// Shrinks or extends an inode to `new_size` bytes like `truncate`, and writes it back.
+    /// Blocks are released or zeroed a piece at a time so that no single transaction outgrows the
+    /// log, however big the file is.  The inode is written back with each piece, so it never
+    /// points at a freed block.
+    pub fn resize(&mut self,
+                  inumber: u32,
+                  inode: &mut Inode,
+                  new_size: u32)
+                  -> Result<(), FsError> {
+        loop {
+            // each transaction moves the size one piece closer, through `inode`
+            let size = inode.size;
+            let next = if size == new_size {
                 return Ok(());
+            } else if size > new_size {
+                max(size.saturating_sub(TRUNCATE_CHUNK), new_size)
+            } else {
+                min(size.saturating_add(WRITE_CHUNK as u32), new_size)
+            };
+            self.transaction(|fs| {
+                fs.truncate(inode, next)?;
+                fs.update_inode(inumber, inode)
+            })?;
+        }
+    }
+
+    /// Writes to a file like `write`, and writes its inode back.  Big writes are split across
+    /// several transactions so that none of them outgrows the log; if one fails, the bytes
+    /// written before it stay written and their count is returned, unless there weren't any.
+    /// Unlike `write`, the offset can be past the end of the file, and the gap is filled with
+    /// zeros first.
+    pub fn write_file(&mut self,
+                      inumber: u32,
+                      inode: &mut Inode,
+                      src_buf: &[u8],
+                      offset: u32)
+                      -> Result<usize, FsError> {
+        if offset > inode.size && !src_buf.is_empty() {
+            if offset as usize + src_buf.len() > MAXFILE * BLOCKSIZE {
+                return Err(FsError::WriteTooLarge);
             }
+            self.resize(inumber, inode, offset)?;
         }
 
-        // We didn't find a free slot, so append a new entry
-        // Copy the new directory entry into the buffer
-        let mut tmp_buf = [0; BLOCKSIZE];
-        {
-            let buf: &mut DirEntry =
-                unsafe { slice_cast::cast_to_mut(&mut tmp_buf[..dirent_size]) };
-            *buf = new_dirent;
+        let mut written = 0;
+        for chunk in src_buf.chunks(WRITE_CHUNK) {
+            let result = self.transaction(|fs| {
+                fs.write(inode, chunk, offset + written as u32)?;
+                fs.update_inode(inumber, inode)
+            });
+            match result {
+                Ok(()) => written += chunk.len(),
+                Err(_) if written > 0 => break,
+                Err(e) => return Err(e),
+            }
         }
+        Ok(written)
+    }
+
+    pub fn dir_add(&mut self, dir: &mut Inode, name: &[u8], target: u32) -> Result<(), FsError> {
+        self.transaction(|fs| {
+            if dir.type_ != InodeType::Directory {
+                return Err(FsError::NotDir);
+            }
+            if name.len() > DIRNAME_SIZE {
+                return Err(FsError::NameTooLong);
+            }
 
-        // write the new entry
-        let offset = dir.size;
-        self.write(dir, &tmp_buf[..dirent_size], offset)?;
+            // Don't add if it's already present
+            if fs.dir_lookup(dir, name).is_ok() {
+                return Err(FsError::EntryExists);
+            }
 
-        Ok(())
+            let new_dirent = DirEntry {
+                inumber: target,
+                name: {
+                    let mut n = [0; DIRNAME_SIZE];
+                    n[..name.len()].copy_from_slice(name);
+                    n
+                },
+            };
+            let mut dirent_buf = [0; DIRENT_SIZE];
+            new_dirent.encode(&mut dirent_buf);
+
+            // search the dir for a free slot in the existing dir file
+            for offset in Iterator::step_by(0..dir.size, DIRENT_SIZE) {
+                let mut tmp_buf = [0; DIRENT_SIZE];
+                // a directory that ends partway through an entry is corrupt
+                if fs.read(dir, &mut tmp_buf, offset)? != DIRENT_SIZE {
+                    return Err(FsError::BadGeometry);
+                }
+
+                if DirEntry::decode(&tmp_buf).inumber == UNUSED_INUM {
+                    fs.write(dir, &dirent_buf, offset)?;
+                    return Ok(());
+                }
+            }
+
+            // We didn't find a free slot, so append the new entry
+            let offset = dir.size;
+            fs.write(dir, &dirent_buf, offset)?;
+
+            Ok(())
+        })
     }
 
     pub fn dir_lookup(&self, dir: &Inode, name: &[u8]) -> Result<(u32, usize), FsError> {
-        assert_eq!(dir.type_, InodeType::Directory, "{:?}", dir.type_);
-        let dirent_size = size_of::<DirEntry>();
-        for offset in Iterator::step_by(0..dir.size, dirent_size) {
-            let mut buf = [0; BLOCKSIZE];
-            self.read(dir, &mut buf[..dirent_size], offset)?;
+        if dir.type_ != InodeType::Directory {
+            return Err(FsError::NotDir);
+        }
+        for offset in Iterator::step_by(0..dir.size, DIRENT_SIZE) {
+            let mut buf = [0; DIRENT_SIZE];
+            self.read(dir, &mut buf, offset)?;
             // read this as a directory entry
-            let entry: &DirEntry = unsafe { slice_cast::cast_to(&buf[..dirent_size]) };
+            let entry = DirEntry::decode(&buf);
             if entry.inumber == UNUSED_INUM {
                 continue;
             }
@@ -363,19 +724,125 @@ impl<T> FileSystem<T>
         Err(FsError::NotFound)
     }
 
+    /// Marks the directory entry at `offset` as unused, so its slot can be reused by `dir_add`
+    pub fn dir_remove(&mut self, dir: &mut Inode, offset: u32) -> Result<(), FsError> {
+        self.transaction(|fs| {
+            if dir.type_ != InodeType::Directory {
+                return Err(FsError::NotDir);
+            }
+            let mut tmp_buf = [0; DIRENT_SIZE];
+            let unused = DirEntry {
+                inumber: UNUSED_INUM,
+                name: [0; DIRNAME_SIZE],
+            };
+            unused.encode(&mut tmp_buf);
+
+            fs.write(dir, &tmp_buf, offset)?;
+            Ok(())
+        })
+    }
+
+    /// Checks whether a directory holds any entries other than `.` and `..`
+    pub fn dir_is_empty(&self, dir: &Inode) -> Result<bool, FsError> {
+        if dir.type_ != InodeType::Directory {
+            return Err(FsError::NotDir);
+        }
+        for offset in Iterator::step_by(0..dir.size, DIRENT_SIZE) {
+            let mut buf = [0; DIRENT_SIZE];
+            self.read(dir, &mut buf, offset)?;
+            let entry = DirEntry::decode(&buf);
+
+            let name = entry.name();
+            if entry.inumber != UNUSED_INUM && name != b"." && name != b".." {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Iterates over the entries of a directory that are in use, skipping free slots.  If `dir`
+    /// isn't a directory, the only thing it yields is a `NotDir` error.
+    pub fn readdir(&self, dir: &Inode) -> ReadDir<T> {
+        let is_dir = dir.type_ == InodeType::Directory;
+        ReadDir {
+            fs: self,
+            dir: *dir,
+            offset: if is_dir { 0 } else { dir.size },
+            error: if is_dir { None } else { Some(FsError::NotDir) },
+        }
+    }
+
     /// Maps sequential block of file into a disk block address, or allocates one if the block
-    /// isn't mapped
+    /// isn't mapped.  Indirect blocks are allocated along the way as needed.
     fn bmap_or_alloc(&mut self, inode: &mut Inode, blockno: u32) -> Result<u32, FsError> {
-        let addr = inode.blocks[blockno as usize];
-        if addr == UNUSED_BLOCKADDR {
-            inode.blocks[blockno as usize] = self.alloc_block(inode.device)?;
+        self.bmap_or_assign(inode, blockno, UNUSED_BLOCKADDR)
+    }
+
+    /// Like `bmap_or_alloc`, but an unmapped block is mapped to `addr`, a block the caller has
+    /// already allocated, unless that's UNUSED_BLOCKADDR
+    fn bmap_or_assign(&mut self,
+                      inode: &mut Inode,
+                      blockno: u32,
+                      addr: u32)
+                      -> Result<u32, FsError> {
+        let mut bn = blockno as usize;
+        let device = inode.device;
+
+        if bn < NDIRECT {
+            if inode.blocks[bn] == UNUSED_BLOCKADDR {
+                inode.blocks[bn] = if addr == UNUSED_BLOCKADDR {
+                    self.alloc_block(device)?
+                } else {
+                    addr
+                };
+            }
+            return Ok(inode.blocks[bn]);
+        }
+        bn -= NDIRECT;
+
+        if bn < INDIRECT_PER_BLOCK * NINDIRECT {
+            if inode.indirect == UNUSED_BLOCKADDR {
+                inode.indirect = self.alloc_block(device)?;
+            }
+            return self.indirect_entry_or_assign(device, inode.indirect, bn, addr);
+        }
+        bn -= INDIRECT_PER_BLOCK * NINDIRECT;
+
+        if bn < INDIRECT_PER_BLOCK * INDIRECT_PER_BLOCK * NDINDIRECT {
+            if inode.double_indirect == UNUSED_BLOCKADDR {
+                inode.double_indirect = self.alloc_block(device)?;
+            }
+            let indirect = self.indirect_entry_or_assign(device,
+                                                         inode.double_indirect,
+                                                         bn / INDIRECT_PER_BLOCK,
+                                                         UNUSED_BLOCKADDR)?;
+            return self.indirect_entry_or_assign(device, indirect, bn % INDIRECT_PER_BLOCK, addr);
         }
-        Ok(inode.blocks[blockno as usize])
+
+        Err(FsError::WriteTooLarge)
     }
 
     /// Maps sequential block of file into a disk block address if mapped
     fn bmap(&self, inode: &Inode, blockno: u32) -> Result<u32, FsError> {
-        let addr = inode.blocks[blockno as usize];
+        let mut bn = blockno as usize;
+        let device = inode.device;
+
+        let addr = if bn < NDIRECT {
+            inode.blocks[bn]
+        } else if bn - NDIRECT < INDIRECT_PER_BLOCK * NINDIRECT {
+            bn -= NDIRECT;
+            self.indirect_entry(device, inode.indirect, bn)?
+        } else if bn - NDIRECT - INDIRECT_PER_BLOCK * NINDIRECT <
+                  INDIRECT_PER_BLOCK * INDIRECT_PER_BLOCK * NDINDIRECT {
+            bn -= NDIRECT + INDIRECT_PER_BLOCK * NINDIRECT;
+            let indirect =
+                self.indirect_entry(device, inode.double_indirect, bn / INDIRECT_PER_BLOCK)?;
+            self.indirect_entry(device, indirect, bn % INDIRECT_PER_BLOCK)?
+        } else {
+            UNUSED_BLOCKADDR
+        };
+
         if addr == UNUSED_BLOCKADDR {
             Err(FsError::BlockNotMapped(blockno))
         } else {
@@ -383,13 +850,234 @@ impl<T> FileSystem<T>
         }
     }
 
+    /// Reads the block address stored at `index` in an indirect block.  An unmapped indirect
+    /// block maps nothing, so every entry in it is unused.
+    fn indirect_entry(&self, device: u32, indirect: u32, index: usize) -> Result<u32, FsError> {
+        if indirect == UNUSED_BLOCKADDR {
+            return Ok(UNUSED_BLOCKADDR);
+        }
+
+        let mut block = [0u8; BLOCKSIZE];
+        self.bread(&mut block, device, indirect)?;
+        Ok(block_addr(&block, index))
+    }
+
+    /// Reads the block address stored at `index` in an indirect block.  If the entry is unused,
+    /// it's set to `addr` (or a newly allocated block if `addr` is UNUSED_BLOCKADDR) and the
+    /// indirect block is written back.
+    fn indirect_entry_or_assign(&mut self,
+                                device: u32,
+                                indirect: u32,
+                                index: usize,
+                                addr: u32)
+                                -> Result<u32, FsError> {
+        let mut block = [0u8; BLOCKSIZE];
+        self.bread(&mut block, device, indirect)?;
+
+        let old_addr = block_addr(&block, index);
+        if old_addr != UNUSED_BLOCKADDR {
+            return Ok(old_addr);
+        }
+
+        let new_addr = if addr == UNUSED_BLOCKADDR {
+            self.alloc_block(device)?
+        } else {
+            addr
+        };
+        set_block_addr(&mut block, index, new_addr);
+        self.bwrite(&block, device, indirect)?;
+        Ok(new_addr)
+    }
+
+    /// Shrinks or extends a file to `new_size` bytes.  Blocks past the new end of the file are
+    /// returned to the free list, along with any indirect blocks that no longer map anything, and
+    /// extending a file fills the new space with zeros.  As with `write`, the caller is
+    /// responsible for writing back the updated inode.
+    pub fn truncate(&mut self, inode: &mut Inode, new_size: u32) -> Result<(), FsError> {
+        self.transaction(|fs| {
+            match inode.type_ {
+                InodeType::File | InodeType::Directory | InodeType::Symlink => {}
+                _ => return Err(FsError::TypeMismatch),
+            }
+
+            if new_size > inode.size {
+                if new_size as usize > MAXFILE * BLOCKSIZE {
+                    return Err(FsError::WriteTooLarge);
+                }
+
+                let zeros = [0; BLOCKSIZE];
+                while inode.size < new_size {
+                    let len = min(BLOCKSIZE as u32, new_size - inode.size) as usize;
+                    let offset = inode.size;
+                    fs.write(inode, &zeros[..len], offset)?;
+                }
+                return Ok(());
+            }
+
+            // number of file blocks that still hold data after truncation
+            let keep = (new_size as usize + BLOCKSIZE - 1) / BLOCKSIZE;
+            let device = inode.device;
+
+            for addr in inode.blocks.iter_mut().skip(keep) {
+                if *addr != UNUSED_BLOCKADDR {
+                    fs.free_block(device, *addr)?;
+                    *addr = UNUSED_BLOCKADDR;
+                }
+            }
+
+            let indirect = inode.indirect;
+            if fs.truncate_indirect(device, indirect, 1, NDIRECT, keep)? {
+                inode.indirect = UNUSED_BLOCKADDR;
+            }
+
+            let double_indirect = inode.double_indirect;
+            if fs.truncate_indirect(device,
+                                      double_indirect,
+                                      2,
+                                      NDIRECT + INDIRECT_PER_BLOCK * NINDIRECT,
+                                      keep)? {
+                inode.double_indirect = UNUSED_BLOCKADDR;
+            }
+
+            if inode.size != new_size {
+                inode.size = new_size;
+                inode.mtime = fs.now();
+            }
+            Ok(())
+        })
+    }
+
+    /// Frees the blocks at or past file block `keep` that are mapped through an indirect block.
+    /// `first` is the file block number of the first block the indirect block maps, and `depth`
+    /// its level of indirection.  Returns true if the indirect block itself was freed.
+    fn truncate_indirect(&mut self,
+                         device: u32,
+                         indirect: u32,
+                         depth: u32,
+                         first: usize,
+                         keep: usize)
+                         -> Result<bool, FsError> {
+        if indirect == UNUSED_BLOCKADDR {
+            return Ok(false);
+        }
+
+        // everything this block maps is past the end, so get rid of all of it
+        if keep <= first {
+            self.free_indirect(device, indirect, depth)?;
+            return Ok(true);
+        }
+
+        // how many file blocks each entry of this indirect block covers
+        let span = INDIRECT_PER_BLOCK.pow(depth - 1);
+        if keep >= first + span * INDIRECT_PER_BLOCK {
+            return Ok(false); // nothing mapped here is past the end
+        }
+
+        let mut block = [0u8; BLOCKSIZE];
+        self.bread(&mut block, device, indirect)?;
+
+        for i in 0..INDIRECT_PER_BLOCK {
+            let addr = block_addr(&block, i);
+            let child_first = first + i * span;
+
+            let freed = if depth > 1 {
+                self.truncate_indirect(device, addr, depth - 1, child_first, keep)?
+            } else if child_first >= keep && addr != UNUSED_BLOCKADDR {
+                self.free_block(device, addr)?;
+                true
+            } else {
+                false
+            };
+
+            if freed {
+                set_block_addr(&mut block, i, UNUSED_BLOCKADDR);
+            }
+        }
+
+        self.bwrite(&block, device, indirect)?;
+        Ok(false)
+    }
+
+    /// Frees an indirect block and everything it maps.  `depth` is the number of levels of
+    /// indirection below and including this block (1 for singly-indirect, 2 for doubly-indirect)
+    fn free_indirect(&mut self, device: u32, indirect: u32, depth: u32) -> Result<(), FsError> {
+        if indirect == UNUSED_BLOCKADDR {
+            return Ok(());
+        }
+
+        let mut block = [0u8; BLOCKSIZE];
+        self.bread(&mut block, device, indirect)?;
+        let addrs = (0..INDIRECT_PER_BLOCK).map(|i| block_addr(&block, i));
+
+        for addr in addrs.filter(|&a| a != UNUSED_BLOCKADDR) {
+            if depth > 1 {
+                self.free_indirect(device, addr, depth - 1)?;
+            } else {
+                self.free_block(device, addr)?;
+            }
+        }
+
+        self.free_block(device, indirect)
+    }
+
+    /// Calls `f` with the address of every block an inode maps, including its indirect blocks.
+    /// `f` returns whether to look inside an indirect block, so a caller that doesn't trust an
+    /// address can avoid following it.
+    pub fn inode_blocks<F>(&self, inode: &Inode, mut f: F) -> Result<(), FsError>
+        where F: FnMut(u32, BlockUse) -> bool
+    {
+        for (bn, &addr) in inode.blocks.iter().enumerate() {
+            if addr != UNUSED_BLOCKADDR {
+                f(addr, BlockUse::Data(bn as u32));
+            }
+        }
+
+        self.indirect_blocks(inode.device, inode.indirect, 1, NDIRECT, &mut f)?;
+        self.indirect_blocks(inode.device,
+                             inode.double_indirect,
+                             2,
+                             NDIRECT + INDIRECT_PER_BLOCK * NINDIRECT,
+                             &mut f)
+    }
+
+    // walk the blocks below an indirect block, where `first` is the file block number of the
+    // first block it maps
+    fn indirect_blocks<F>(&self,
+                          device: u32,
+                          indirect: u32,
+                          depth: u32,
+                          first: usize,
+                          f: &mut F)
+                          -> Result<(), FsError>
+        where F: FnMut(u32, BlockUse) -> bool
+    {
+        if indirect == UNUSED_BLOCKADDR || !f(indirect, BlockUse::Indirect(depth)) {
+            return Ok(());
+        }
+
+        let mut block = [0u8; BLOCKSIZE];
+        self.bread(&mut block, device, indirect)?;
+        let addrs = (0..INDIRECT_PER_BLOCK).map(|i| block_addr(&block, i));
+
+        // how many file blocks each entry covers
+        let span = INDIRECT_PER_BLOCK.pow(depth - 1);
+        for (i, addr) in addrs.enumerate().filter(|&(_, a)| a != UNUSED_BLOCKADDR) {
+            if depth > 1 {
+                self.indirect_blocks(device, addr, depth - 1, first + i * span, f)?;
+            } else {
+                f(addr, BlockUse::Data((first + i) as u32));
+            }
+        }
+        Ok(())
+    }
+
     pub fn read(&self,
                 inode: &Inode,
                 dst_buf: &mut [u8],
-                mut offset: u32)
+                offset: u32)
                 -> Result<usize, FsError> {
         match inode.type_ {
-            InodeType::File | InodeType::Directory => {
+            InodeType::File | InodeType::Directory | InodeType::Symlink => {
                 let mut len = dst_buf.len() as u32;
                 // Don't allow reading past end of file, or reading large amount that would cause
                 // an overflow
@@ -404,33 +1092,21 @@ impl<T> FileSystem<T>
 
                 let short_buf = &mut dst_buf[..len as usize];
 
-                // for 0th block, copy from (offset % BLOCKSIZE, BLOCKSIZE)
-                // for intermediate blocks, we can copy BLOCKSIZE at a time
-                // for last block, copy from block from (0, dst_buf.len() - cursor).
-
-                // for the first block, we copy only from after the offset
-                let blockaddr = self.bmap(inode, offset / (BLOCKSIZE as u32))?;
-                let mut tmp_buf = [0; BLOCKSIZE];
-                self.disk.read(&mut tmp_buf, inode.device, blockaddr)?;
-                for (buf, tmp) in short_buf.iter_mut()
-                    .zip(tmp_buf[(offset as usize) % BLOCKSIZE..].iter()) {
-                    *buf = *tmp;
-                }
-                offset += tmp_buf[(offset as usize) % BLOCKSIZE..].len() as u32;
-                if offset >= short_buf.len() as u32 {
-                    return Ok(short_buf.len());
-                }
-
-                // no more reading to do if the offset is greater than the len
+                // copy a block at a time, only taking the part of the first and last blocks that
+                // overlaps with the requested range
+                let mut copied = 0;
+                while copied < short_buf.len() {
+                    let pos = offset as usize + copied;
+                    let blockaddr = self.bmap(inode, (pos / BLOCKSIZE) as u32)?;
+                    let start = pos % BLOCKSIZE;
+                    let n = min(BLOCKSIZE - start, short_buf.len() - copied);
 
-                // now, copy a block at a time, truncating the last block as necessary
-                // this is fucked for so many reasons, fix later
-                for mut chunk in short_buf[BLOCKSIZE + (offset as usize) % BLOCKSIZE..]
-                    .chunks_mut(BLOCKSIZE) {
-                    let blockaddr = self.bmap(inode, offset / (BLOCKSIZE as u32))?;
-                    self.disk.read(&mut chunk, inode.device, blockaddr)?;
-                    offset += chunk.len() as u32;
+                    let mut tmp_buf = [0; BLOCKSIZE];
+                    self.bread(&mut tmp_buf, inode.device, blockaddr)?;
+                    short_buf[copied..copied + n].copy_from_slice(&tmp_buf[start..start + n]);
+                    copied += n;
                 }
+
                 Ok(short_buf.len())
             }
             _ => Err(FsError::TypeMismatch),
@@ -440,99 +1116,583 @@ impl<T> FileSystem<T>
     pub fn write(&mut self,
                  inode: &mut Inode,
                  src_buf: &[u8],
-                 mut offset: u32)
+                 offset: u32)
                  -> Result<usize, FsError> {
-        match inode.type_ {
-            InodeType::File | InodeType::Directory => {
-                let len = src_buf.len() as u32;
+        self.transaction(|fs| {
+            match inode.type_ {
+                InodeType::File | InodeType::Directory | InodeType::Symlink => {
+                    let len = src_buf.len() as u32;
+
+                    // Don't allow writing large amount that would cause an overflow
+                    if (Wrapping(offset) + Wrapping(len)).0 < offset || offset > inode.size {
+                        return Err(FsError::WriteTooLarge);
+                    }
 
-                // Don't allow writing large amount that would cause an overflow
-                if (Wrapping(offset) + Wrapping(len)).0 < offset || offset > inode.size {
-                    return Err(FsError::WriteTooLarge);
-                }
+                    // if we're trying to write a file that's too large, abort
+                    if len + offset > (MAXFILE * BLOCKSIZE) as u32 {
+                        return Err(FsError::WriteTooLarge);
+                    }
 
-                // if we're trying to write a file that's too large, abort
-                if len + offset > (MAXFILE * BLOCKSIZE) as u32 {
-                    return Err(FsError::WriteTooLarge);
+                    fs.extend_contiguous(inode, offset + len)?;
+
+                    // directories and symlinks are metadata, so their contents go through the log
+                    let logged = inode.type_ != InodeType::File;
+
+                    let mut written = 0;
+                    while written < src_buf.len() {
+                        let pos = offset as usize + written;
+                        let blockaddr = fs.bmap_or_alloc(inode, (pos / BLOCKSIZE) as u32)?;
+                        let start = pos % BLOCKSIZE;
+                        let n = min(BLOCKSIZE - start, src_buf.len() - written);
+
+                        let mut tmp_buf = [0; BLOCKSIZE];
+                        if n < BLOCKSIZE {
+                            // partial block: read it in first so we don't clobber the bytes around
+                            // the range we're writing
+                            fs.bread(&mut tmp_buf, inode.device, blockaddr)?;
+                        }
+                        let src = &src_buf[written..written + n];
+                        tmp_buf[start..start + n].copy_from_slice(src);
+
+                        if logged {
+                            fs.bwrite(&tmp_buf, inode.device, blockaddr)?;
+                        } else {
+                            fs.bwrite_data(&tmp_buf, inode.device, blockaddr)?;
+                        }
+                        written += n;
+                    }
+
+                    // update file size if we extended past the end of the file
+                    if offset + len > inode.size {
+                        inode.size = offset + len;
+                    }
+                    if len > 0 {
+                        inode.mtime = fs.now();
+                    }
+
+                    Ok(len as usize)
                 }
+                _ => Err(FsError::TypeMismatch),
+            }
+        })
+    }
 
-                // for the first block, take care to correctly overlap offset with the rest of the
-                // block
-                let blockaddr = self.bmap_or_alloc(inode, offset / (BLOCKSIZE as u32))?;
+    /// When a write is about to grow a file by more than one block, tries to allocate all of the
+    /// new blocks as one extent so the file is laid out sequentially.  Without a block bitmap, or
+    /// if no run of free blocks is long enough, this leaves the blocks to be allocated one at a
+    /// time as they're written.
+    fn extend_contiguous(&mut self, inode: &mut Inode, new_size: u32) -> Result<(), FsError> {
+        let first = (inode.size as usize + BLOCKSIZE - 1) / BLOCKSIZE;
+        let end = (new_size as usize + BLOCKSIZE - 1) / BLOCKSIZE;
+        if end <= first + 1 {
+            return Ok(());
+        }
+        let len = (end - first) as u32;
 
-                if offset as usize % BLOCKSIZE != 0 {
-                    let mut tmp_buf = [0; BLOCKSIZE];
-                    self.disk.read(&mut tmp_buf, inode.device, blockaddr)?;
+        let sb = self.superblock(inode.device)?;
+        if sb.bitmap_start == UNUSED_BLOCKADDR {
+            return Ok(());
+        }
+        // blocks past the end of a file should never be mapped, but don't leak one if it is
+        for bn in first..end {
+            if self.bmap(inode, bn as u32).is_ok() {
+                return Ok(());
+            }
+        }
 
-                    for (tmp, src) in tmp_buf[(offset as usize) % BLOCKSIZE..BLOCKSIZE]
-                        .iter_mut()
-                        .zip(src_buf.iter()) {
-                        *tmp = *src;
-                    }
+        let start = match self.bitmap_alloc(inode.device, &sb, len) {
+            Ok(start) => start,
+            Err(FsError::ExhaustedBlocks) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for i in 0..len {
+            if let Err(e) = self.bmap_or_assign(inode, first as u32 + i, start + i) {
+                // give back the part of the extent that didn't make it into the file
+                self.bitmap_mark(inode.device, &sb, start + i, len - i, false)?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
 
-                    offset += self.disk.write(&tmp_buf, inode.device, blockaddr)? as u32;
-                } else {
-                    // else business as usual, write the first block
-                    // note: offset doesn't mean don't pad the end, just means we don't need to do
-                    // weird prepend logic
-                    offset += self.disk
-                        .write(&src_buf[..min(BLOCKSIZE, src_buf.len())],
-                               inode.device,
-                               blockaddr)? as u32;
+    /// Creates a new, empty directory at `path` and returns its inode number
+    pub fn mkdir(&mut self, path: &[u8]) -> Result<u32, FsError> {
+        self.transaction(|fs| {
+            let (parent_inum, name) = fs.nameiparent(path)?;
+            let mut parent = fs.read_inode(ROOT_DEV, parent_inum)?;
+
+            if name.len() > DIRNAME_SIZE {
+                return Err(FsError::NameTooLong);
+            }
+            if fs.dir_lookup(&parent, name).is_ok() {
+                return Err(FsError::EntryExists);
+            }
+
+            let mut dir = fs.new_inode(InodeType::Directory, parent.device);
+            let inum = fs.alloc_inode(parent.device, dir)?;
+
+            // every directory starts out with entries for itself and its parent
+            fs.dir_add(&mut dir, b".", inum)?;
+            fs.dir_add(&mut dir, b"..", parent_inum)?;
+            fs.update_inode(inum, &dir)?;
+
+            fs.dir_add(&mut parent, name, inum)?;
+            fs.update_inode(parent_inum, &parent)?;
+
+            Ok(inum)
+        })
+    }
+
+    /// Creates a new, empty file at `path` and returns its inode number
+    pub fn create(&mut self, path: &[u8]) -> Result<u32, FsError> {
+        let file = self.new_inode(InodeType::File, ROOT_DEV);
+        self.add_inode(path, file)
+    }
+
+    /// Creates a device file at `path` and returns its inode number.  The file system only
+    /// records the major and minor numbers; reading and writing the file is up to the driver that
+    /// the major number belongs to.
+    pub fn mknod(&mut self, path: &[u8], major: u16, minor: u16) -> Result<u32, FsError> {
+        let mut node = self.new_inode(InodeType::Device, ROOT_DEV);
+        node.major = major;
+        node.minor = minor;
+        self.add_inode(path, node)
+    }
+
+    // Allocates a new inode that doesn't need any blocks yet and adds it to a directory
+    fn add_inode(&mut self, path: &[u8], mut inode: Inode) -> Result<u32, FsError> {
+        self.transaction(|fs| {
+            let (parent_inum, name) = fs.nameiparent(path)?;
+            let mut parent = fs.read_inode(ROOT_DEV, parent_inum)?;
+
+            if name.len() > DIRNAME_SIZE {
+                return Err(FsError::NameTooLong);
+            }
+            if fs.dir_lookup(&parent, name).is_ok() {
+                return Err(FsError::EntryExists);
+            }
+
+            inode.device = parent.device;
+            let inum = fs.alloc_inode(parent.device, inode)?;
+
+            fs.dir_add(&mut parent, name, inum)?;
+            fs.update_inode(parent_inum, &parent)?;
+
+            Ok(inum)
+        })
+    }
+
+    /// Removes the directory entry at `path`, freeing the inode it names along with its blocks
+    /// once no other entry links to it.  Directories can only be removed once they're empty.
+    pub fn unlink(&mut self, path: &[u8]) -> Result<(), FsError> {
+        let (parent_inum, name) = self.nameiparent(path)?;
+        if name == b"." || name == b".." {
+            return Err(FsError::InvalidPath);
+        }
+
+        let mut parent = self.read_inode(ROOT_DEV, parent_inum)?;
+        let (inum, offset) = self.dir_lookup(&parent, name)?;
+
+        let inode = self.read_inode(parent.device, inum)?;
+        if inode.type_ == InodeType::Directory && !self.dir_is_empty(&inode)? {
+            return Err(FsError::DirNotEmpty);
+        }
+
+        // the entry goes away in its own transaction.  If we crash before the inode is freed, it's
+        // only leaked, which fsck can clean up
+        self.dir_remove(&mut parent, offset as u32)?;
+        self.update_inode(parent_inum, &parent)?;
+
+        self.drop_link(parent.device, inum)
+    }
+
+    /// Adds a new directory entry at `new` for the file that `old` names.  Directories can't be
+    /// linked, since that could make a loop in the tree.  If `old` is a symbolic link, the new
+    /// entry names the link itself rather than what it points to.
+    pub fn link(&mut self, old: &[u8], new: &[u8]) -> Result<(), FsError> {
+        self.transaction(|fs| {
+            let inum = fs.lookup(old, false)?;
+            let mut inode = fs.read_inode(ROOT_DEV, inum)?;
+            if inode.type_ == InodeType::Directory {
+                return Err(FsError::IsDir);
+            }
+
+            let (parent_inum, name) = fs.nameiparent(new)?;
+            let mut parent = fs.read_inode(ROOT_DEV, parent_inum)?;
+            if name.len() > DIRNAME_SIZE {
+                return Err(FsError::NameTooLong);
+            }
+            if fs.dir_lookup(&parent, name).is_ok() {
+                return Err(FsError::EntryExists);
+            }
+
+            // count the link before adding it: if we fail in between, the count is only too high,
+            // which leaks the inode rather than freeing it out from under an entry
+            inode.nlink = max(inode.nlink, 1) + 1;
+            fs.update_inode(inum, &inode)?;
+
+            fs.dir_add(&mut parent, name, inum)?;
+            fs.update_inode(parent_inum, &parent)
+        })
+    }
+
+    /// Moves the entry at `old` to `new`, which can be in another directory.  If `new` already
+    /// exists, it's replaced: a file by anything but a directory, and an empty directory by
+    /// another directory.  The entries change in a single transaction, so after a crash the file
+    /// is always reachable under one name or the other.
+    pub fn rename(&mut self, old: &[u8], new: &[u8]) -> Result<(), FsError> {
+        let replaced = self.transaction(|fs| {
+            let (old_parent, old_name) = fs.nameiparent(old)?;
+            let (new_parent, new_name) = fs.nameiparent(new)?;
+            if old_name == b"." || old_name == b".." || new_name == b"." || new_name == b".." {
+                return Err(FsError::InvalidPath);
+            }
+            if new_name.len() > DIRNAME_SIZE {
+                return Err(FsError::NameTooLong);
+            }
+
+            let (inum, old_offset) = fs.dir_lookup(&fs.read_inode(ROOT_DEV, old_parent)?,
+                                                   old_name)?;
+            let inode = fs.read_inode(ROOT_DEV, inum)?;
+            let is_dir = inode.type_ == InodeType::Directory;
+
+            // a directory can't move underneath itself, or it would be cut off from the root
+            if is_dir && old_parent != new_parent && fs.is_ancestor(inum, new_parent)? {
+                return Err(FsError::InvalidPath);
+            }
+
+            let mut parent = fs.read_inode(ROOT_DEV, new_parent)?;
+            let mut replaced = None;
+            if let Ok((target, new_offset)) = fs.dir_lookup(&parent, new_name) {
+                if target == inum {
+                    return Ok(None); // both names already refer to the same file
                 }
 
-                if offset >= src_buf.len() as u32 {
-                    if offset > inode.size {
-                        inode.size += src_buf.len() as u32;
+                let target_inode = fs.read_inode(ROOT_DEV, target)?;
+                match (is_dir, target_inode.type_ == InodeType::Directory) {
+                    (true, true) => {
+                        if !fs.dir_is_empty(&target_inode)? {
+                            return Err(FsError::DirNotEmpty);
+                        }
                     }
-                    return Ok(src_buf.len());
+                    (true, false) => return Err(FsError::NotDir),
+                    (false, true) => return Err(FsError::IsDir),
+                    (false, false) => {}
                 }
 
-                // should figure out how to offset correctly
-                // now, copy a block at a time, truncating the last block as necessary
-                for chunk in src_buf[(offset as usize)..].chunks(BLOCKSIZE) {
-                    let blockaddr = self.bmap_or_alloc(inode, offset / (BLOCKSIZE as u32))?;
-                    offset += self.disk.write(chunk, inode.device, blockaddr)? as u32;
-                }
+                fs.dir_remove(&mut parent, new_offset as u32)?;
+                replaced = Some(target);
+            }
+            fs.dir_add(&mut parent, new_name, inum)?;
+            fs.update_inode(new_parent, &parent)?;
+
+            // read the old parent back in, since it may be the directory we just changed
+            let mut parent = fs.read_inode(ROOT_DEV, old_parent)?;
+            fs.dir_remove(&mut parent, old_offset as u32)?;
+            fs.update_inode(old_parent, &parent)?;
+
+            if is_dir && old_parent != new_parent {
+                let mut dir = inode;
+                let (_, dotdot) = fs.dir_lookup(&dir, b"..")?;
+                fs.dir_remove(&mut dir, dotdot as u32)?;
+                fs.dir_add(&mut dir, b"..", new_parent)?;
+                fs.update_inode(inum, &dir)?;
+            }
 
-                // update file size if we extended past the end of the file
-                if offset > inode.size {
-                    inode.size = offset;
-                }
+            Ok(replaced)
+        })?;
+
+        // like unlink, the replaced file is released after its entry is gone, so that freeing a
+        // big one doesn't have to fit in the same transaction
+        match replaced {
+            Some(inum) => self.drop_link(ROOT_DEV, inum),
+            None => Ok(()),
+        }
+    }
 
-                Ok(len as usize)
+    /// Creates a symbolic link at `path` that points to `target`, and returns its inode number.
+    /// The target doesn't have to exist.  Relative targets are resolved from the directory that
+    /// holds the link.
+    pub fn symlink(&mut self, target: &[u8], path: &[u8]) -> Result<u32, FsError> {
+        if target.is_empty() {
+            return Err(FsError::InvalidPath);
+        }
+        if target.len() > SYMLINK_MAX {
+            return Err(FsError::NameTooLong);
+        }
+
+        self.transaction(|fs| {
+            let (parent_inum, name) = fs.nameiparent(path)?;
+            let mut parent = fs.read_inode(ROOT_DEV, parent_inum)?;
+
+            if name.len() > DIRNAME_SIZE {
+                return Err(FsError::NameTooLong);
             }
-            _ => Err(FsError::TypeMismatch),
+            if fs.dir_lookup(&parent, name).is_ok() {
+                return Err(FsError::EntryExists);
+            }
+
+            let mut link = fs.new_inode(InodeType::Symlink, parent.device);
+            let inum = fs.alloc_inode(parent.device, link)?;
+            fs.write(&mut link, target, 0)?;
+            fs.update_inode(inum, &link)?;
+
+            fs.dir_add(&mut parent, name, inum)?;
+            fs.update_inode(parent_inum, &parent)?;
+
+            Ok(inum)
+        })
+    }
+
+    /// Reads the path that the symbolic link at `path` points to into `buf`, and returns its
+    /// length
+    pub fn readlink(&self, path: &[u8], buf: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.read_inode(ROOT_DEV, self.lookup(path, false)?)?;
+        if inode.type_ != InodeType::Symlink {
+            return Err(FsError::TypeMismatch);
         }
+        if inode.size as usize > buf.len() {
+            return Err(FsError::ReadTooLarge);
+        }
+        self.read(&inode, &mut buf[..inode.size as usize], 0)
     }
 
-    // return inum of the target file
-    pub fn namex(&self, path: &[u8], name: &[u8]) -> Result<u32, FsError> {
-        let inode: Inode = if path == b"/" {
-            self.read_inode(ROOT_DEV, ROOT_INUM)?
-        } else {
-            return Err(FsError::NotFound); // if/when we decide to support subdirectories, inode is current working directory
-        };
+    // Drops one of an inode's links, freeing it when that was the last one.  Images from before
+    // link counts have nlink 0, which counts as a single link.
+    fn drop_link(&mut self, device: u32, inum: u32) -> Result<(), FsError> {
+        let mut inode = self.read_inode(device, inum)?;
+        if inode.nlink > 1 {
+            inode.nlink -= 1;
+            return self.update_inode(inum, &inode);
+        }
+        self.free_inode(device, inum)
+    }
+
+    // Whether the directory `ancestor` is `dir` or one of the directories above it.  A path to
+    // the root can't pass through more directories than there are inodes, so a walk that does
+    // has found a loop of corrupt `..` entries.
+    fn is_ancestor(&self, ancestor: u32, dir: u32) -> Result<bool, FsError> {
+        let ninodes = self.superblock(ROOT_DEV)?.ninodes;
+        let mut inum = dir;
+        for _ in 0..ninodes {
+            if inum == ancestor {
+                return Ok(true);
+            }
+            if inum == ROOT_INUM {
+                return Ok(false);
+            }
+            let (parent, _) = self.dir_lookup(&self.read_inode(ROOT_DEV, inum)?, b"..")?;
+            inum = parent;
+        }
+        Err(FsError::BadGeometry)
+    }
+
+    /// Resolves a path to the inode number of the file or directory it names.  Paths are always
+    /// resolved from the root directory, and may contain `.` and `..` components.  Symbolic
+    /// links are followed, including one at the end of the path.
+    pub fn namex(&self, path: &[u8]) -> Result<u32, FsError> {
+        self.lookup(path, true)
+    }
+
+    /// Splits a path into the inode number of its parent directory and its final component
+    pub fn nameiparent<'a>(&self, path: &'a [u8]) -> Result<(u32, &'a [u8]), FsError> {
+        // ignore any trailing slashes, so "/www/css/" names "css" in "/www"
+        let end = path.iter().rposition(|&c| c != b'/').map_or(0, |i| i + 1);
+        let start = path[..end].iter().rposition(|&c| c == b'/').map_or(0, |i| i + 1);
+
+        let name = &path[start..end];
+        if name.is_empty() {
+            return Err(FsError::NotFound); // the root directory has no parent entry
+        }
+
+        let parent = self.namex(&path[..start])?;
+        if self.read_inode(ROOT_DEV, parent)?.type_ != InodeType::Directory {
+            return Err(FsError::NotDir);
+        }
+
+        Ok((parent, name))
+    }
+
+    /// Like `namex`, but if the final component of the path is a symbolic link and `follow` is
+    /// false, returns the link itself
+    pub fn lookup(&self, path: &[u8], follow: bool) -> Result<u32, FsError> {
+        // When we come to a link, the rest of the path is spliced onto the end of its target and
+        // the walk carries on from there.  Doing this in a loop rather than recursing keeps a
+        // chain of links from eating the kernel stack.
+        let mut path = path.to_vec();
+        let mut pos = 0;
+        let mut inum = ROOT_INUM;
+        let mut links = 0;
+
+        loop {
+            while pos < path.len() && path[pos] == b'/' {
+                pos += 1;
+            }
+            if pos == path.len() {
+                return Ok(inum);
+            }
+            let end = path[pos..].iter().position(|&c| c == b'/').map_or(path.len(), |i| pos + i);
+
+            let dir = self.read_inode(ROOT_DEV, inum)?;
+            if dir.type_ != InodeType::Directory {
+                return Err(FsError::NotDir);
+            }
+            let (next, _) = self.dir_lookup(&dir, &path[pos..end])?;
+
+            let inode = self.read_inode(ROOT_DEV, next)?;
+            let last = path[end..].iter().all(|&c| c == b'/');
+            if inode.type_ != InodeType::Symlink || (last && !follow) {
+                inum = next;
+                pos = end;
+                continue;
+            }
+
+            links += 1;
+            if links > MAXSYMLINKS {
+                return Err(FsError::SymlinkLoop);
+            }
+
+            // symlink never writes a longer target, so a bigger link is corrupt
+            if inode.size as usize > SYMLINK_MAX {
+                return Err(FsError::BadGeometry);
+            }
+            let mut target = Vec::new();
+            target.resize(inode.size as usize, 0);
+            self.read(&inode, &mut target, 0)?;
+            if target.first() == Some(&b'/') {
+                inum = ROOT_INUM;
+            }
+            // otherwise the target is relative to the directory we're in, which inum still names
+            target.extend_from_slice(&path[end..]);
+            path = target;
+            pos = 0;
+        }
+    }
+}
+
+/// An iterator over the live entries of a directory, from `FileSystem::readdir`.  It stops after
+/// the first error.
+pub struct ReadDir<'a, T>
+    where T: Disk + 'a
+{
+    fs: &'a FileSystem<T>,
+    dir: Inode,
+    offset: u32,
+    error: Option<FsError>, // yielded before anything else
+}
+
+impl<'a, T> Iterator for ReadDir<'a, T>
+    where T: Disk
+{
+    type Item = Result<DirItem, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        // a partial entry at the end of a corrupt directory is ignored here; fsck reports it
+        while self.offset + DIRENT_SIZE as u32 <= self.dir.size {
+            let offset = self.offset;
+            self.offset += DIRENT_SIZE as u32;
+
+            let mut buf = [0; DIRENT_SIZE];
+            let entry = match self.fs.read(&self.dir, &mut buf, offset) {
+                Ok(_) => DirEntry::decode(&buf),
+                Err(e) => return Some(self.fail(e)),
+            };
+            if entry.inumber == UNUSED_INUM {
+                continue;
+            }
 
-        let (inum, _) = self.dir_lookup(&inode, name)?;
-        Ok(inum)
+            return Some(match self.fs.read_inode(self.dir.device, entry.inumber) {
+                Ok(inode) => {
+                    Ok(DirItem {
+                        inumber: entry.inumber,
+                        type_: inode.type_,
+                        name: entry.name,
+                    })
+                }
+                Err(e) => self.fail(e),
+            });
+        }
+        None
     }
 }
 
-// prevent weird aliasing violations by forcing borrow and lifetime with a function
-// versus doing this in the same scope
-fn buffer_to_sb(buffer: &mut [u8; 512]) -> &mut SuperBlock {
-    unsafe { &mut *(buffer.as_mut_ptr() as *mut SuperBlock) }
+impl<'a, T> ReadDir<'a, T>
+    where T: Disk
+{
+    fn fail(&mut self, e: FsError) -> Result<DirItem, FsError> {
+        self.offset = self.dir.size;
+        Err(e)
+    }
 }
 
-#[repr(C)]
+// The on-disk structures.  These are read and written with the routines in encoding.rs, never by
+// reinterpreting the bytes of a block.
+
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub struct SuperBlock {
+    pub magic: u32, // always FSMAGIC
+    pub version: u32, // on-disk format version, FSVERSION
     pub size: u32,
     pub nblocks: u32,
     pub ninodes: u32,
     pub inode_start: u32,
     pub freelist_start: u32,
+    pub log_start: u32,
+    pub nlog: u32,
+    pub bitmap_start: u32, // 0 if free blocks are kept on the free list instead of a bitmap
+}
+
+impl SuperBlock {
+    /// Number of blocks taken up by the ilist
+    pub fn ilist_blocks(&self) -> u32 {
+        let inodes_per_block = INODES_PER_BLOCK as u32;
+        (self.ninodes + inodes_per_block - 1) / inodes_per_block
+    }
+
+    /// Number of blocks taken up by the free block bitmap, which has a bit for every block on
+    /// the disk
+    pub fn bitmap_blocks(&self) -> u32 {
+        if self.bitmap_start == UNUSED_BLOCKADDR {
+            0
+        } else {
+            (self.nblocks + BITS_PER_BLOCK - 1) / BITS_PER_BLOCK
+        }
+    }
+
+    /// The first block after the ilist and bitmap, where the data blocks start
+    pub fn data_start(&self) -> u32 {
+        self.inode_start + self.ilist_blocks() + self.bitmap_blocks()
+    }
+
+    /// Checks that this is a superblock for a format we understand, and that the regions it
+    /// describes fit on the disk in order
+    pub fn validate(&self) -> Result<(), FsError> {
+        if self.magic != FSMAGIC {
+            return Err(FsError::BadMagic(self.magic));
+        }
+        if self.version < FSVERSION_MIN || self.version > FSVERSION {
+            return Err(FsError::BadVersion(self.version));
+        }
+
+        let log_fits = self.log_start == 0 || (self.log_start > SUPERBLOCK_ADDR && self.nlog > 0);
+        // the ilist comes after the superblock and the log, if there is one
+        let ilist_min = if self.log_start == 0 {
+            SUPERBLOCK_ADDR + 1
+        } else {
+            self.log_start.saturating_add(1).saturating_add(self.nlog)
+        };
+        // the bitmap sits right after the ilist
+        let bitmap_fits = self.bitmap_start == UNUSED_BLOCKADDR ||
+                          self.bitmap_start == self.inode_start + self.ilist_blocks();
+        if self.ninodes == 0 || !log_fits || self.inode_start < ilist_min || !bitmap_fits ||
+           self.data_start() >= self.nblocks {
+            return Err(FsError::BadGeometry);
+        }
+        Ok(())
+    }
 }
 
 #[repr(u16)]
@@ -541,6 +1701,22 @@ pub enum InodeType {
     Unused,
     File,
     Directory,
+    Symlink, // the contents are the path the link points to
+    Device, // no contents; major and minor say which device driver handles it
+}
+
+/// What an inode uses one of its blocks for
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum BlockUse {
+    Data(u32), // holds the given file block
+    Indirect(u32), // an indirect block with the given depth
+}
+
+/// How a file system keeps track of its free blocks, chosen when it's formatted
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum BlockAllocator {
+    FreeList, // a chain of blocks listing free blocks, hanging off the superblock
+    Bitmap, // a bit for every block, after the ilist, which can find runs of free blocks
 }
 
 #[derive(Debug, PartialEq)]
@@ -551,10 +1727,22 @@ pub enum FsError {
     EOF,
     EntryExists,
     NotFound,
+    NameTooLong,
+    InvalidPath,
+    DirNotEmpty,
     ReadTooLarge,
     WriteTooLarge,
     BlockNotMapped(u32),
     TypeMismatch, // don't invoke the file system on a device file!
+    LogFull, // the operation wrote more blocks than the log can hold
+    BadMagic(u32), // the disk doesn't hold a simple_fs file system
+    BadVersion(u32), // the file system is in a format version we don't understand
+    BadGeometry, // something on disk doesn't fit the layout, like a superblock describing more
+                 // blocks than the disk has or a directory entry naming an inode past the ilist
+    BadInodeType(u16), // an inode in the ilist has a type we don't know about
+    Unsupported, // the file system's layout can't do that, like extents on a free list
+    IsDir, // directories can't be hard linked, or replaced by something that isn't one
+    SymlinkLoop, // following a path went through more than MAXSYMLINKS symbolic links
     DiskFault(DiskError),
 }
 
@@ -569,15 +1757,20 @@ pub enum DiskError {
     IoError,
 }
 
-#[repr(C)]
 #[derive(Copy)]
 pub struct Inode {
     pub type_: InodeType,
+    pub mode: u16, // permission bits, like 0o644.  Recorded, but nothing enforces them yet
     pub device: u32,
     pub major: u16,
     pub minor: u16,
     pub size: u32,
     pub blocks: [u32; NDIRECT],
+    pub indirect: u32,
+    pub double_indirect: u32,
+    pub nlink: u16, // number of directory entries naming this inode, not counting . and ..
+    pub ctime: u32, // creation time, in seconds since the Unix epoch
+    pub mtime: u32, // time the contents last changed
 }
 
 // Rust doesn't yet support integer type parameterization, so we manually implement the clone
@@ -590,15 +1783,61 @@ impl Clone for Inode {
 
 pub const UNUSED_INODE: Inode = Inode {
     type_: InodeType::Unused,
+    mode: 0,
     major: 0,
     minor: 0,
     size: 0,
     device: 0,
     blocks: [0; NDIRECT],
+    indirect: 0,
+    double_indirect: 0,
+    nlink: 0,
+    ctime: 0,
+    mtime: 0,
 };
 
-#[repr(C)]
 pub struct DirEntry {
-    inumber: u32,
+    pub inumber: u32,
+    pub name: [u8; DIRNAME_SIZE],
+}
+
+impl DirEntry {
+    /// The entry's name, up to the first NUL (or all DIRNAME_SIZE bytes)
+    pub fn name(&self) -> &[u8] {
+        name_of(&self.name)
+    }
+}
+
+/// A directory entry along with the type of the inode it names, as `readdir` yields them
+pub struct DirItem {
+    pub inumber: u32,
+    pub type_: InodeType,
     name: [u8; DIRNAME_SIZE],
 }
+
+impl DirItem {
+    /// For file systems that keep their directories some other way, but list them the same way
+    /// we do.  Fails if the name won't fit in a directory entry.
+    pub fn new(inumber: u32, type_: InodeType, name: &[u8]) -> Result<DirItem, FsError> {
+        if name.len() > DIRNAME_SIZE {
+            return Err(FsError::NameTooLong);
+        }
+        let mut item = DirItem {
+            inumber: inumber,
+            type_: type_,
+            name: [0; DIRNAME_SIZE],
+        };
+        item.name[..name.len()].copy_from_slice(name);
+        Ok(item)
+    }
+
+    pub fn name(&self) -> &[u8] {
+        name_of(&self.name)
+    }
+}
+
+// a NUL-padded directory entry name, up to the first NUL (or all DIRNAME_SIZE bytes)
+fn name_of(name: &[u8; DIRNAME_SIZE]) -> &[u8] {
+    let len = name.iter().position(|&x| x == 0).unwrap_or(DIRNAME_SIZE);
+    &name[..len]
+}
@@ -0,0 +1,245 @@
+use core::cmp::min;
+use encoding::{get_u32, put_u32};
+use {Disk, FsError, BLOCKSIZE};
+
+pub const MAXOPBLOCKS: usize = 10; // max # of blocks any FS op writes
+pub const LOGSIZE: usize = MAXOPBLOCKS * 3; // max data blocks in on-disk log
+
+// The on-disk log lives in its own region, recorded in the superblock.  The first block holds a
+// LogHeader, and the next LOGSIZE blocks hold copies of the blocks written by a transaction.
+//
+// Committing a transaction writes the block copies into the log region, then writes the header
+// with a nonzero count.  Writing the header is the commit point: once it's on disk, recovery will
+// copy the logged blocks to their home locations even if we crash while installing them.  Finally
+// the count is reset to zero to mark the log as empty.
+//
+// Only metadata (the superblock, free list, ilist, indirect blocks and directory contents) goes
+// through the log.  Regular file contents are written straight to the disk, except into blocks the
+// running transaction has already logged, like a free list block it's taking for the file.
+
+#[derive(Copy, Clone)]
+struct LogHeader {
+    n: u32,
+    blocks: [u32; LOGSIZE], // home block number of each logged block
+}
+
+// on disk, the header is the count followed by the block numbers, all little-endian
+impl LogHeader {
+    fn decode(buf: &[u8]) -> LogHeader {
+        let mut header = LogHeader {
+            n: get_u32(buf, 0),
+            blocks: [0; LOGSIZE],
+        };
+        for (i, b) in header.blocks.iter_mut().enumerate() {
+            *b = get_u32(buf, 4 * (i + 1));
+        }
+        header
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        put_u32(buf, 0, self.n);
+        for (i, &b) in self.blocks.iter().enumerate() {
+            put_u32(buf, 4 * (i + 1), b);
+        }
+    }
+}
+
+/// Reads how many blocks a log header block says are waiting to be installed
+pub fn pending(header_block: &[u8]) -> u32 {
+    LogHeader::decode(header_block).n
+}
+
+pub struct Log {
+    device: u32,
+    start: u32, // block address of the log header.  0 when the file system isn't logged
+    capacity: usize, // number of blocks the log region can hold
+    outstanding: u32, // how many begin_op()s haven't had a matching end_op() yet
+    aborted: bool, // the transaction outgrew the log and must be thrown away
+    header: LogHeader, // blocks written by the current transaction
+    data: [[u8; BLOCKSIZE]; LOGSIZE], // and their new contents
+}
+
+impl Log {
+    pub fn new() -> Log {
+        Log {
+            device: 0,
+            start: 0,
+            capacity: 0,
+            outstanding: 0,
+            aborted: false,
+            header: LogHeader {
+                n: 0,
+                blocks: [0; LOGSIZE],
+            },
+            data: [[0; BLOCKSIZE]; LOGSIZE],
+        }
+    }
+
+    /// Starts logging writes to `device`, using the log region at `start`
+    pub fn init(&mut self, device: u32, start: u32, nlog: u32) {
+        self.device = device;
+        self.start = start;
+        self.capacity = min(nlog as usize, LOGSIZE);
+    }
+
+    pub fn begin(&mut self) {
+        self.outstanding += 1;
+    }
+
+    /// Ends an operation, returning true once the outermost operation has finished and the
+    /// transaction is ready to commit
+    pub fn end(&mut self) -> bool {
+        assert!(self.outstanding > 0, "end_op without a matching begin_op");
+        self.outstanding -= 1;
+        self.outstanding == 0
+    }
+
+    fn active(&self, device: u32) -> bool {
+        self.start != 0 && self.outstanding > 0 && device == self.device
+    }
+
+    fn find(&self, device: u32, blockno: u32) -> Option<usize> {
+        if device != self.device {
+            return None;
+        }
+        self.header.blocks[..self.header.n as usize].iter().position(|&b| b == blockno)
+    }
+
+    /// Copies the pending contents of a block into `buffer`, if the current transaction has
+    /// written it.  Returns false if the block should be read from the disk instead.
+    pub fn read(&self, buffer: &mut [u8], device: u32, blockno: u32) -> bool {
+        match self.find(device, blockno) {
+            Some(i) => {
+                let n = min(buffer.len(), BLOCKSIZE);
+                buffer[..n].copy_from_slice(&self.data[i][..n]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records a metadata write in the current transaction.  Returns false if there's no
+    /// transaction running, in which case the caller should write to the disk directly.
+    pub fn write<T: Disk>(&mut self,
+                          disk: &T,
+                          buffer: &[u8],
+                          device: u32,
+                          blockno: u32)
+                          -> Result<bool, FsError> {
+        if !self.active(device) {
+            return Ok(false);
+        }
+
+        let i = match self.find(device, blockno) {
+            Some(i) => i,
+            None => {
+                // absorb the write into a new slot.  If we're out of room, the operation is too
+                // big to be atomic, so give up on it rather than commit half of it
+                let n = self.header.n as usize;
+                if n == self.capacity {
+                    self.aborted = true;
+                    return Err(FsError::LogFull);
+                }
+                if buffer.len() < BLOCKSIZE {
+                    disk.read(&mut self.data[n], device, blockno)?;
+                }
+                self.header.blocks[n] = blockno;
+                self.header.n += 1;
+                n
+            }
+        };
+
+        let n = min(buffer.len(), BLOCKSIZE);
+        self.data[i][..n].copy_from_slice(&buffer[..n]);
+        Ok(true)
+    }
+
+    /// Takes a write that would otherwise bypass the log, if the current transaction has already
+    /// written the block.  The block might be one the transaction only just took off the free
+    /// list, so it can't be touched on disk until the transaction commits.  Returns false if the
+    /// caller should write to the disk directly.
+    pub fn write_through(&mut self, buffer: &[u8], device: u32, blockno: u32) -> bool {
+        match self.find(device, blockno) {
+            Some(i) => {
+                let n = min(buffer.len(), BLOCKSIZE);
+                self.data[i][..n].copy_from_slice(&buffer[..n]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Writes the current transaction to the log, then installs it
+    pub fn commit<T: Disk>(&mut self, disk: &mut T) -> Result<(), FsError> {
+        if self.aborted {
+            self.aborted = false;
+            self.header.n = 0;
+            return Err(FsError::LogFull);
+        }
+        if self.header.n == 0 {
+            return Ok(());
+        }
+
+        let result = self.write_and_install(disk);
+
+        // whatever happened, the transaction is over.  If we got as far as writing the header,
+        // recovery will finish installing it
+        self.header.n = 0;
+        result?;
+
+        self.write_header(disk)
+    }
+
+    /// Replays a committed transaction left in the log by a crash
+    pub fn recover<T: Disk>(&mut self, disk: &mut T) -> Result<(), FsError> {
+        let mut buf = [0u8; BLOCKSIZE];
+        disk.read(&mut buf, self.device, self.start)?;
+        self.header = LogHeader::decode(&buf);
+
+        if self.header.n as usize > self.capacity {
+            // a torn or garbage header; nothing in it can be trusted
+            self.header.n = 0;
+        }
+
+        for i in 0..self.header.n as usize {
+            disk.read(&mut self.data[i], self.device, self.start + 1 + i as u32)?;
+        }
+        self.install(disk)?;
+
+        self.header.n = 0;
+        self.write_header(disk)
+    }
+
+    fn write_and_install<T: Disk>(&self, disk: &mut T) -> Result<(), FsError> {
+        self.write_log(disk)?;
+        self.write_header(disk)?;
+        self.install(disk)
+    }
+
+    // copy each block of the transaction into the log region
+    fn write_log<T: Disk>(&self, disk: &mut T) -> Result<(), FsError> {
+        for i in 0..self.header.n as usize {
+            disk.write(&self.data[i], self.device, self.start + 1 + i as u32)?;
+        }
+        disk.sync()?;
+        Ok(())
+    }
+
+    // write the in-memory header to disk.  With a nonzero count, this commits the transaction
+    fn write_header<T: Disk>(&self, disk: &mut T) -> Result<(), FsError> {
+        let mut buf = [0u8; BLOCKSIZE];
+        self.header.encode(&mut buf);
+        disk.write(&buf, self.device, self.start)?;
+        disk.sync()?;
+        Ok(())
+    }
+
+    // copy each logged block to its home location
+    fn install<T: Disk>(&self, disk: &mut T) -> Result<(), FsError> {
+        for i in 0..self.header.n as usize {
+            disk.write(&self.data[i], self.device, self.header.blocks[i])?;
+        }
+        disk.sync()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use alloc::vec::Vec;
+use {Disk, DiskError, BLOCKSIZE};
+
+/// A disk that lives in memory, for tests and for file systems that don't need to outlive the
+/// machine.  Every device number refers to the same blocks.
+pub struct RamDisk {
+    data: Vec<u8>,
+}
+
+impl RamDisk {
+    /// Creates a zero-filled disk of `nblocks` blocks
+    pub fn new(nblocks: u32) -> RamDisk {
+        let mut data = Vec::new();
+        data.resize(nblocks as usize * BLOCKSIZE, 0);
+        RamDisk { data: data }
+    }
+
+    /// Wraps an existing disk image, which must be a whole number of blocks
+    pub fn from_image(data: Vec<u8>) -> RamDisk {
+        assert!(data.len() % BLOCKSIZE == 0,
+                "image is {} bytes, which isn't a whole number of blocks",
+                data.len());
+        RamDisk { data: data }
+    }
+
+    pub fn nblocks(&self) -> u32 {
+        (self.data.len() / BLOCKSIZE) as u32
+    }
+
+    /// The raw contents of the disk
+    pub fn image(&self) -> &[u8] {
+        &self.data
+    }
+
+    // the bytes of a sector, or None if it's past the end of the disk
+    fn range(&self, sector: u32, len: usize) -> Option<(usize, usize)> {
+        assert!(len <= BLOCKSIZE, "length was {}", len);
+        let start = sector as usize * BLOCKSIZE;
+        if sector >= self.nblocks() {
+            None
+        } else {
+            Some((start, start + len))
+        }
+    }
+}
+
+impl Disk for RamDisk {
+    fn read(&self, buffer: &mut [u8], _: u32, sector: u32) -> Result<(), DiskError> {
+        let (start, end) = self.range(sector, buffer.len()).ok_or(DiskError::IoError)?;
+        buffer.copy_from_slice(&self.data[start..end]);
+        Ok(())
+    }
+
+    fn write(&mut self, buffer: &[u8], _: u32, sector: u32) -> Result<usize, DiskError> {
+        let (start, end) = self.range(sector, buffer.len()).ok_or(DiskError::IoError)?;
+        self.data[start..end].copy_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn sector_size() -> usize {
+        BLOCKSIZE
+    }
+}
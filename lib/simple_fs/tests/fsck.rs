@@ -0,0 +1,106 @@
+extern crate simple_fs as fs;
+
+use fs::{BlockAllocator, FileSystem, RamDisk};
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+
+const NBLOCKS: u32 = 1000;
+const NINODES: u32 = 64;
+
+// fsck's exit codes
+const CLEAN: i32 = 0;
+const REPAIRED: i32 = 1;
+const UNCORRECTED: i32 = 4;
+
+fn new_fs() -> FileSystem<RamDisk> {
+    let mut fs = FileSystem::new(RamDisk::new(NBLOCKS));
+    fs.format(NBLOCKS, NINODES, BlockAllocator::FreeList).unwrap();
+    FileSystem::mount(fs.disk).unwrap()
+}
+
+// the fsck binary, which cargo builds next to the directory holding the test binaries
+fn fsck_path() -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.join("fsck")
+}
+
+// a copy of a file system's image in the temporary directory, removed when it's dropped
+struct Image {
+    path: PathBuf,
+}
+
+impl Image {
+    fn new(name: &str, fs: &FileSystem<RamDisk>) -> Image {
+        let path = env::temp_dir().join(format!("simple_fs-fsck-{}.img", name));
+        File::create(&path).unwrap().write_all(fs.disk.image()).unwrap();
+        Image { path: path }
+    }
+
+    // run fsck over the image, returning its exit code and what it printed
+    fn fsck(&self, repair: bool) -> (i32, String) {
+        let mut command = Command::new(fsck_path());
+        if repair {
+            command.arg("--repair");
+        }
+        let output = command.arg(&self.path).output().expect("Could not run fsck");
+        (output.status.code().expect("fsck was killed by a signal"),
+         String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[test]
+fn clean_image() {
+    let mut fs = new_fs();
+    fs.mkdir(b"/d").unwrap();
+    fs.create(b"/d/f").unwrap();
+
+    let image = Image::new("clean", &fs);
+    assert_eq!(image.fsck(false).0, CLEAN);
+}
+
+#[test]
+fn unreadable_directory_blocks_are_problems() {
+    let mut fs = new_fs();
+    let d = fs.mkdir(b"/d").unwrap();
+    let mut dir = fs.read_inode(fs::ROOT_DEV, d).unwrap();
+    // "." and ".." fill the first two blocks, and the third was never mapped
+    dir.size = 5 * fs::DIRENT_SIZE as u32;
+    fs.update_inode(d, &dir).unwrap();
+
+    let image = Image::new("unreadable-directory", &fs);
+    let (code, output) = image.fsck(false);
+    assert_eq!(code, UNCORRECTED, "{}", output);
+    assert!(output.contains(&format!("Directory {} can't be read", d)), "{}", output);
+
+    assert_eq!(image.fsck(true).0, REPAIRED);
+    let (code, output) = image.fsck(false);
+    assert_eq!(code, CLEAN, "{}", output);
+}
+
+#[test]
+fn repaired_directory_sizes_are_whole_entries() {
+    let mut fs = new_fs();
+    let d = fs.mkdir(b"/d").unwrap();
+    let mut dir = fs.read_inode(fs::ROOT_DEV, d).unwrap();
+    dir.size -= 1;
+    fs.update_inode(d, &dir).unwrap();
+
+    let image = Image::new("partial-entry", &fs);
+    assert_eq!(image.fsck(false).0, UNCORRECTED);
+    assert_eq!(image.fsck(true).0, REPAIRED);
+    let (code, output) = image.fsck(false);
+    assert_eq!(code, CLEAN, "{}", output);
+}
@@ -0,0 +1,1208 @@
+extern crate simple_fs as fs;
+
+use fs::{BlockAllocator, BufferCache, Disk, FileSystem, FsError, Inode, InodeType, RamDisk};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const NBLOCKS: u32 = 1000;
+const NINODES: u32 = 64;
+
+// a freshly formatted and mounted file system on a RamDisk
+fn format(allocator: BlockAllocator) -> FileSystem<RamDisk> {
+    let mut fs = FileSystem::new(RamDisk::new(NBLOCKS));
+    fs.format(NBLOCKS, NINODES, allocator).unwrap();
+    FileSystem::mount(fs.disk).unwrap()
+}
+
+fn new_fs() -> FileSystem<RamDisk> {
+    format(BlockAllocator::FreeList)
+}
+
+fn new_bitmap_fs() -> FileSystem<RamDisk> {
+    format(BlockAllocator::Bitmap)
+}
+
+fn new_inode(fs: &mut FileSystem<RamDisk>, type_: InodeType) -> (u32, Inode) {
+    let inode = fs.new_inode(type_, fs::ROOT_DEV);
+    (fs.alloc_inode(fs::ROOT_DEV, inode).unwrap(), inode)
+}
+
+// a clock that moves forward a second every time it's read
+static CLOCK: AtomicUsize = AtomicUsize::new(0);
+
+fn ticking_clock() -> u32 {
+    1000000 + CLOCK.fetch_add(1, Ordering::SeqCst) as u32
+}
+
+fn new_clocked_fs() -> FileSystem<RamDisk> {
+    let mut fs = new_fs();
+    fs.set_clock(ticking_clock);
+    fs
+}
+
+// walk the free list, returning every block on it (including the list blocks themselves)
+fn free_blocks(fs: &FileSystem<RamDisk>) -> Vec<u32> {
+    let mut free = vec![];
+    let mut head = fs.superblock(fs::ROOT_DEV).unwrap().freelist_start;
+    let mut buf = [0; fs::BLOCKSIZE];
+    while head != fs::UNUSED_BLOCKADDR {
+        free.push(head);
+        fs.disk.read(&mut buf, fs::ROOT_DEV, head).unwrap();
+        for i in 1..fs::INDIRECT_PER_BLOCK {
+            let addr = fs::block_addr(&buf, i);
+            if addr != fs::UNUSED_BLOCKADDR {
+                free.push(addr);
+            }
+        }
+        head = fs::block_addr(&buf, 0);
+    }
+    free
+}
+
+// the blocks the bitmap says are free
+fn bitmap_free_blocks(fs: &FileSystem<RamDisk>) -> Vec<u32> {
+    let sb = fs.superblock(fs::ROOT_DEV).unwrap();
+    let mut free = vec![];
+    let mut buf = [0; fs::BLOCKSIZE];
+    for addr in 0..sb.nblocks {
+        if addr % fs::BITS_PER_BLOCK == 0 {
+            let bitmap_block = sb.bitmap_start + addr / fs::BITS_PER_BLOCK;
+            fs.disk.read(&mut buf, fs::ROOT_DEV, bitmap_block).unwrap();
+        }
+        if !fs::bit_is_set(&buf, addr % fs::BITS_PER_BLOCK) {
+            free.push(addr);
+        }
+    }
+    free
+}
+
+// the disk blocks holding each block of a file, in order
+fn file_blocks(fs: &FileSystem<RamDisk>, inode: &Inode) -> Vec<u32> {
+    let mut data = vec![];
+    fs.inode_blocks(inode, |addr, usage| {
+            if let fs::BlockUse::Data(bn) = usage {
+                data.push((bn, addr));
+            }
+            true
+        })
+        .unwrap();
+    data.sort();
+    data.into_iter().map(|(_, addr)| addr).collect()
+}
+
+// deterministic filler, so mismatched offsets show up as mismatched bytes
+fn pattern(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * 7 + seed) % 251) as u8).collect()
+}
+
+#[test]
+fn format_and_mount() {
+    let fs = new_fs();
+    let sb = fs.superblock(fs::ROOT_DEV).unwrap();
+    assert_eq!(sb.magic, fs::FSMAGIC);
+    assert_eq!(sb.nblocks, NBLOCKS);
+    assert_eq!(sb.ninodes, NINODES);
+
+    let root = fs.read_inode(fs::ROOT_DEV, fs::ROOT_INUM).unwrap();
+    assert_eq!(root.type_, InodeType::Directory);
+    assert_eq!(fs.dir_lookup(&root, b"."), Ok((fs::ROOT_INUM, 0)));
+    assert_eq!(fs.dir_lookup(&root, b".."), Ok((fs::ROOT_INUM, fs::DIRENT_SIZE)));
+    assert_eq!(fs.namex(b"/"), Ok(fs::ROOT_INUM));
+}
+
+#[test]
+fn mount_rejects_blank_disk() {
+    match FileSystem::mount(RamDisk::new(NBLOCKS)) {
+        Err(e) => assert_eq!(e, FsError::BadMagic(0)),
+        Ok(_) => panic!("mounted a blank disk"),
+    }
+}
+
+#[test]
+fn format_rejects_tiny_disk() {
+    let mut fs = FileSystem::new(RamDisk::new(40));
+    assert_eq!(fs.format(40, NINODES, BlockAllocator::FreeList),
+               Err(FsError::BadGeometry));
+}
+
+#[test]
+fn mount_rejects_ilist_inside_the_log() {
+    let fs = new_fs();
+    let mut sb = fs.superblock(fs::ROOT_DEV).unwrap();
+    sb.inode_start = sb.log_start + sb.nlog;
+    let mut buf = [0; fs::BLOCKSIZE];
+    sb.encode(&mut buf);
+    let mut disk = fs.disk;
+    disk.write(&buf, fs::ROOT_DEV, fs::SUPERBLOCK_ADDR).unwrap();
+    match FileSystem::mount(disk) {
+        Err(e) => assert_eq!(e, FsError::BadGeometry),
+        Ok(_) => panic!("mounted an ilist that overlaps the log"),
+    }
+}
+
+#[test]
+fn free_list_holds_every_unused_data_block() {
+    let fs = new_fs();
+    let sb = fs.superblock(fs::ROOT_DEV).unwrap();
+    let root = fs.read_inode(fs::ROOT_DEV, fs::ROOT_INUM).unwrap();
+
+    let mut free = free_blocks(&fs);
+    free.sort();
+    free.dedup();
+    // the root directory's two entries take up the only blocks in use
+    let used = (root.size as usize + fs::BLOCKSIZE - 1) / fs::BLOCKSIZE;
+    assert_eq!(free.len(), (NBLOCKS - sb.data_start()) as usize - used);
+    assert!(free.iter().all(|&b| b >= sb.data_start() && b < NBLOCKS));
+}
+
+#[test]
+fn alloc_and_free_blocks() {
+    let mut fs = new_fs();
+    let sb = fs.superblock(fs::ROOT_DEV).unwrap();
+    let nfree = free_blocks(&fs).len();
+
+    let mut allocated = vec![];
+    for i in 0..10 {
+        let b = fs.alloc_block(fs::ROOT_DEV).unwrap();
+        assert!(b >= sb.data_start() && b < NBLOCKS, "allocated block {}", b);
+        assert!(!allocated.contains(&b), "allocated block {} twice", b);
+        assert!(!free_blocks(&fs).contains(&b));
+        assert_eq!(free_blocks(&fs).len(), nfree - i - 1);
+        allocated.push(b);
+    }
+
+    for (i, &b) in allocated.iter().enumerate() {
+        fs.free_block(fs::ROOT_DEV, b).unwrap();
+        assert!(free_blocks(&fs).contains(&b));
+        assert_eq!(free_blocks(&fs).len(), nfree - allocated.len() + i + 1);
+    }
+}
+
+#[test]
+fn alloc_block_zeroes_the_block() {
+    let mut fs = new_fs();
+    let b = fs.alloc_block(fs::ROOT_DEV).unwrap();
+    fs.disk.write(&[0xaa; fs::BLOCKSIZE], fs::ROOT_DEV, b).unwrap();
+    fs.free_block(fs::ROOT_DEV, b).unwrap();
+
+    // keep allocating until the dirty block comes back around
+    loop {
+        let next = fs.alloc_block(fs::ROOT_DEV).unwrap();
+        if next == b {
+            break;
+        }
+    }
+    let mut buf = [0xff; fs::BLOCKSIZE];
+    fs.disk.read(&mut buf, fs::ROOT_DEV, b).unwrap();
+    assert!(buf.iter().all(|&x| x == 0));
+}
+
+#[test]
+fn exhaust_and_refill_free_list() {
+    let mut fs = new_fs();
+    let nfree = free_blocks(&fs).len();
+
+    let mut allocated = vec![];
+    loop {
+        match fs.alloc_block(fs::ROOT_DEV) {
+            Ok(b) => allocated.push(b),
+            Err(e) => {
+                assert_eq!(e, FsError::ExhaustedBlocks);
+                break;
+            }
+        }
+    }
+    assert_eq!(allocated.len(), nfree);
+    assert!(free_blocks(&fs).is_empty());
+
+    let mut sorted = allocated.clone();
+    sorted.sort();
+    sorted.dedup();
+    assert_eq!(sorted.len(), allocated.len());
+
+    for &b in &allocated {
+        fs.free_block(fs::ROOT_DEV, b).unwrap();
+    }
+    let mut free = free_blocks(&fs);
+    free.sort();
+    assert_eq!(free, sorted);
+    assert!(fs.alloc_block(fs::ROOT_DEV).is_ok());
+}
+
+#[test]
+fn dir_add_and_lookup() {
+    let mut fs = new_fs();
+    let (inum, mut dir) = new_inode(&mut fs, InodeType::Directory);
+    fs.dir_add(&mut dir, b".", inum).unwrap();
+    fs.dir_add(&mut dir, b"..", fs::ROOT_INUM).unwrap();
+
+    let names: Vec<Vec<u8>> = (0..5).map(|i| format!("file{}", i).into_bytes()).collect();
+    for (i, name) in names.iter().enumerate() {
+        fs.dir_add(&mut dir, name, 100 + i as u32).unwrap();
+    }
+    assert_eq!(dir.size as usize, 7 * fs::DIRENT_SIZE);
+
+    for (i, name) in names.iter().enumerate() {
+        assert_eq!(fs.dir_lookup(&dir, name),
+                   Ok((100 + i as u32, (i + 2) * fs::DIRENT_SIZE)));
+    }
+    assert_eq!(fs.dir_lookup(&dir, b".."), Ok((fs::ROOT_INUM, fs::DIRENT_SIZE)));
+
+    // a name has to match exactly, not just share a prefix
+    assert_eq!(fs.dir_lookup(&dir, b"file"), Err(FsError::NotFound));
+    assert_eq!(fs.dir_lookup(&dir, b"file00"), Err(FsError::NotFound));
+    assert_eq!(fs.dir_lookup(&dir, b"nope"), Err(FsError::NotFound));
+
+    assert_eq!(fs.dir_add(&mut dir, b"file3", 1), Err(FsError::EntryExists));
+    assert_eq!(fs.dir_add(&mut dir, &[b'x'; fs::DIRNAME_SIZE + 1], 1),
+               Err(FsError::NameTooLong));
+}
+
+#[test]
+fn dir_add_uses_full_length_names() {
+    let mut fs = new_fs();
+    let (_, mut dir) = new_inode(&mut fs, InodeType::Directory);
+    let long = [b'n'; fs::DIRNAME_SIZE];
+    fs.dir_add(&mut dir, &long, 7).unwrap();
+    assert_eq!(fs.dir_lookup(&dir, &long), Ok((7, 0)));
+    assert_eq!(fs.dir_lookup(&dir, &long[..fs::DIRNAME_SIZE - 1]),
+               Err(FsError::NotFound));
+}
+
+#[test]
+fn dir_remove_frees_the_slot() {
+    let mut fs = new_fs();
+    let (_, mut dir) = new_inode(&mut fs, InodeType::Directory);
+    fs.dir_add(&mut dir, b"a", 1).unwrap();
+    fs.dir_add(&mut dir, b"b", 2).unwrap();
+    fs.dir_add(&mut dir, b"c", 3).unwrap();
+
+    let (_, offset) = fs.dir_lookup(&dir, b"b").unwrap();
+    fs.dir_remove(&mut dir, offset as u32).unwrap();
+    assert_eq!(fs.dir_lookup(&dir, b"b"), Err(FsError::NotFound));
+    assert_eq!(fs.dir_lookup(&dir, b"c"), Ok((3, 2 * fs::DIRENT_SIZE)));
+
+    // the next entry goes in the hole instead of growing the directory
+    let size = dir.size;
+    fs.dir_add(&mut dir, b"d", 4).unwrap();
+    assert_eq!(dir.size, size);
+    assert_eq!(fs.dir_lookup(&dir, b"d"), Ok((4, offset)));
+}
+
+#[test]
+fn dir_entries_survive_remount() {
+    let mut fs = new_fs();
+    let (inum, mut dir) = new_inode(&mut fs, InodeType::Directory);
+    fs.dir_add(&mut dir, b"kept", 5).unwrap();
+    fs.update_inode(inum, &dir).unwrap();
+
+    let fs = FileSystem::mount(fs.disk).unwrap();
+    let dir = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
+    assert_eq!(fs.dir_lookup(&dir, b"kept"), Ok((5, 0)));
+}
+
+#[test]
+fn unaligned_writes_across_blocks() {
+    let mut fs = new_fs();
+    let (_, mut inode) = new_inode(&mut fs, InodeType::File);
+    let mut model = pattern(3 * fs::BLOCKSIZE + 100, 0);
+    assert_eq!(fs.write(&mut inode, &model, 0), Ok(model.len()));
+    assert_eq!(inode.size as usize, model.len());
+
+    // overwrite ranges that start and end in the middle of blocks, and straddle one or more
+    // block boundaries
+    let patches = [(500, 24), (300, 700), (1, fs::BLOCKSIZE * 2), (1023, 2), (1500, 50)];
+    for (seed, &(offset, len)) in patches.iter().enumerate() {
+        let data = pattern(len, seed + 1);
+        assert_eq!(fs.write(&mut inode, &data, offset as u32), Ok(len));
+        model[offset..offset + len].copy_from_slice(&data);
+    }
+    assert_eq!(inode.size as usize, model.len());
+
+    let mut whole = vec![0; model.len()];
+    assert_eq!(fs.read(&inode, &mut whole, 0), Ok(model.len()));
+    assert!(whole == model);
+}
+
+#[test]
+fn unaligned_reads_across_blocks() {
+    let mut fs = new_fs();
+    let (_, mut inode) = new_inode(&mut fs, InodeType::File);
+    let model = pattern(4 * fs::BLOCKSIZE, 3);
+    fs.write(&mut inode, &model, 0).unwrap();
+
+    for &(offset, len) in &[(0, 1), (511, 2), (100, 1000), (513, 511), (1, 2046), (2047, 1)] {
+        let mut buf = vec![0; len];
+        assert_eq!(fs.read(&inode, &mut buf, offset as u32), Ok(len));
+        assert!(buf[..] == model[offset..offset + len],
+                "read of {} bytes at {} differs",
+                len,
+                offset);
+    }
+}
+
+#[test]
+fn append_grows_the_file() {
+    let mut fs = new_fs();
+    let (_, mut inode) = new_inode(&mut fs, InodeType::File);
+    let mut model = vec![];
+    for (seed, &len) in [10, 600, 1, 511, 1025].iter().enumerate() {
+        let data = pattern(len, seed);
+        let offset = inode.size;
+        assert_eq!(fs.write(&mut inode, &data, offset), Ok(len));
+        model.extend_from_slice(&data);
+        assert_eq!(inode.size as usize, model.len());
+    }
+
+    let mut buf = vec![0; model.len()];
+    fs.read(&inode, &mut buf, 0).unwrap();
+    assert!(buf == model);
+}
+
+#[test]
+fn writes_across_the_indirect_boundary() {
+    let mut fs = new_fs();
+    let (inum, mut inode) = new_inode(&mut fs, InodeType::File);
+    let direct = fs::NDIRECT * fs::BLOCKSIZE;
+    let mut model = pattern(direct + 3 * fs::BLOCKSIZE, 5);
+    fs.write(&mut inode, &model, 0).unwrap();
+    assert!(inode.indirect != fs::UNUSED_BLOCKADDR);
+
+    let data = pattern(fs::BLOCKSIZE, 9);
+    let offset = direct - 200;
+    fs.write(&mut inode, &data, offset as u32).unwrap();
+    model[offset..offset + data.len()].copy_from_slice(&data);
+    fs.update_inode(inum, &inode).unwrap();
+
+    let inode = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
+    let mut buf = vec![0; 2 * fs::BLOCKSIZE];
+    let start = direct - fs::BLOCKSIZE;
+    assert_eq!(fs.read(&inode, &mut buf, start as u32), Ok(buf.len()));
+    assert!(buf[..] == model[start..start + buf.len()]);
+}
+
+#[test]
+fn write_too_large() {
+    let mut fs = new_fs();
+    let (_, mut inode) = new_inode(&mut fs, InodeType::File);
+    fs.write(&mut inode, &[1; 10], 0).unwrap();
+
+    // writes can't leave a hole past the end of the file
+    assert_eq!(fs.write(&mut inode, &[1], 11), Err(FsError::WriteTooLarge));
+    // or wrap around the end of the address space
+    assert_eq!(fs.write(&mut inode, &[1; 10], ::std::u32::MAX - 4),
+               Err(FsError::WriteTooLarge));
+    assert_eq!(inode.size, 10);
+
+    // writing right at the end is just an append
+    assert_eq!(fs.write(&mut inode, &[2], 10), Ok(1));
+    assert_eq!(inode.size, 11);
+}
+
+#[test]
+fn write_past_max_file_size() {
+    let mut fs = new_fs();
+    let (_, mut inode) = new_inode(&mut fs, InodeType::File);
+    let max = (fs::MAXFILE * fs::BLOCKSIZE) as u32;
+
+    // pretend the file is already as big as it can get, without allocating anything
+    inode.size = max;
+    assert_eq!(fs.write(&mut inode, &[1], max), Err(FsError::WriteTooLarge));
+    assert_eq!(fs.write(&mut inode, &[1; 2], max - 1), Err(FsError::WriteTooLarge));
+    assert_eq!(inode.size, max);
+}
+
+#[test]
+fn read_too_large() {
+    let mut fs = new_fs();
+    let (_, mut inode) = new_inode(&mut fs, InodeType::File);
+    let model = pattern(1000, 0);
+    fs.write(&mut inode, &model, 0).unwrap();
+
+    let mut buf = [0; 100];
+    assert_eq!(fs.read(&inode, &mut buf, 1001), Err(FsError::ReadTooLarge));
+    assert_eq!(fs.read(&inode, &mut buf, ::std::u32::MAX - 10),
+               Err(FsError::ReadTooLarge));
+
+    // reading at the end gets nothing, and reading across it stops there
+    assert_eq!(fs.read(&inode, &mut buf, 1000), Ok(0));
+    assert_eq!(fs.read(&inode, &mut buf, 950), Ok(50));
+    assert!(buf[..50] == model[950..]);
+}
+
+#[test]
+fn read_and_write_need_a_file_or_directory() {
+    let mut fs = new_fs();
+    let mut inode = Inode { device: fs::ROOT_DEV, ..fs::UNUSED_INODE };
+    let mut buf = [0; 10];
+    assert_eq!(fs.read(&inode, &mut buf, 0), Err(FsError::TypeMismatch));
+    assert_eq!(fs.write(&mut inode, &buf, 0), Err(FsError::TypeMismatch));
+}
+
+#[test]
+fn directory_operations_need_a_directory() {
+    let mut fs = new_fs();
+    let inum = create(&mut fs, b"/file", b"not entries");
+    let mut file = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
+
+    assert_eq!(fs.dir_lookup(&file, b"x"), Err(FsError::NotDir));
+    assert_eq!(fs.dir_is_empty(&file), Err(FsError::NotDir));
+    assert_eq!(fs.dir_add(&mut file, b"x", inum), Err(FsError::NotDir));
+    assert_eq!(fs.dir_remove(&mut file, 0), Err(FsError::NotDir));
+    let items = fs.readdir(&file).collect::<Vec<_>>();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].as_ref().err(), Some(&FsError::NotDir));
+    assert_eq!(contents(&fs, b"/file"), b"not entries");
+}
+
+#[test]
+fn bitmap_format() {
+    let fs = new_bitmap_fs();
+    let sb = fs.superblock(fs::ROOT_DEV).unwrap();
+    assert_eq!(sb.bitmap_start, sb.inode_start + sb.ilist_blocks());
+    assert_eq!(sb.bitmap_blocks(), 1);
+    assert_eq!(sb.freelist_start, fs::UNUSED_BLOCKADDR);
+
+    // everything is free except the metadata and the root directory
+    let root = fs.read_inode(fs::ROOT_DEV, fs::ROOT_INUM).unwrap();
+    let root_blocks = file_blocks(&fs, &root);
+    let free = bitmap_free_blocks(&fs);
+    assert_eq!(free.len(),
+               (NBLOCKS - sb.data_start()) as usize - root_blocks.len());
+    assert!(free.iter().all(|&b| b >= sb.data_start() && !root_blocks.contains(&b)));
+}
+
+#[test]
+fn bitmap_alloc_and_free_blocks() {
+    let mut fs = new_bitmap_fs();
+    let nfree = bitmap_free_blocks(&fs).len();
+
+    let mut allocated = vec![];
+    for i in 0..10 {
+        let b = fs.alloc_block(fs::ROOT_DEV).unwrap();
+        assert!(!allocated.contains(&b), "allocated block {} twice", b);
+        assert!(!bitmap_free_blocks(&fs).contains(&b));
+        assert_eq!(bitmap_free_blocks(&fs).len(), nfree - i - 1);
+        allocated.push(b);
+    }
+
+    for &b in &allocated {
+        fs.free_block(fs::ROOT_DEV, b).unwrap();
+        assert!(bitmap_free_blocks(&fs).contains(&b));
+    }
+    assert_eq!(bitmap_free_blocks(&fs).len(), nfree);
+}
+
+#[test]
+fn bitmap_exhaust() {
+    let mut fs = new_bitmap_fs();
+    let nfree = bitmap_free_blocks(&fs).len();
+    let mut n = 0;
+    while fs.alloc_block(fs::ROOT_DEV).is_ok() {
+        n += 1;
+    }
+    assert_eq!(n, nfree);
+    assert_eq!(fs.alloc_block(fs::ROOT_DEV), Err(FsError::ExhaustedBlocks));
+    assert_eq!(fs.alloc_extent(fs::ROOT_DEV, 2), Err(FsError::ExhaustedBlocks));
+}
+
+#[test]
+fn alloc_extent_is_contiguous() {
+    let mut fs = new_bitmap_fs();
+    let start = fs.alloc_extent(fs::ROOT_DEV, 20).unwrap();
+    let free = bitmap_free_blocks(&fs);
+    assert!((start..start + 20).all(|b| !free.contains(&b)));
+
+    let mut buf = [0xff; fs::BLOCKSIZE];
+    fs.disk.read(&mut buf, fs::ROOT_DEV, start + 19).unwrap();
+    assert!(buf.iter().all(|&x| x == 0));
+}
+
+#[test]
+fn alloc_extent_skips_short_runs() {
+    let mut fs = new_bitmap_fs();
+    let blocks: Vec<u32> = (0..10).map(|_| fs.alloc_block(fs::ROOT_DEV).unwrap()).collect();
+
+    // punch holes of one and three blocks; a four block extent fits in neither
+    fs.free_block(fs::ROOT_DEV, blocks[2]).unwrap();
+    for &b in &blocks[5..8] {
+        fs.free_block(fs::ROOT_DEV, b).unwrap();
+    }
+    let start = fs.alloc_extent(fs::ROOT_DEV, 4).unwrap();
+    assert!(start > blocks[9]);
+
+    // but a three block one fits in the second hole
+    assert_eq!(fs.alloc_extent(fs::ROOT_DEV, 3), Ok(blocks[5]));
+}
+
+#[test]
+fn alloc_extent_needs_a_bitmap() {
+    let mut fs = new_fs();
+    assert_eq!(fs.alloc_extent(fs::ROOT_DEV, 4), Err(FsError::Unsupported));
+}
+
+#[test]
+fn large_files_are_sequential_with_a_bitmap() {
+    let mut fs = new_bitmap_fs();
+    let (_, mut inode) = new_inode(&mut fs, InodeType::File);
+    let model = pattern(40 * fs::BLOCKSIZE + 17, 1);
+    fs.write(&mut inode, &model, 0).unwrap();
+
+    let blocks = file_blocks(&fs, &inode);
+    assert_eq!(blocks.len(), 41);
+    assert!(blocks.windows(2).all(|w| w[1] == w[0] + 1), "{:?}", blocks);
+
+    // growing it again keeps the new part contiguous too
+    let offset = inode.size;
+    fs.write(&mut inode, &model, offset).unwrap();
+    let blocks = file_blocks(&fs, &inode);
+    assert!(blocks[41..].windows(2).all(|w| w[1] == w[0] + 1), "{:?}", blocks);
+
+    let mut buf = vec![0; model.len()];
+    fs.read(&inode, &mut buf, offset).unwrap();
+    assert!(buf == model);
+}
+
+#[test]
+fn writes_fall_back_when_fragmented() {
+    let mut fs = new_bitmap_fs();
+    // take every other free block, so there are no runs longer than one
+    let mut taken = vec![];
+    while let Ok(b) = fs.alloc_block(fs::ROOT_DEV) {
+        taken.push(b);
+    }
+    for i in 0..20 {
+        fs.free_block(fs::ROOT_DEV, taken[2 * i]).unwrap();
+    }
+
+    let (_, mut inode) = new_inode(&mut fs, InodeType::File);
+    let model = pattern(10 * fs::BLOCKSIZE, 2);
+    assert_eq!(fs.write(&mut inode, &model, 0), Ok(model.len()));
+    let mut buf = vec![0; model.len()];
+    fs.read(&inode, &mut buf, 0).unwrap();
+    assert!(buf == model);
+}
+
+#[test]
+fn truncate_returns_blocks_to_the_bitmap() {
+    let mut fs = new_bitmap_fs();
+    let nfree = bitmap_free_blocks(&fs).len();
+    let (inum, mut inode) = new_inode(&mut fs, InodeType::File);
+    fs.write(&mut inode, &pattern(100 * fs::BLOCKSIZE, 3), 0).unwrap();
+    fs.update_inode(inum, &inode).unwrap();
+    assert!(bitmap_free_blocks(&fs).len() < nfree - 100);
+
+    fs.free_inode(fs::ROOT_DEV, inum).unwrap();
+    assert_eq!(bitmap_free_blocks(&fs).len(), nfree);
+}
+
+#[test]
+fn new_inodes_get_defaults() {
+    let fs = new_clocked_fs();
+    let file = fs.new_inode(InodeType::File, fs::ROOT_DEV);
+    assert_eq!(file.mode, fs::DEFAULT_FILE_MODE);
+    assert_eq!(file.nlink, 1);
+    assert!(file.ctime >= 1000000);
+    assert_eq!(file.ctime, file.mtime);
+
+    let dir = fs.new_inode(InodeType::Directory, fs::ROOT_DEV);
+    assert_eq!(dir.mode, fs::DEFAULT_DIR_MODE);
+}
+
+#[test]
+fn inode_fields_survive_remount() {
+    let mut fs = new_clocked_fs();
+    let (inum, mut inode) = new_inode(&mut fs, InodeType::File);
+    inode.mode = 0o4751;
+    inode.nlink = 3;
+    inode.ctime = 0xdeadbeef;
+    inode.mtime = 0x12345678;
+    fs.update_inode(inum, &inode).unwrap();
+
+    let fs = FileSystem::mount(fs.disk).unwrap();
+    let read = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
+    assert_eq!((read.mode, read.nlink, read.ctime, read.mtime),
+               (0o4751, 3, 0xdeadbeef, 0x12345678));
+}
+
+#[test]
+fn writes_update_mtime() {
+    let mut fs = new_clocked_fs();
+    let (_, mut inode) = new_inode(&mut fs, InodeType::File);
+    let created = inode.ctime;
+
+    fs.write(&mut inode, b"hello", 0).unwrap();
+    let written = inode.mtime;
+    assert!(written > created);
+    assert_eq!(inode.ctime, created);
+
+    // an empty write doesn't change anything
+    fs.write(&mut inode, b"", 0).unwrap();
+    assert_eq!(inode.mtime, written);
+
+    fs.truncate(&mut inode, 2).unwrap();
+    assert!(inode.mtime > written);
+    assert_eq!(inode.ctime, created);
+}
+
+#[test]
+fn mkdir_stamps_directories() {
+    let mut fs = new_clocked_fs();
+    let before = fs.read_inode(fs::ROOT_DEV, fs::ROOT_INUM).unwrap();
+    let inum = fs.mkdir(b"/d").unwrap();
+
+    let dir = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
+    assert_eq!(dir.mode, fs::DEFAULT_DIR_MODE);
+    assert_eq!(dir.nlink, 1);
+    assert!(dir.ctime >= 1000000);
+
+    // adding the entry changed the root directory
+    let root = fs.read_inode(fs::ROOT_DEV, fs::ROOT_INUM).unwrap();
+    assert!(root.mtime > before.mtime);
+}
+
+#[test]
+fn unlink_keeps_inodes_with_other_links() {
+    let mut fs = new_fs();
+    let (inum, mut inode) = new_inode(&mut fs, InodeType::File);
+    fs.write(&mut inode, b"shared", 0).unwrap();
+    inode.nlink = 2;
+    fs.update_inode(inum, &inode).unwrap();
+
+    let mut root = fs.read_inode(fs::ROOT_DEV, fs::ROOT_INUM).unwrap();
+    fs.dir_add(&mut root, b"a", inum).unwrap();
+    fs.dir_add(&mut root, b"b", inum).unwrap();
+    fs.update_inode(fs::ROOT_INUM, &root).unwrap();
+
+    fs.unlink(b"/a").unwrap();
+    assert_eq!(fs.namex(b"/a"), Err(FsError::NotFound));
+    let inode = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
+    assert_eq!(inode.type_, InodeType::File);
+    assert_eq!(inode.nlink, 1);
+
+    fs.unlink(b"/b").unwrap();
+    let inode = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
+    assert_eq!(inode.type_, InodeType::Unused);
+}
+
+// create a file at `path` holding `contents`, returning its inode number
+fn create(fs: &mut FileSystem<RamDisk>, path: &[u8], contents: &[u8]) -> u32 {
+    let (inum, mut inode) = new_inode(fs, InodeType::File);
+    fs.write(&mut inode, contents, 0).unwrap();
+    fs.update_inode(inum, &inode).unwrap();
+
+    let (parent_inum, name) = fs.nameiparent(path).unwrap();
+    let mut parent = fs.read_inode(fs::ROOT_DEV, parent_inum).unwrap();
+    fs.dir_add(&mut parent, name, inum).unwrap();
+    fs.update_inode(parent_inum, &parent).unwrap();
+    inum
+}
+
+fn contents(fs: &FileSystem<RamDisk>, path: &[u8]) -> Vec<u8> {
+    let inode = fs.read_inode(fs::ROOT_DEV, fs.namex(path).unwrap()).unwrap();
+    let mut buf = vec![0; inode.size as usize];
+    fs.read(&inode, &mut buf, 0).unwrap();
+    buf
+}
+
+#[test]
+fn link_counts_names() {
+    let mut fs = new_fs();
+    fs.mkdir(b"/d").unwrap();
+    let inum = create(&mut fs, b"/a", b"shared");
+
+    fs.link(b"/a", b"/d/b").unwrap();
+    assert_eq!(fs.namex(b"/d/b"), Ok(inum));
+    assert_eq!(fs.read_inode(fs::ROOT_DEV, inum).unwrap().nlink, 2);
+
+    fs.unlink(b"/a").unwrap();
+    assert_eq!(contents(&fs, b"/d/b"), b"shared");
+    assert_eq!(fs.read_inode(fs::ROOT_DEV, inum).unwrap().nlink, 1);
+}
+
+#[test]
+fn link_refuses_directories_and_existing_names() {
+    let mut fs = new_fs();
+    fs.mkdir(b"/d").unwrap();
+    let inum = create(&mut fs, b"/a", b"");
+    create(&mut fs, b"/b", b"");
+
+    assert_eq!(fs.link(b"/d", b"/e"), Err(FsError::IsDir));
+    assert_eq!(fs.link(b"/a", b"/b"), Err(FsError::EntryExists));
+    assert_eq!(fs.link(b"/missing", b"/c"), Err(FsError::NotFound));
+    assert_eq!(fs.read_inode(fs::ROOT_DEV, inum).unwrap().nlink, 1);
+}
+
+#[test]
+fn rename_within_a_directory() {
+    let mut fs = new_fs();
+    let inum = create(&mut fs, b"/a", b"contents");
+
+    fs.rename(b"/a", b"/b").unwrap();
+    assert_eq!(fs.namex(b"/a"), Err(FsError::NotFound));
+    assert_eq!(fs.namex(b"/b"), Ok(inum));
+
+    // renaming a file onto itself does nothing
+    fs.rename(b"/b", b"/b").unwrap();
+    assert_eq!(contents(&fs, b"/b"), b"contents");
+}
+
+#[test]
+fn rename_across_directories() {
+    let mut fs = new_fs();
+    let d = fs.mkdir(b"/d").unwrap();
+    let e = fs.mkdir(b"/e").unwrap();
+    let inum = create(&mut fs, b"/d/a", b"contents");
+
+    fs.rename(b"/d/a", b"/e/b").unwrap();
+    assert_eq!(fs.namex(b"/d/a"), Err(FsError::NotFound));
+    assert_eq!(fs.namex(b"/e/b"), Ok(inum));
+
+    // a directory that moves has its ".." pointed at its new parent
+    fs.rename(b"/d", b"/e/d").unwrap();
+    assert_eq!(fs.namex(b"/e/d"), Ok(d));
+    assert_eq!(fs.namex(b"/e/d/.."), Ok(e));
+
+    let fs = FileSystem::mount(fs.disk).unwrap();
+    assert_eq!(fs.namex(b"/e/d/../b"), Ok(inum));
+}
+
+#[test]
+fn rename_replaces_the_target() {
+    let mut fs = new_fs();
+    let a = create(&mut fs, b"/a", b"new");
+    let b = create(&mut fs, b"/b", b"old");
+
+    fs.rename(b"/a", b"/b").unwrap();
+    assert_eq!(fs.namex(b"/a"), Err(FsError::NotFound));
+    assert_eq!(fs.namex(b"/b"), Ok(a));
+    assert_eq!(fs.read_inode(fs::ROOT_DEV, b).unwrap().type_, InodeType::Unused);
+
+    // an empty directory can be replaced by another directory
+    fs.mkdir(b"/d").unwrap();
+    let empty = fs.mkdir(b"/e").unwrap();
+    fs.rename(b"/d", b"/e").unwrap();
+    assert_eq!(fs.read_inode(fs::ROOT_DEV, empty).unwrap().type_, InodeType::Unused);
+}
+
+#[test]
+fn rename_errors() {
+    let mut fs = new_fs();
+    fs.mkdir(b"/d").unwrap();
+    fs.mkdir(b"/d/sub").unwrap();
+    fs.mkdir(b"/e").unwrap();
+    create(&mut fs, b"/e/file", b"");
+    create(&mut fs, b"/f", b"");
+
+    assert_eq!(fs.rename(b"/d", b"/d/sub/d"), Err(FsError::InvalidPath));
+    assert_eq!(fs.rename(b"/d", b"/d/d"), Err(FsError::InvalidPath));
+    assert_eq!(fs.rename(b"/d", b"/e"), Err(FsError::DirNotEmpty));
+    assert_eq!(fs.rename(b"/d", b"/f"), Err(FsError::NotDir));
+    assert_eq!(fs.rename(b"/f", b"/d"), Err(FsError::IsDir));
+    assert_eq!(fs.rename(b"/missing", b"/g"), Err(FsError::NotFound));
+
+    // nothing moved
+    assert!(fs.namex(b"/d/sub").is_ok());
+    assert!(fs.namex(b"/e/file").is_ok());
+    assert!(fs.namex(b"/f").is_ok());
+}
+
+#[test]
+fn rename_stops_at_parent_loops() {
+    let mut fs = new_fs();
+    let a = fs.mkdir(b"/a").unwrap();
+    let b = fs.mkdir(b"/a/b").unwrap();
+    fs.mkdir(b"/c").unwrap();
+
+    // point a's ".." at b, so walking up from b never reaches the root
+    let mut dir = fs.read_inode(fs::ROOT_DEV, a).unwrap();
+    let (_, offset) = fs.dir_lookup(&dir, b"..").unwrap();
+    fs.dir_remove(&mut dir, offset as u32).unwrap();
+    fs.dir_add(&mut dir, b"..", b).unwrap();
+    fs.update_inode(a, &dir).unwrap();
+
+    assert_eq!(fs.rename(b"/c", b"/a/b/c"), Err(FsError::BadGeometry));
+}
+
+#[test]
+fn symlinks_are_followed() {
+    let mut fs = new_fs();
+    fs.mkdir(b"/d").unwrap();
+    let inum = create(&mut fs, b"/d/file", b"contents");
+
+    let abs = fs.symlink(b"/d/file", b"/abs").unwrap();
+    fs.symlink(b"file", b"/d/rel").unwrap();
+    fs.symlink(b"../d", b"/d/up").unwrap();
+    assert_eq!(fs.namex(b"/abs"), Ok(inum));
+    assert_eq!(fs.namex(b"/d/rel"), Ok(inum));
+    assert_eq!(fs.namex(b"/d/up/up/rel"), Ok(inum));
+
+    // the link itself, unless it's followed
+    assert_eq!(fs.lookup(b"/abs", false), Ok(abs));
+    assert_eq!(fs.read_inode(fs::ROOT_DEV, abs).unwrap().type_, InodeType::Symlink);
+    let mut buf = [0; 64];
+    let len = fs.readlink(b"/abs", &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"/d/file");
+    assert_eq!(fs.readlink(b"/d/file", &mut buf), Err(FsError::TypeMismatch));
+
+    // removing a link leaves its target alone
+    fs.unlink(b"/abs").unwrap();
+    assert_eq!(fs.read_inode(fs::ROOT_DEV, abs).unwrap().type_, InodeType::Unused);
+    assert_eq!(contents(&fs, b"/d/file"), b"contents");
+}
+
+#[test]
+fn dangling_and_looping_symlinks() {
+    let mut fs = new_fs();
+    fs.symlink(b"/nowhere", b"/dangling").unwrap();
+    assert_eq!(fs.namex(b"/dangling"), Err(FsError::NotFound));
+
+    fs.symlink(b"/b", b"/a").unwrap();
+    fs.symlink(b"/a", b"/b").unwrap();
+    fs.symlink(b"self/x", b"/self").unwrap();
+    assert_eq!(fs.namex(b"/a"), Err(FsError::SymlinkLoop));
+    assert_eq!(fs.namex(b"/self"), Err(FsError::SymlinkLoop));
+
+    // a loop doesn't stop the links themselves from being renamed or removed
+    fs.rename(b"/a", b"/c").unwrap();
+    fs.unlink(b"/b").unwrap();
+    assert_eq!(fs.namex(b"/c"), Err(FsError::NotFound));
+}
+
+#[test]
+fn oversized_symlinks_are_errors() {
+    let mut fs = new_fs();
+    let inum = fs.symlink(b"/target", b"/link").unwrap();
+    let mut link = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
+    link.size = u32::max_value();
+    fs.update_inode(inum, &link).unwrap();
+
+    assert_eq!(fs.namex(b"/link"), Err(FsError::BadGeometry));
+}
+
+#[test]
+fn readdir_skips_free_slots() {
+    let mut fs = new_fs();
+    let d = fs.mkdir(b"/d").unwrap();
+    let a = create(&mut fs, b"/a", b"");
+    create(&mut fs, b"/b", b"");
+    let l = fs.symlink(b"/a", b"/l").unwrap();
+    fs.unlink(b"/b").unwrap();
+
+    let root = fs.read_inode(fs::ROOT_DEV, fs::ROOT_INUM).unwrap();
+    let items = fs.readdir(&root)
+        .map(|item| item.map(|item| (item.name().to_vec(), item.inumber, item.type_)))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(items,
+               vec![(b".".to_vec(), fs::ROOT_INUM, InodeType::Directory),
+                    (b"..".to_vec(), fs::ROOT_INUM, InodeType::Directory),
+                    (b"d".to_vec(), d, InodeType::Directory),
+                    (b"a".to_vec(), a, InodeType::File),
+                    (b"l".to_vec(), l, InodeType::Symlink)]);
+}
+
+#[test]
+fn corrupt_entries_are_errors() {
+    let mut fs = new_fs();
+    let d = fs.mkdir(b"/d").unwrap();
+    let mut dir = fs.read_inode(fs::ROOT_DEV, d).unwrap();
+    fs.dir_add(&mut dir, b"lost", NINODES + 5).unwrap();
+    fs.update_inode(d, &dir).unwrap();
+
+    assert_eq!(fs.namex(b"/d/lost"), Err(FsError::BadGeometry));
+    assert_eq!(fs.read_inode(fs::ROOT_DEV, NINODES + 5).err(),
+               Some(FsError::BadGeometry));
+    assert!(fs.readdir(&dir).any(|item| item.err() == Some(FsError::BadGeometry)));
+}
+
+#[test]
+fn corrupt_directory_sizes_are_errors() {
+    let mut fs = new_fs();
+    let d = fs.mkdir(b"/d").unwrap();
+    let mut dir = fs.read_inode(fs::ROOT_DEV, d).unwrap();
+    dir.size -= 1;
+    fs.update_inode(d, &dir).unwrap();
+
+    assert_eq!(fs.create(b"/d/new"), Err(FsError::BadGeometry));
+}
+
+#[test]
+fn create_makes_empty_files() {
+    let mut fs = new_fs();
+    let inum = fs.create(b"/new").unwrap();
+    assert_eq!(fs.namex(b"/new"), Ok(inum));
+    let inode = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
+    assert_eq!((inode.type_, inode.size, inode.nlink), (InodeType::File, 0, 1));
+
+    assert_eq!(fs.create(b"/new"), Err(FsError::EntryExists));
+    assert_eq!(fs.create(b"/missing/new"), Err(FsError::NotFound));
+}
+
+#[test]
+fn device_files_keep_their_numbers() {
+    let mut fs = new_fs();
+    let inum = fs.mknod(b"/console", 1, 0).unwrap();
+    let fs = FileSystem::mount(fs.disk).unwrap();
+
+    let inode = fs.read_inode(fs::ROOT_DEV, fs.namex(b"/console").unwrap()).unwrap();
+    assert_eq!((inode.type_, inode.major, inode.minor, inode.size),
+               (InodeType::Device, 1, 0, 0));
+    let mut buf = [0; 8];
+    assert_eq!(fs.read(&inode, &mut buf, 0), Err(FsError::TypeMismatch));
+
+    let mut fs = fs;
+    assert_eq!(fs.mknod(b"/console", 1, 1), Err(FsError::EntryExists));
+    fs.unlink(b"/console").unwrap();
+    assert_eq!(fs.read_inode(fs::ROOT_DEV, inum).unwrap().type_, InodeType::Unused);
+}
+
+#[test]
+fn write_file_persists_the_inode() {
+    let mut fs = new_fs();
+    let inum = fs.create(b"/big").unwrap();
+    let mut inode = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
+
+    // big enough to take several transactions
+    let data = pattern(300 * 1024, 5);
+    assert_eq!(fs.write_file(inum, &mut inode, &data, 0), Ok(data.len()));
+    assert_eq!(fs.write_file(inum, &mut inode, b"tail", data.len() as u32), Ok(4));
+
+    let fs = FileSystem::mount(fs.disk).unwrap();
+    let read = contents(&fs, b"/big");
+    assert_eq!(read.len(), data.len() + 4);
+    assert!(read[..data.len()] == data[..]);
+    assert_eq!(&read[data.len()..], b"tail");
+}
+
+#[test]
+fn write_file_past_the_end_fills_the_gap() {
+    let mut fs = new_fs();
+    let inum = create(&mut fs, b"/sparse", b"head");
+    let mut inode = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
+
+    assert_eq!(fs.write_file(inum, &mut inode, b"tail", 3000), Ok(4));
+    let read = contents(&fs, b"/sparse");
+    assert_eq!(read.len(), 3004);
+    assert_eq!(&read[..4], b"head");
+    assert!(read[4..3000].iter().all(|&b| b == 0));
+    assert_eq!(&read[3000..], b"tail");
+
+    // nothing is filled in if the write can't fit anyway
+    let max = (fs::MAXFILE * fs::BLOCKSIZE) as u32;
+    assert_eq!(fs.write_file(inum, &mut inode, b"tail", max - 2),
+               Err(FsError::WriteTooLarge));
+    assert_eq!(fs.read_inode(fs::ROOT_DEV, inum).unwrap().size, 3004);
+}
+
+#[test]
+fn resize_works_in_pieces() {
+    let mut fs = new_fs();
+    let nfree = free_blocks(&fs).len();
+    let inum = fs.create(b"/big").unwrap();
+    let mut inode = fs.read_inode(fs::ROOT_DEV, inum).unwrap();
+    fs.write_file(inum, &mut inode, &pattern(300 * 1024, 6), 0).unwrap();
+
+    fs.resize(inum, &mut inode, 1000).unwrap();
+    assert_eq!(fs.read_inode(fs::ROOT_DEV, inum).unwrap().size, 1000);
+    assert_eq!(contents(&fs, b"/big"), &pattern(300 * 1024, 6)[..1000]);
+
+    fs.resize(inum, &mut inode, 0).unwrap();
+    assert_eq!(free_blocks(&fs).len(), nfree);
+
+    // growing fills with zeros
+    fs.write_file(inum, &mut inode, b"abc", 0).unwrap();
+    fs.resize(inum, &mut inode, 200 * 1024).unwrap();
+    let read = contents(&fs, b"/big");
+    assert_eq!(read.len(), 200 * 1024);
+    assert_eq!(&read[..3], b"abc");
+    assert!(read[3..].iter().all(|&b| b == 0));
+}
+
+// A disk that stops taking writes after a set number of them, as if the machine went down, and
+// counts the reads that reach it.  Clones share the same blocks and counters, so a test can
+// still look at them after handing the disk to a cache.
+#[derive(Clone)]
+struct CrashingDisk {
+    disk: Rc<RefCell<RamDisk>>,
+    reads: Rc<Cell<usize>>,
+    writes_left: Rc<Cell<usize>>,
+}
+
+impl CrashingDisk {
+    fn new(disk: RamDisk) -> CrashingDisk {
+        CrashingDisk {
+            disk: Rc::new(RefCell::new(disk)),
+            reads: Rc::new(Cell::new(0)),
+            writes_left: Rc::new(Cell::new(usize::max_value())),
+        }
+    }
+
+    // how many writes have reached the disk, as long as there's been no crash
+    fn writes(&self) -> usize {
+        usize::max_value() - self.writes_left.get()
+    }
+
+    fn block(&self, sector: u32) -> Vec<u8> {
+        let mut buf = vec![0; fs::BLOCKSIZE];
+        self.disk.borrow().read(&mut buf, fs::ROOT_DEV, sector).unwrap();
+        buf
+    }
+}
+
+impl Disk for CrashingDisk {
+    fn read(&self, buffer: &mut [u8], device: u32, sector: u32) -> Result<(), fs::DiskError> {
+        self.reads.set(self.reads.get() + 1);
+        self.disk.borrow().read(buffer, device, sector)
+    }
+
+    fn write(&mut self, buffer: &[u8], device: u32, sector: u32) -> Result<usize, fs::DiskError> {
+        if self.writes_left.get() == 0 {
+            return Err(fs::DiskError::IoError);
+        }
+        self.writes_left.set(self.writes_left.get() - 1);
+        self.disk.borrow_mut().write(buffer, device, sector)
+    }
+
+    fn sector_size() -> usize {
+        fs::BLOCKSIZE
+    }
+}
+
+// Writes a file with the machine going down after each number of disk writes in turn, through
+// whatever `wrap` puts between the file system and the disk, and checks what's left each time
+fn check_crashes<D, F>(wrap: F)
+    where D: Disk,
+          F: Fn(CrashingDisk) -> D
+{
+    let mut base = new_fs();
+    let inum = base.create(b"/file").unwrap();
+    let data = pattern(40 * 1024, 7);
+
+    let mut crashes = 0;
+    for k in 0.. {
+        let disk = CrashingDisk::new(RamDisk::from_image(base.disk.image().to_vec()));
+        let mut crashing = FileSystem::mount(wrap(disk.clone())).unwrap();
+        disk.writes_left.set(k);
+        let mut inode = crashing.read_inode(fs::ROOT_DEV, inum).unwrap();
+        let finished = crashing.write_file(inum, &mut inode, &data, 0) == Ok(data.len());
+
+        // every data block is either on the free list or in use, and never both
+        let fs = FileSystem::mount(RamDisk::from_image(disk.disk.borrow().image().to_vec()))
+            .unwrap();
+        let sb = fs.superblock(fs::ROOT_DEV).unwrap();
+        let mut blocks = free_blocks(&fs);
+        for &i in &[fs::ROOT_INUM, inum] {
+            let inode = fs.read_inode(fs::ROOT_DEV, i).unwrap();
+            fs.inode_blocks(&inode, |addr, _| {
+                    blocks.push(addr);
+                    true
+                })
+                .unwrap();
+        }
+        blocks.sort();
+        assert_eq!(blocks, (sb.data_start()..NBLOCKS).collect::<Vec<_>>(), "crash after {}", k);
+
+        let size = fs.read_inode(fs::ROOT_DEV, inum).unwrap().size as usize;
+        assert!(contents(&fs, b"/file") == &data[..size], "crash after {}", k);
+        if finished {
+            break;
+        }
+        crashes += 1;
+    }
+    assert!(crashes > 40);
+}
+
+#[test]
+fn crashes_leave_the_free_list_consistent() {
+    check_crashes(|disk| disk);
+}
+
+#[test]
+fn crashes_through_the_buffer_cache_leave_the_free_list_consistent() {
+    check_crashes(BufferCache::new);
+}
+
+#[test]
+fn cache_reads_what_was_written() {
+    let disk = CrashingDisk::new(RamDisk::new(100));
+    let mut cache = BufferCache::new(disk.clone());
+    let block = pattern(fs::BLOCKSIZE, 8);
+    cache.write(&block, fs::ROOT_DEV, 5).unwrap();
+
+    let mut buf = [0; fs::BLOCKSIZE];
+    cache.read(&mut buf, fs::ROOT_DEV, 5).unwrap();
+    assert_eq!(&buf[..], &block[..]);
+    // the write is still only in the cache
+    assert_eq!(disk.writes(), 0);
+    assert!(disk.block(5).iter().all(|&b| b == 0));
+
+    // a partial write keeps the rest of the block
+    disk.disk.borrow_mut().write(&block, fs::ROOT_DEV, 6).unwrap();
+    cache.write(b"abc", fs::ROOT_DEV, 6).unwrap();
+    cache.read(&mut buf, fs::ROOT_DEV, 6).unwrap();
+    assert_eq!(&buf[..3], b"abc");
+    assert_eq!(&buf[3..], &block[3..]);
+}
+
+#[test]
+fn cache_evicts_the_least_recently_used_block() {
+    let disk = CrashingDisk::new(RamDisk::new(100));
+    let cache = BufferCache::new(disk.clone());
+    let mut buf = [0; fs::BLOCKSIZE];
+    for i in 0..fs::NBUF as u32 {
+        cache.read(&mut buf, fs::ROOT_DEV, i).unwrap();
+    }
+    assert_eq!(disk.reads.get(), fs::NBUF);
+
+    // block 0 is used again, so block 1 is the one that makes room for another
+    cache.read(&mut buf, fs::ROOT_DEV, 0).unwrap();
+    cache.read(&mut buf, fs::ROOT_DEV, fs::NBUF as u32).unwrap();
+    assert_eq!(disk.reads.get(), fs::NBUF + 1);
+    cache.read(&mut buf, fs::ROOT_DEV, 0).unwrap();
+    assert_eq!(disk.reads.get(), fs::NBUF + 1);
+    cache.read(&mut buf, fs::ROOT_DEV, 1).unwrap();
+    assert_eq!(disk.reads.get(), fs::NBUF + 2);
+}
+
+#[test]
+fn cache_writes_back_evicted_blocks() {
+    let disk = CrashingDisk::new(RamDisk::new(100));
+    let mut cache = BufferCache::new(disk.clone());
+    let block = pattern(fs::BLOCKSIZE, 9);
+    cache.write(&block, fs::ROOT_DEV, 0).unwrap();
+
+    let mut buf = [0; fs::BLOCKSIZE];
+    for i in 1..fs::NBUF as u32 {
+        cache.read(&mut buf, fs::ROOT_DEV, i).unwrap();
+    }
+    assert_eq!(disk.writes(), 0);
+
+    // only the dirty block is written when clean ones are pushed out
+    for i in fs::NBUF as u32..2 * fs::NBUF as u32 {
+        cache.read(&mut buf, fs::ROOT_DEV, i).unwrap();
+    }
+    assert_eq!(disk.writes(), 1);
+    assert_eq!(disk.block(0), block);
+}
+
+#[test]
+fn cache_sync_writes_back_dirty_blocks() {
+    let disk = CrashingDisk::new(RamDisk::new(100));
+    let mut cache = BufferCache::new(disk.clone());
+    let mut buf = [0; fs::BLOCKSIZE];
+    cache.read(&mut buf, fs::ROOT_DEV, 1).unwrap();
+    cache.write(&pattern(fs::BLOCKSIZE, 10), fs::ROOT_DEV, 2).unwrap();
+    cache.write(&pattern(fs::BLOCKSIZE, 11), fs::ROOT_DEV, 3).unwrap();
+    assert_eq!(disk.writes(), 0);
+
+    cache.sync().unwrap();
+    assert_eq!(disk.writes(), 2);
+    assert_eq!(disk.block(2), pattern(fs::BLOCKSIZE, 10));
+    assert_eq!(disk.block(3), pattern(fs::BLOCKSIZE, 11));
+
+    // the blocks are clean now, so there's nothing more to write
+    cache.sync().unwrap();
+    assert_eq!(disk.writes(), 2);
+}
@@ -1,5 +1,7 @@
 use core::fmt;
 
+use dev;
+use io;
 use spinlock;
 use uart;
 
@@ -43,6 +45,33 @@ impl Console {
 }
 
 
+/// The console as a device, for /dev/console.  Reads take whatever input has already arrived, and
+/// don't wait for more.
+pub struct ConsoleDriver;
+
+impl dev::Driver for ConsoleDriver {
+    fn read(&self, _minor: u16, _offset: usize, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut console = CONSOLE.lock();
+        let mut n = 0;
+        while n < buffer.len() {
+            match console.read_byte() {
+                Some(b) => buffer[n] = b,
+                None => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    fn write(&self, _minor: u16, _offset: usize, buffer: &[u8]) -> io::Result<usize> {
+        let mut console = CONSOLE.lock();
+        for &b in buffer {
+            console.write_byte(b);
+        }
+        Ok(buffer.len())
+    }
+}
+
 impl fmt::Write for Console {
     fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
         for b in s.bytes() {
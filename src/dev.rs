@@ -0,0 +1,223 @@
+use fs;
+use io;
+use file::{FileHandle, OpenFlags, Stat, UnixFileSystem, O_CREATE};
+use alloc::arc::Arc;
+use alloc::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp::min;
+use spinlock::Mutex;
+
+// The device switch.  A device file is an inode with a major and a minor number instead of
+// contents: the major number picks a driver out of the table here, and the minor number tells the
+// driver which of its devices is meant.  Opening a device file on any mounted file system goes to
+// its driver, and the file system below /dev lists the devices the kernel knows about.
+
+pub const CONSOLE_MAJOR: u16 = 1; // the serial console
+pub const IDE_MAJOR: u16 = 2; // a whole IDE drive, by drive number
+pub const NIC_MAJOR: u16 = 3; // the network card's packet counters, as text
+pub const HEAP_MAJOR: u16 = 4; // the kernel heap's statistics, as text
+
+/// A driver for one kind of device
+pub trait Driver: Send + Sync {
+    /// Reads from the device starting `offset` bytes in.  Devices without positions, like the
+    /// console, ignore the offset.
+    fn read(&self, minor: u16, offset: usize, buffer: &mut [u8]) -> io::Result<usize>;
+    fn write(&self, minor: u16, offset: usize, buffer: &[u8]) -> io::Result<usize>;
+    /// How many bytes the device holds, or 0 if it's a stream
+    fn size(&self, _minor: u16) -> usize {
+        0
+    }
+}
+
+lazy_static! {
+    static ref DRIVERS: Mutex<BTreeMap<u16, Arc<Driver>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Makes `driver` handle the device files with major number `major`, replacing any driver that
+/// already did
+pub fn register(major: u16, driver: Arc<Driver>) {
+    DRIVERS.lock().insert(major, driver);
+}
+
+fn driver(major: u16) -> io::Result<Arc<Driver>> {
+    DRIVERS.lock().get(&major).cloned().ok_or(io::Error::NoDevice)
+}
+
+/// Opens the device with the given numbers, which fails if no driver has its major number
+pub fn open(major: u16, minor: u16) -> io::Result<DeviceFile> {
+    Ok(DeviceFile {
+        driver: driver(major)?,
+        minor: minor,
+        offset: 0,
+    })
+}
+
+/// Copies what's at `offset` in `text` into `buffer`, for devices that read as a snapshot of
+/// some text, and returns how many bytes that was
+pub fn read_text(text: &[u8], offset: usize, buffer: &mut [u8]) -> usize {
+    if offset >= text.len() {
+        return 0;
+    }
+    let n = min(buffer.len(), text.len() - offset);
+    buffer[..n].copy_from_slice(&text[offset..offset + n]);
+    n
+}
+
+/// The kernel heap's statistics, for /dev/heap
+pub struct HeapDriver;
+
+impl Driver for HeapDriver {
+    fn read(&self, _minor: u16, offset: usize, buffer: &mut [u8]) -> io::Result<usize> {
+        let text = format!("{}", ::ALLOCATOR.stats());
+        Ok(read_text(text.as_bytes(), offset, buffer))
+    }
+
+    fn write(&self, _minor: u16, _offset: usize, _buffer: &[u8]) -> io::Result<usize> {
+        Err(io::Error::ReadOnly)
+    }
+}
+
+pub struct DeviceFile {
+    driver: Arc<Driver>,
+    minor: u16,
+    offset: usize,
+}
+
+impl FileHandle for DeviceFile {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let n = self.driver.read(self.minor, self.offset, buffer)?;
+        self.offset += n;
+        Ok(n)
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let n = self.driver.write(self.minor, self.offset, buffer)?;
+        self.offset += n;
+        Ok(n)
+    }
+
+    fn truncate(&mut self, _size: usize) -> io::Result<()> {
+        Err(io::Error::Fs(fs::FsError::TypeMismatch))
+    }
+
+    fn seek_absolute(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn size(&self) -> usize {
+        self.driver.size(self.minor)
+    }
+}
+
+const ROOT_INUM: u32 = 0;
+
+struct Node {
+    name: Vec<u8>,
+    major: u16,
+    minor: u16,
+}
+
+/// A flat directory of device files, one per device, meant to be mounted at /dev.  Its inode
+/// numbers are 1 plus the device's position in the list, and the directory itself is 0.
+pub struct DevFs {
+    nodes: Vec<Node>,
+}
+
+impl DevFs {
+    pub fn new() -> DevFs {
+        DevFs { nodes: Vec::new() }
+    }
+
+    /// Lists a device under `name`.  The driver doesn't have to be registered yet.
+    pub fn add(&mut self, name: &[u8], major: u16, minor: u16) {
+        self.nodes.push(Node {
+            name: name.to_vec(),
+            major: major,
+            minor: minor,
+        });
+    }
+
+    fn lookup(&self, path: &[u8]) -> io::Result<u32> {
+        let mut names = path.split(|&c| c == b'/').filter(|&name| !name.is_empty() && name != b".");
+        match (names.next(), names.next()) {
+            (None, _) => Ok(ROOT_INUM),
+            (Some(name), None) if name == b".." => Ok(ROOT_INUM),
+            (Some(name), None) => {
+                self.nodes
+                    .iter()
+                    .position(|node| node.name.as_slice() == name)
+                    .map(|i| i as u32 + 1)
+                    .ok_or(io::Error::Fs(fs::FsError::NotFound))
+            }
+            (Some(_), Some(_)) => Err(io::Error::Fs(fs::FsError::NotDir)),
+        }
+    }
+
+    fn node(&self, inum: u32) -> &Node {
+        &self.nodes[inum as usize - 1]
+    }
+}
+
+impl UnixFileSystem for DevFs {
+    type File = DeviceFile;
+
+    fn open(&self, path: &[u8], flags: OpenFlags) -> io::Result<DeviceFile> {
+        let inum = match self.lookup(path) {
+            Err(io::Error::Fs(fs::FsError::NotFound)) if flags.contains(O_CREATE) => {
+                return Err(io::Error::ReadOnly);
+            }
+            result => result?,
+        };
+        if inum == ROOT_INUM {
+            return Err(io::Error::Fs(fs::FsError::IsDir));
+        }
+        let node = self.node(inum);
+        open(node.major, node.minor)
+    }
+
+    fn stat(&self, path: &[u8]) -> io::Result<Stat> {
+        let inum = self.lookup(path)?;
+        let (type_, mode, major, minor) = if inum == ROOT_INUM {
+            (fs::InodeType::Directory, fs::DEFAULT_DIR_MODE, 0, 0)
+        } else {
+            let node = self.node(inum);
+            (fs::InodeType::Device, fs::DEFAULT_FILE_MODE, node.major, node.minor)
+        };
+        Ok(Stat {
+            inum: inum,
+            type_: type_,
+            mode: mode,
+            nlink: 1,
+            major: major,
+            minor: minor,
+            size: driver(major).map_or(0, |d| d.size(minor)),
+            ctime: 0,
+            mtime: 0,
+        })
+    }
+
+    fn readdir(&self, path: &[u8]) -> io::Result<Vec<fs::DirItem>> {
+        if self.lookup(path)? != ROOT_INUM {
+            return Err(io::Error::Fs(fs::FsError::NotDir));
+        }
+
+        let mut items = Vec::with_capacity(self.nodes.len() + 2);
+        items.push(fs::DirItem::new(ROOT_INUM, fs::InodeType::Directory, b".")?);
+        items.push(fs::DirItem::new(ROOT_INUM, fs::InodeType::Directory, b"..")?);
+        for (i, node) in self.nodes.iter().enumerate() {
+            items.push(fs::DirItem::new(i as u32 + 1, fs::InodeType::Device, &node.name)?);
+        }
+        Ok(items)
+    }
+
+    fn mkdir(&self, _path: &[u8]) -> io::Result<()> {
+        Err(io::Error::ReadOnly)
+    }
+
+    fn unlink(&self, _path: &[u8]) -> io::Result<()> {
+        Err(io::Error::ReadOnly)
+    }
+}
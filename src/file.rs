@@ -1,76 +1,258 @@
 use fs;
-use alloc::rc::Rc;
+use io;
+use rtc;
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use spinlock::Mutex;
+
+bitflags! {
+    /// How `open` treats the file
+    pub struct OpenFlags: u32 {
+        const O_CREATE = 1; // create the file if it doesn't exist
+        const O_TRUNC = 1 << 1; // empty the file when it's opened
+        const O_APPEND = 1 << 2; // every write goes at the end of the file
+    }
+}
 
 pub trait UnixFileSystem {
     type File: FileHandle;
-    fn open(&self, path: &[u8], name: &[u8]) -> Self::File;
+    fn open(&self, path: &[u8], flags: OpenFlags) -> io::Result<Self::File>;
+    fn stat(&self, path: &[u8]) -> io::Result<Stat>;
+    /// The entries of the directory at `path`, including `.` and `..`
+    fn readdir(&self, path: &[u8]) -> io::Result<Vec<fs::DirItem>>;
+    fn mkdir(&self, path: &[u8]) -> io::Result<()>;
+    /// Removes a file, or a directory if it's empty
+    fn unlink(&self, path: &[u8]) -> io::Result<()>;
 }
 
+/// What `stat` reports about a file
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+    pub inum: u32,
+    pub type_: fs::InodeType,
+    pub mode: u16,
+    pub nlink: u16,
+    pub major: u16, // which driver a device file belongs to; 0 for anything else
+    pub minor: u16, // which of that driver's devices it is
+    pub size: usize,
+    pub ctime: u32, // creation time, in seconds since the Unix epoch
+    pub mtime: u32, // last modification time
+}
+
+// the file system is shared by every file opened on it, and any of them can change it
+type SharedFs<T> = Arc<Mutex<fs::FileSystem<fs::BufferCache<T>>>>;
+
 pub struct SimpleFs<T: fs::Disk> {
-    fs: Rc<fs::FileSystem<T>>,
+    fs: SharedFs<T>,
 }
 
 impl<T: fs::Disk> SimpleFs<T> {
-    pub fn new(disk: T) -> Self {
-        SimpleFs { fs: Rc::new(fs::FileSystem::new(disk)) }
+    /// Mounts the file system on a disk, which fails if the disk doesn't hold one
+    pub fn new(disk: T) -> io::Result<Self> {
+        let mut fs = fs::FileSystem::mount(fs::BufferCache::new(disk))?;
+        fs.set_clock(rtc::unix_time);
+        Ok(SimpleFs { fs: Arc::new(Mutex::new(fs)) })
     }
 }
 
 impl<T: fs::Disk> UnixFileSystem for SimpleFs<T> {
     type File = SimpleFile<T>;
 
-    fn open(&self, path: &[u8], name: &[u8]) -> Self::File {
-        let inum = (*self.fs).namex(path, name).unwrap();
-        let inode = (*self.fs).read_inode(fs::ROOT_DEV, inum).unwrap();
-        SimpleFile {
+    fn open(&self, path: &[u8], flags: OpenFlags) -> io::Result<Self::File> {
+        let mut fs = self.fs.lock();
+        let found = fs.namex(path);
+        let inum = match found {
+            Err(fs::FsError::NotFound) if flags.contains(O_CREATE) => fs.create(path)?,
+            result => result?,
+        };
+
+        let mut inode = fs.read_inode(fs::ROOT_DEV, inum)?;
+        if flags.contains(O_TRUNC) {
+            if inode.type_ != fs::InodeType::File {
+                return Err(io::Error::Fs(fs::FsError::IsDir));
+            }
+            fs.resize(inum, &mut inode, 0)?;
+        }
+
+        Ok(SimpleFile {
             inum: inum,
             inode: inode,
             offset: 0,
+            append: flags.contains(O_APPEND),
             fs: self.fs.clone(),
+        })
+    }
+
+    fn stat(&self, path: &[u8]) -> io::Result<Stat> {
+        let fs = self.fs.lock();
+        let inum = fs.namex(path)?;
+        let inode = fs.read_inode(fs::ROOT_DEV, inum)?;
+        Ok(Stat {
+            inum: inum,
+            type_: inode.type_,
+            mode: inode.mode,
+            nlink: inode.nlink,
+            major: inode.major,
+            minor: inode.minor,
+            size: inode.size as usize,
+            ctime: inode.ctime,
+            mtime: inode.mtime,
+        })
+    }
+
+    fn readdir(&self, path: &[u8]) -> io::Result<Vec<fs::DirItem>> {
+        let fs = self.fs.lock();
+        let inum = fs.namex(path)?;
+        let dir = fs.read_inode(fs::ROOT_DEV, inum)?;
+        // readdir yields a NotDir error for anything but a directory
+        let mut items = Vec::new();
+        for item in fs.readdir(&dir) {
+            items.push(item?);
         }
+        Ok(items)
+    }
+
+    fn mkdir(&self, path: &[u8]) -> io::Result<()> {
+        self.fs.lock().mkdir(path)?;
+        Ok(())
+    }
+
+    fn unlink(&self, path: &[u8]) -> io::Result<()> {
+        self.fs.lock().unlink(path)?;
+        Ok(())
     }
 }
 
 pub trait FileHandle {
-    fn read(&mut self, buffer: &mut [u8]);
-    fn write(&mut self, buffer: &mut [u8]);
+    /// Reads from the current offset into `buffer`, and returns how many bytes were read.  That's
+    /// less than the length of `buffer` near the end of the file, and 0 once the offset reaches it.
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
+    /// Writes `buffer` at the current offset, growing the file if it runs past the end, and
+    /// returns how many bytes were written
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize>;
+    /// Cuts the file off at `size` bytes, or pads it out to `size` with zeros
+    fn truncate(&mut self, size: usize) -> io::Result<()>;
     fn seek_absolute(&mut self, offset: usize);
     fn offset(&self) -> usize;
     fn size(&self) -> usize;
 }
 
-pub struct SimpleFile<T: fs::Disk> {
-    inum: u32,
-    inode: fs::Inode,
-    offset: usize,
-    fs: Rc<fs::FileSystem<T>>,
+impl<F: FileHandle + ?Sized> FileHandle for Box<F> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        (**self).read(buffer)
+    }
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        (**self).write(buffer)
+    }
+    fn truncate(&mut self, size: usize) -> io::Result<()> {
+        (**self).truncate(size)
+    }
+    fn seek_absolute(&mut self, offset: usize) {
+        (**self).seek_absolute(offset)
+    }
+    fn offset(&self) -> usize {
+        (**self).offset()
+    }
+    fn size(&self) -> usize {
+        (**self).size()
+    }
 }
 
-impl<T> SimpleFile<T>
-    where T: fs::Disk
-{
-    fn fs(&self) -> &fs::FileSystem<T> {
-        &*self.fs
+// Every open file works with the generic I/O traits, so it can go in a `BufReader` and the like
+impl<F: FileHandle + ?Sized> io::Read for F {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        FileHandle::read(self, buffer)
     }
+}
 
-    fn fs_mut(&mut self) -> &mut fs::FileSystem<T> {
-        Rc::get_mut(&mut self.fs).unwrap()
+impl<F: FileHandle + ?Sized> io::Write for F {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        FileHandle::write(self, buffer)
     }
 }
 
-// TODO: implement better error handling semantics here
+impl<F: FileHandle + ?Sized> io::Seek for F {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<usize> {
+        let (base, delta) = match pos {
+            io::SeekFrom::Start(offset) => {
+                self.seek_absolute(offset);
+                return Ok(offset);
+            }
+            io::SeekFrom::Current(delta) => (self.offset(), delta),
+            io::SeekFrom::End(delta) => (self.size(), delta),
+        };
+        let offset = if delta < 0 {
+            base.checked_sub(delta.wrapping_neg() as usize)
+        } else {
+            base.checked_add(delta as usize)
+        };
+        match offset {
+            Some(offset) => {
+                self.seek_absolute(offset);
+                Ok(offset)
+            }
+            None => Err(io::Error::InvalidSeek),
+        }
+    }
+}
+
+pub struct SimpleFile<T: fs::Disk> {
+    inum: u32,
+    inode: fs::Inode, // as of the last read or write; other handles may have changed it since
+    offset: usize,
+    append: bool,
+    fs: SharedFs<T>,
+}
+
 impl<T: fs::Disk> FileHandle for SimpleFile<T> {
-    fn read(&mut self, buffer: &mut [u8]) {
-        let offset = self.offset as u32;
-        let inode = self.inode;
-        self.fs().read(&inode, buffer, offset).unwrap();
-        self.offset += buffer.len();
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let fs = self.fs.lock();
+        self.inode = fs.read_inode(fs::ROOT_DEV, self.inum)?;
+
+        // seeking past the end is allowed, but there's nothing to read there
+        if self.offset >= self.size() {
+            return Ok(0);
+        }
+        let n = fs.read(&self.inode, buffer, self.offset as u32)?;
+        self.offset += n;
+        Ok(n)
     }
-    fn write(&mut self, _buffer: &mut [u8]) {
-        unimplemented!();
-        // TODO: implement write, taking care to make sure that the changed inode is written back
-        // to the disk
+
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let mut fs = self.fs.lock();
+        self.inode = fs.read_inode(fs::ROOT_DEV, self.inum)?;
+        if self.inode.type_ != fs::InodeType::File {
+            return Err(io::Error::Fs(fs::FsError::IsDir));
+        }
+
+        if self.append {
+            self.offset = self.size();
+        }
+        // an offset past what a file can hold could wrap around as a u32
+        if self.offset > fs::MAXFILE * fs::BLOCKSIZE {
+            return Err(io::Error::Fs(fs::FsError::WriteTooLarge));
+        }
+        // the inode is written back along with the data, so there's nothing left to do on close.
+        // Writing past the end zero-fills the gap first, like tmpfs does
+        let n = fs.write_file(self.inum, &mut self.inode, buffer, self.offset as u32)?;
+        self.offset += n;
+        Ok(n)
+    }
+
+    fn truncate(&mut self, size: usize) -> io::Result<()> {
+        let mut fs = self.fs.lock();
+        self.inode = fs.read_inode(fs::ROOT_DEV, self.inum)?;
+        if self.inode.type_ != fs::InodeType::File {
+            return Err(io::Error::Fs(fs::FsError::IsDir));
+        }
+        if size > fs::MAXFILE * fs::BLOCKSIZE {
+            return Err(io::Error::Fs(fs::FsError::WriteTooLarge));
+        }
+        fs.resize(self.inum, &mut self.inode, size as u32)?;
+        Ok(())
     }
+
     fn seek_absolute(&mut self, offset: usize) {
         self.offset = offset;
     }
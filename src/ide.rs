@@ -1,7 +1,9 @@
 use x86::shared::io;
 use fs;
 use slice_cast;
+use dev;
 use core::cell::Cell;
+use core::cmp::min;
 
 pub struct Ide {
     busy: Cell<bool>,
@@ -14,6 +16,7 @@ pub const IDE_DF: u8 = 0x20;
 pub const IDE_ERR: u8 = 0x01;
 pub const IDE_CMD_READ: u8 = 0x20;
 pub const IDE_CMD_WRITE: u8 = 0x30;
+pub const IDE_CMD_IDENTIFY: u8 = 0xec;
 pub const IDE_CMD_RDMUL: usize = 0xc4;
 pub const IDE_CMD_WRMUL: usize = 0xc5;
 
@@ -35,6 +38,34 @@ impl Ide {
         Ide { busy: Cell::new(false) }
     }
 
+    /// Whether a drive is attached as `device`.  An absent drive never becomes ready, so this
+    /// gives up after polling a while instead of waiting on it.
+    pub fn present(device: u32) -> bool {
+        unsafe {
+            io::outb(0x1f6, 0xe0 | ((device & 0x1) as u8) << 4);
+            (0..1000).any(|_| io::inb(0x1f7) != 0)
+        }
+    }
+
+    /// How many sectors a drive holds, from the IDENTIFY data's count of sectors reachable with
+    /// 28-bit addresses
+    pub fn sectors(&self, device: u32) -> Result<u32, fs::DiskError> {
+        self.wait()?;
+        unsafe {
+            io::outb(0x1f6, 0xe0 | ((device & 0x1) as u8) << 4);
+            io::outb(0x1f7, IDE_CMD_IDENTIFY);
+        }
+        self.wait()?;
+
+        let mut identify = [0u32; SECTOR_SIZE / 4];
+        {
+            let mut as_u32: &mut [u32] = &mut identify;
+            unsafe { io::insl(0x1f0, &mut as_u32) };
+        }
+        // words 60 and 61, low word first
+        Ok(identify[30])
+    }
+
     // we pass a buffer that's larger than 512:
     // truncate after 512?  can't really do anything else
     // shorter: read the last block, overwrite the first N bytes, writeback
@@ -166,3 +197,106 @@ impl Ide {
         }
     }
 }
+
+pub const NDRIVES: usize = 2; // a master and a slave on the primary controller
+pub const BOOT_DRIVE: u32 = 0; // holds the boot block and the kernel
+
+/// Whole IDE drives as devices, for /dev/ide0 and /dev/ide1.  The minor number is the drive
+/// number, and offsets are bytes from the start of the drive, file system and all.  A drive that
+/// a file system is mounted from can only be read here, since writes would go around the file
+/// system's buffer cache and log.  Neither can the boot drive, so that a stray write can't leave
+/// the machine unbootable.
+pub struct IdeDriver {
+    mounted: [bool; NDRIVES],
+    sectors: [u32; NDRIVES], // 0 if the drive isn't there
+}
+
+impl IdeDriver {
+    /// Looks up the sizes of the drives that are attached.  `mounted` lists the drives that file
+    /// systems are mounted from.
+    pub fn new(mounted: &[u32]) -> IdeDriver {
+        let disk = Ide::init();
+        let mut driver = IdeDriver {
+            mounted: [false; NDRIVES],
+            sectors: [0; NDRIVES],
+        };
+        for drive in 0..NDRIVES {
+            if Ide::present(drive as u32) {
+                driver.sectors[drive] = disk.sectors(drive as u32).unwrap_or(0);
+            }
+        }
+        for &drive in mounted {
+            driver.mounted[drive as usize] = true;
+        }
+        driver
+    }
+
+    // the size of a drive in bytes, or 0 if it isn't there
+    fn drive_size(&self, minor: u16) -> usize {
+        self.sectors.get(minor as usize).map_or(0, |&n| n as usize * SECTOR_SIZE)
+    }
+
+    // how many of `len` bytes at `offset` are on the drive
+    fn clamp(&self, minor: u16, offset: usize, len: usize) -> ::io::Result<usize> {
+        match self.drive_size(minor) {
+            0 => Err(::io::Error::NoDevice),
+            size => Ok(min(len, size.saturating_sub(offset))),
+        }
+    }
+}
+
+impl dev::Driver for IdeDriver {
+    fn read(&self, minor: u16, offset: usize, buffer: &mut [u8]) -> ::io::Result<usize> {
+        // reading at the end of the drive is like reading at the end of a file
+        let len = self.clamp(minor, offset, buffer.len())?;
+        let buffer = &mut buffer[..len];
+        let disk = Ide::init();
+        let mut sector = [0; SECTOR_SIZE];
+        let mut n = 0;
+        while n < buffer.len() {
+            let pos = offset + n;
+            disk.read(&mut sector, minor as u32, (pos / SECTOR_SIZE) as u32)?;
+            let start = pos % SECTOR_SIZE;
+            let len = min(SECTOR_SIZE - start, buffer.len() - n);
+            buffer[n..n + len].copy_from_slice(&sector[start..start + len]);
+            n += len;
+        }
+        Ok(n)
+    }
+
+    fn write(&self, minor: u16, offset: usize, buffer: &[u8]) -> ::io::Result<usize> {
+        let len = self.clamp(minor, offset, buffer.len())?;
+        if minor as u32 == BOOT_DRIVE {
+            return Err(::io::Error::ReadOnly);
+        }
+        if self.mounted[minor as usize] {
+            return Err(::io::Error::Busy);
+        }
+        if len == 0 && !buffer.is_empty() {
+            return Err(::io::Error::PastEnd);
+        }
+        let buffer = &buffer[..len];
+
+        let mut disk = Ide::init();
+        let mut sector = [0; SECTOR_SIZE];
+        let mut n = 0;
+        while n < buffer.len() {
+            let pos = offset + n;
+            let sector_num = (pos / SECTOR_SIZE) as u32;
+            let start = pos % SECTOR_SIZE;
+            let len = min(SECTOR_SIZE - start, buffer.len() - n);
+            // only part of the sector changes, so keep the rest of what's there
+            if len < SECTOR_SIZE {
+                disk.read(&mut sector, minor as u32, sector_num)?;
+            }
+            sector[start..start + len].copy_from_slice(&buffer[n..n + len]);
+            disk.write(&sector, minor as u32, sector_num)?;
+            n += len;
+        }
+        Ok(n)
+    }
+
+    fn size(&self, minor: u16) -> usize {
+        self.drive_size(minor)
+    }
+}
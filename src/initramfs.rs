@@ -0,0 +1,313 @@
+use fs;
+use io;
+use file::{FileHandle, OpenFlags, Stat, UnixFileSystem, O_CREATE, O_TRUNC};
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::slice;
+
+// A read-only file system backed by a cpio archive in the "newc" format, which is what
+// `cpio -o -H newc` writes.  The Makefile links one into the kernel image, so the kernel has files
+// to serve without a second disk.  Each member of the archive is a 110-byte header of ASCII hex
+// fields, then its NUL-terminated name, padded out to four bytes, then its contents, padded out to
+// four bytes again.  A member named TRAILER!!! marks the end.  Only regular files and directories
+// are used; anything else in the archive is skipped.
+
+extern "C" {
+    /// The start and end of the archive the Makefile links in with `-b binary`
+    static _binary_initramfs_cpio_start: u8;
+    static _binary_initramfs_cpio_end: u8;
+}
+
+const HEADER_SIZE: usize = 110;
+const MAGIC: &'static [u8] = b"070701";
+const TRAILER: &'static [u8] = b"TRAILER!!!";
+
+// header fields, in order after the magic, each eight hex digits
+const FIELD_MODE: usize = 1;
+const FIELD_MTIME: usize = 5;
+const FIELD_FILESIZE: usize = 6;
+const FIELD_NAMESIZE: usize = 11;
+
+const S_IFMT: u32 = 0o170000; // the file type bits of the mode
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+const ROOT_INUM: u32 = 0;
+
+struct Entry {
+    path: Vec<u8>, // without a leading "/" or "./", so the root is empty
+    parent: u32,
+    type_: fs::InodeType,
+    mode: u16,
+    mtime: u32,
+    data: &'static [u8],
+}
+
+impl Entry {
+    fn name(&self) -> &[u8] {
+        let start = self.path.iter().rposition(|&c| c == b'/').map_or(0, |i| i + 1);
+        &self.path[start..]
+    }
+}
+
+/// The files in a cpio archive.  Inode numbers are indexes into the list of entries, and the
+/// root directory is entry 0.
+pub struct Initramfs {
+    entries: Vec<Entry>,
+}
+
+/// The archive linked into the kernel
+pub fn archive() -> &'static [u8] {
+    unsafe {
+        let start = &_binary_initramfs_cpio_start as *const u8;
+        let end = &_binary_initramfs_cpio_end as *const u8;
+        slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+impl Initramfs {
+    /// Reads the table of contents of an archive.  The contents themselves stay where they are.
+    pub fn new(archive: &'static [u8]) -> io::Result<Initramfs> {
+        let mut initramfs = Initramfs {
+            entries: vec![Entry {
+                              path: Vec::new(),
+                              parent: ROOT_INUM,
+                              type_: fs::InodeType::Directory,
+                              mode: fs::DEFAULT_DIR_MODE,
+                              mtime: 0,
+                              data: &[],
+                          }],
+        };
+
+        let mut pos = 0;
+        loop {
+            let header = archive.get(pos..pos + HEADER_SIZE).ok_or(io::Error::InvalidData)?;
+            if &header[..MAGIC.len()] != MAGIC {
+                return Err(io::Error::InvalidData);
+            }
+            let mode = field(header, FIELD_MODE)?;
+            let mtime = field(header, FIELD_MTIME)?;
+            let filesize = field(header, FIELD_FILESIZE)? as usize;
+            let namesize = field(header, FIELD_NAMESIZE)? as usize;
+
+            // the name size counts the NUL at the end
+            let name_start = pos + HEADER_SIZE;
+            let name = archive.get(name_start..name_start + namesize.saturating_sub(1))
+                .ok_or(io::Error::InvalidData)?;
+            let data_start = align4(name_start + namesize);
+            let data = archive.get(data_start..data_start + filesize)
+                .ok_or(io::Error::InvalidData)?;
+            pos = align4(data_start + filesize);
+
+            if name == TRAILER {
+                break;
+            }
+            let type_ = match mode & S_IFMT {
+                S_IFDIR => fs::InodeType::Directory,
+                S_IFREG => fs::InodeType::File,
+                _ => continue,
+            };
+            initramfs.add(name, type_, (mode & 0o7777) as u16, mtime, data);
+        }
+
+        Ok(initramfs)
+    }
+
+    // Adds an archive member, along with any directories above it that the archive doesn't list
+    // on their own
+    fn add(&mut self,
+           name: &[u8],
+           type_: fs::InodeType,
+           mode: u16,
+           mtime: u32,
+           data: &'static [u8]) {
+        let mut path = Vec::new();
+        let mut parent = ROOT_INUM;
+        let mut components = name.split(|&c| c == b'/')
+            .filter(|&c| !c.is_empty() && c != b".")
+            .peekable();
+
+        while let Some(component) = components.next() {
+            if !path.is_empty() {
+                path.push(b'/');
+            }
+            path.extend_from_slice(component);
+
+            let last = components.peek().is_none();
+            let inum = match self.entries.iter().position(|e| e.path == path) {
+                Some(i) => i,
+                None => {
+                    self.entries.push(Entry {
+                        path: path.clone(),
+                        parent: parent,
+                        type_: fs::InodeType::Directory,
+                        mode: fs::DEFAULT_DIR_MODE,
+                        mtime: mtime,
+                        data: &[],
+                    });
+                    self.entries.len() - 1
+                }
+            };
+
+            if last {
+                let entry = &mut self.entries[inum];
+                entry.type_ = type_;
+                entry.mode = mode;
+                entry.mtime = mtime;
+                entry.data = data;
+            }
+            parent = inum as u32;
+        }
+    }
+
+    fn entry(&self, inum: u32) -> &Entry {
+        &self.entries[inum as usize]
+    }
+
+    // the entries in a directory, not counting "." and ".."
+    fn children(&self, dir: u32) -> Vec<u32> {
+        (1..self.entries.len() as u32).filter(|&i| self.entry(i).parent == dir).collect()
+    }
+
+    fn lookup(&self, path: &[u8]) -> io::Result<u32> {
+        let mut inum = ROOT_INUM;
+        for name in path.split(|&c| c == b'/').filter(|name| !name.is_empty()) {
+            if self.entry(inum).type_ != fs::InodeType::Directory {
+                return Err(io::Error::Fs(fs::FsError::NotDir));
+            }
+            inum = if name == b"." {
+                inum
+            } else if name == b".." {
+                self.entry(inum).parent
+            } else {
+                let found = self.children(inum).into_iter().find(|&i| self.entry(i).name() == name);
+                found.ok_or(io::Error::Fs(fs::FsError::NotFound))?
+            };
+        }
+        Ok(inum)
+    }
+}
+
+impl UnixFileSystem for Initramfs {
+    type File = InitramFile;
+
+    fn open(&self, path: &[u8], flags: OpenFlags) -> io::Result<InitramFile> {
+        let inum = match self.lookup(path) {
+            Err(io::Error::Fs(fs::FsError::NotFound)) if flags.contains(O_CREATE) => {
+                return Err(io::Error::ReadOnly);
+            }
+            result => result?,
+        };
+        if flags.contains(O_TRUNC) {
+            return Err(io::Error::ReadOnly);
+        }
+
+        let entry = self.entry(inum);
+        Ok(InitramFile {
+            type_: entry.type_,
+            data: entry.data,
+            offset: 0,
+        })
+    }
+
+    fn stat(&self, path: &[u8]) -> io::Result<Stat> {
+        let inum = self.lookup(path)?;
+        let entry = self.entry(inum);
+        Ok(Stat {
+            inum: inum,
+            type_: entry.type_,
+            mode: entry.mode,
+            nlink: 1,
+            major: 0,
+            minor: 0,
+            size: entry.data.len(),
+            ctime: entry.mtime, // cpio doesn't keep creation times
+            mtime: entry.mtime,
+        })
+    }
+
+    fn readdir(&self, path: &[u8]) -> io::Result<Vec<fs::DirItem>> {
+        let inum = self.lookup(path)?;
+        let dir = self.entry(inum);
+        if dir.type_ != fs::InodeType::Directory {
+            return Err(io::Error::Fs(fs::FsError::NotDir));
+        }
+
+        let mut items = Vec::new();
+        items.push(fs::DirItem::new(inum, fs::InodeType::Directory, b".")?);
+        items.push(fs::DirItem::new(dir.parent, fs::InodeType::Directory, b"..")?);
+        for child in self.children(inum) {
+            let entry = self.entry(child);
+            items.push(fs::DirItem::new(child, entry.type_, entry.name())?);
+        }
+        Ok(items)
+    }
+
+    fn mkdir(&self, _path: &[u8]) -> io::Result<()> {
+        Err(io::Error::ReadOnly)
+    }
+
+    fn unlink(&self, _path: &[u8]) -> io::Result<()> {
+        Err(io::Error::ReadOnly)
+    }
+}
+
+pub struct InitramFile {
+    type_: fs::InodeType,
+    data: &'static [u8],
+    offset: usize,
+}
+
+impl FileHandle for InitramFile {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.type_ == fs::InodeType::Directory {
+            return Err(io::Error::Fs(fs::FsError::IsDir));
+        }
+        if self.offset >= self.data.len() {
+            return Ok(0);
+        }
+        let n = min(buffer.len(), self.data.len() - self.offset);
+        buffer[..n].copy_from_slice(&self.data[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+
+    fn write(&mut self, _buffer: &[u8]) -> io::Result<usize> {
+        Err(io::Error::ReadOnly)
+    }
+
+    fn truncate(&mut self, _size: usize) -> io::Result<()> {
+        Err(io::Error::ReadOnly)
+    }
+
+    fn seek_absolute(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+}
+
+// one of the eight-digit hex fields of a header
+fn field(header: &[u8], index: usize) -> io::Result<u32> {
+    let start = MAGIC.len() + index * 8;
+    let mut value = 0;
+    for &c in &header[start..start + 8] {
+        let digit = match c {
+            b'0'...b'9' => c - b'0',
+            b'a'...b'f' => c - b'a' + 10,
+            b'A'...b'F' => c - b'A' + 10,
+            _ => return Err(io::Error::InvalidData),
+        };
+        value = value << 4 | digit as u32;
+    }
+    Ok(value)
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
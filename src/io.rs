@@ -0,0 +1,226 @@
+use fs;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::fmt;
+use core::result;
+
+/// Why a file or disk operation failed, for everything in the kernel that does I/O
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Fs(fs::FsError), // the file system refused, like a missing file or a full disk
+    Disk(fs::DiskError), // the hardware underneath it failed
+    NotMounted, // no file system is mounted at or above the path
+    AlreadyMounted, // something is already mounted at that path
+    ReadOnly, // the file system can't be changed
+    InvalidData, // the file system's own data is malformed
+    NoDevice, // no driver handles a device file's major number
+    InvalidSeek, // seeking to before the start of a file
+    WriteZero, // a write made no progress, so it would never finish
+    Busy, // a file system is mounted from the device, so it can't be written around it
+    PastEnd, // the device ends before the offset
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl From<fs::FsError> for Error {
+    fn from(e: fs::FsError) -> Self {
+        match e {
+            // the file system only passes disk errors through, so report them as what they are
+            fs::FsError::DiskFault(e) => Error::Disk(e),
+            e => Error::Fs(e),
+        }
+    }
+}
+
+impl From<fs::DiskError> for Error {
+    fn from(e: fs::DiskError) -> Self {
+        Error::Disk(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Fs(ref e) => write!(f, "file system error: {:?}", e),
+            Error::Disk(ref e) => write!(f, "disk error: {:?}", e),
+            Error::NotMounted => write!(f, "no file system is mounted there"),
+            Error::AlreadyMounted => write!(f, "a file system is already mounted there"),
+            Error::ReadOnly => write!(f, "the file system is read-only"),
+            Error::InvalidData => write!(f, "the file system's data is malformed"),
+            Error::NoDevice => write!(f, "no driver for that device"),
+            Error::InvalidSeek => write!(f, "seek to before the start of the file"),
+            Error::WriteZero => write!(f, "the write made no progress"),
+            Error::Busy => write!(f, "the device has a file system mounted from it"),
+            Error::PastEnd => write!(f, "past the end of the device"),
+        }
+    }
+}
+
+/// Something bytes can be read from, like an open file
+pub trait Read {
+    /// Reads into `buffer` and returns how many bytes were read, which is 0 only at the end
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize>;
+
+    /// Reads everything up to the end onto the end of `buffer`, and returns how many bytes that
+    /// was
+    fn read_to_end(&mut self, buffer: &mut Vec<u8>) -> Result<usize> {
+        let start = buffer.len();
+        let mut chunk = [0; CHUNK_SIZE];
+        loop {
+            match self.read(&mut chunk)? {
+                0 => return Ok(buffer.len() - start),
+                n => buffer.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+}
+
+/// Something bytes can be written to
+pub trait Write {
+    /// Writes some of `buffer` and returns how many bytes were written
+    fn write(&mut self, buffer: &[u8]) -> Result<usize>;
+
+    /// Writes all of `buffer`, however many writes that takes
+    fn write_all(&mut self, mut buffer: &[u8]) -> Result<()> {
+        while !buffer.is_empty() {
+            match self.write(buffer)? {
+                0 => return Err(Error::WriteZero),
+                n => buffer = &buffer[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where `Seek::seek` moves to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeekFrom {
+    Start(usize), // this many bytes from the start
+    Current(isize), // this many bytes from where we are now
+    End(isize), // this many bytes from the end, so usually negative
+}
+
+pub trait Seek {
+    /// Moves the offset the next read or write happens at, and returns the new offset from the
+    /// start.  Moving past the end is allowed; moving before the start isn't.
+    fn seek(&mut self, pos: SeekFrom) -> Result<usize>;
+}
+
+const CHUNK_SIZE: usize = 512; // how much read_to_end and BufReader read at a time
+
+/// Reads from something else in big chunks, so that reading a line or a few bytes at a time
+/// doesn't go to the file system for each one
+pub struct BufReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    pos: usize, // the next unread byte in the buffer
+    filled: usize, // how much of the buffer holds data
+}
+
+impl<R: Read> BufReader<R> {
+    pub fn new(inner: R) -> BufReader<R> {
+        BufReader::with_capacity(CHUNK_SIZE, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: R) -> BufReader<R> {
+        BufReader {
+            inner: inner,
+            buffer: vec![0; capacity],
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    /// The bytes that have been read but not consumed, reading more first if there aren't any.
+    /// Empty only at the end.
+    pub fn fill_buf(&mut self) -> Result<&[u8]> {
+        if self.pos >= self.filled {
+            self.filled = self.inner.read(&mut self.buffer)?;
+            self.pos = 0;
+        }
+        Ok(&self.buffer[self.pos..self.filled])
+    }
+
+    /// Marks `n` bytes from `fill_buf` as used, so they aren't returned again
+    pub fn consume(&mut self, n: usize) {
+        self.pos = min(self.pos + n, self.filled);
+    }
+
+    /// Reads onto the end of `out` up to and including the next `delim`, or up to the end if
+    /// there isn't one, and returns how many bytes were read
+    pub fn read_until(&mut self, delim: u8, out: &mut Vec<u8>) -> Result<usize> {
+        let mut total = 0;
+        loop {
+            let (done, used) = {
+                let available = self.fill_buf()?;
+                match available.iter().position(|&b| b == delim) {
+                    Some(i) => {
+                        out.extend_from_slice(&available[..i + 1]);
+                        (true, i + 1)
+                    }
+                    None => {
+                        out.extend_from_slice(available);
+                        (available.is_empty(), available.len())
+                    }
+                }
+            };
+            self.consume(used);
+            total += used;
+            if done {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// Reads a line, newline included, onto the end of `line`.  Returns 0 at the end.
+    pub fn read_line(&mut self, line: &mut Vec<u8>) -> Result<usize> {
+        self.read_until(b'\n', line)
+    }
+
+    /// The rest of the lines, without their line endings
+    pub fn lines(self) -> Lines<R> {
+        Lines { reader: self }
+    }
+}
+
+impl<R: Read> Read for BufReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        // reads at least as big as the buffer would only be copied through it
+        if self.pos >= self.filled && buffer.len() >= self.buffer.len() {
+            return self.inner.read(buffer);
+        }
+        let n = {
+            let available = self.fill_buf()?;
+            let n = min(buffer.len(), available.len());
+            buffer[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+pub struct Lines<R> {
+    reader: BufReader<R>,
+}
+
+impl<R: Read> Iterator for Lines<R> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>>> {
+        let mut line = Vec::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => {
+                if line.ends_with(b"\n") {
+                    line.pop();
+                    if line.ends_with(b"\r") {
+                        line.pop();
+                    }
+                }
+                Some(Ok(line))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
@@ -41,10 +41,16 @@ mod process;
 mod vm;
 mod traps;
 mod mmu;
+mod io;
 mod file;
+mod vfs;
+mod tmpfs;
+mod initramfs;
+mod dev;
 mod picirq;
 mod uart;
 mod timer;
+mod rtc;
 mod ide;
 mod rtl8139;
 mod logger;
@@ -58,6 +64,8 @@ use service::Service;
 #[global_allocator]
 static ALLOCATOR: kalloc::RangeAlloc = kalloc::RANGE_ALLOC_INIT;
 
+const TMPFS_SIZE: usize = 1024 * 1024; // bytes of file data /tmp can hold
+
 #[no_mangle]
 pub extern "C" fn main() {
     println!("");
@@ -97,6 +105,42 @@ pub extern "C" fn main() {
         *rtl8139::NIC.lock() = rtl8139::Rtl8139::init();
     }
 
+    info!("Mounting the initramfs");
+    let root = initramfs::Initramfs::new(initramfs::archive())
+        .expect("Could not read the initramfs");
+    vfs::mount(b"/", alloc::arc::Arc::new(root)).expect("Could not mount /");
+    let mut mounted_drives = alloc::vec::Vec::new();
+    if ide::Ide::present(fs::ROOT_DEV) {
+        info!("Mounting the IDE disk at /disk");
+        match file::SimpleFs::new(ide::Ide::init()) {
+            Ok(disk) => {
+                vfs::mount(b"/disk", alloc::arc::Arc::new(disk)).expect("Could not mount /disk");
+                mounted_drives.push(fs::ROOT_DEV);
+            }
+            Err(e) => warn!("Could not mount the IDE disk: {}", e),
+        }
+    }
+    let tmp = tmpfs::TmpFs::new(TMPFS_SIZE);
+    vfs::mount(b"/tmp", alloc::arc::Arc::new(tmp)).expect("Could not mount /tmp");
+
+    info!("Registering device drivers");
+    dev::register(dev::CONSOLE_MAJOR, alloc::arc::Arc::new(console::ConsoleDriver));
+    dev::register(dev::IDE_MAJOR,
+                  alloc::arc::Arc::new(ide::IdeDriver::new(&mounted_drives)));
+    dev::register(dev::NIC_MAJOR, alloc::arc::Arc::new(rtl8139::StatsDriver));
+    dev::register(dev::HEAP_MAJOR, alloc::arc::Arc::new(dev::HeapDriver));
+    let mut devices = dev::DevFs::new();
+    devices.add(b"console", dev::CONSOLE_MAJOR, 0);
+    devices.add(b"ide0", dev::IDE_MAJOR, 0);
+    if ide::Ide::present(1) {
+        devices.add(b"ide1", dev::IDE_MAJOR, 1);
+    }
+    if rtl8139::NIC.lock().is_some() {
+        devices.add(b"nic", dev::NIC_MAJOR, 0);
+    }
+    devices.add(b"heap", dev::HEAP_MAJOR, 0);
+    vfs::mount(b"/dev", alloc::arc::Arc::new(devices)).expect("Could not mount /dev");
+
     info!("COFFLOS initialization complete, jumping to user code");
     unsafe { irq::enable() };
     service::UserService::start();
@@ -0,0 +1,145 @@
+use x86::shared::io;
+
+// The MC146818-compatible real-time clock in the CMOS, which keeps the date and time while the
+// machine is off.  Its registers are read by writing the register number to the address port and
+// reading the data port.  We assume it's set to UTC, like QEMU's is by default.
+
+const CMOS_ADDR: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09; // two digits; we assume the 21st century
+const RTC_STATUS_A: u8 = 0x0a;
+const RTC_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATING: u8 = 0x80; // the clock is ticking over, so the registers are in flux
+const STATUS_B_24HOUR: u8 = 0x02; // otherwise hours are 1-12, with the top bit set for PM
+const STATUS_B_BINARY: u8 = 0x04; // otherwise values are binary-coded decimal
+
+const SECONDS_PER_DAY: u32 = 24 * 60 * 60;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u32, // 1-12
+    pub day: u32, // 1-31
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    pub fn from_unix(time: u32) -> DateTime {
+        let (year, month, day) = civil_from_days(time / SECONDS_PER_DAY);
+        let seconds = time % SECONDS_PER_DAY;
+        DateTime {
+            year: year,
+            month: month,
+            day: day,
+            hour: seconds / 3600,
+            minute: seconds % 3600 / 60,
+            second: seconds % 60,
+        }
+    }
+
+    /// Seconds since the Unix epoch
+    pub fn to_unix(&self) -> u32 {
+        days_from_civil(self.year, self.month, self.day) * SECONDS_PER_DAY + self.hour * 3600 +
+        self.minute * 60 + self.second
+    }
+
+    /// Day of the week, with 0 for Sunday
+    pub fn weekday(&self) -> u32 {
+        // the epoch was a Thursday
+        (days_from_civil(self.year, self.month, self.day) + 4) % 7
+    }
+}
+
+/// Reads the date and time from the RTC
+pub fn now() -> DateTime {
+    // read until two reads in a row agree, so we never see the clock halfway through ticking over
+    let mut regs = read_registers();
+    loop {
+        let again = read_registers();
+        if again == regs {
+            break;
+        }
+        regs = again;
+    }
+
+    let status = cmos_read(RTC_STATUS_B);
+    let decode = |x: u8| if status & STATUS_B_BINARY != 0 {
+        x as u32
+    } else {
+        ((x >> 4) * 10 + (x & 0x0f)) as u32
+    };
+
+    let mut hour = decode(regs[2] & 0x7f);
+    if status & STATUS_B_24HOUR == 0 {
+        hour %= 12;
+        if regs[2] & 0x80 != 0 {
+            hour += 12;
+        }
+    }
+
+    DateTime {
+        year: 2000 + decode(regs[5]),
+        month: decode(regs[4]),
+        day: decode(regs[3]),
+        hour: hour,
+        minute: decode(regs[1]),
+        second: decode(regs[0]),
+    }
+}
+
+/// The current time in seconds since the Unix epoch, which is what file system timestamps use
+pub fn unix_time() -> u32 {
+    now().to_unix()
+}
+
+fn cmos_read(reg: u8) -> u8 {
+    unsafe {
+        io::outb(CMOS_ADDR, reg);
+        io::inb(CMOS_DATA)
+    }
+}
+
+// the raw time registers, read once the clock isn't in the middle of an update
+fn read_registers() -> [u8; 6] {
+    while cmos_read(RTC_STATUS_A) & STATUS_A_UPDATING != 0 {}
+    [cmos_read(RTC_SECONDS),
+     cmos_read(RTC_MINUTES),
+     cmos_read(RTC_HOURS),
+     cmos_read(RTC_DAY),
+     cmos_read(RTC_MONTH),
+     cmos_read(RTC_YEAR)]
+}
+
+// Days between the epoch and a date, and back again, from Howard Hinnant's "chrono-Compatible
+// Low-Level Date Algorithms".  Years start in March, so leap days fall at the end of the year.
+fn days_from_civil(year: u32, month: u32, day: u32) -> u32 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400; // [0, 399]
+    let mp = (month + 9) % 12; // March is 0
+    let doy = (153 * mp + 2) / 5 + day - 1; // [0, 365]
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy; // [0, 146096]
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: u32) -> (u32, u32, u32) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -9,6 +9,7 @@ use mem::{VirtAddr, Address};
 use smoltcp::Error;
 use smoltcp::phy::Device;
 use alloc::Vec;
+use dev;
 
 const CONFIG_REG1: u16 = 0x52;
 const CMD_REG: u16 = 0x37;
@@ -36,6 +37,16 @@ pub struct Rtl8139 {
     tx_offset: u8, // which TX buffer we're using
     free_tx_buffers: u8,
     rx_offset: usize, // where in the RX ring buffer we are.  SW counterpart to CAPR
+    stats: Stats,
+}
+
+/// Packets and bytes through the card since it was initialized
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Stats {
+    pub rx_packets: usize,
+    pub rx_bytes: usize,
+    pub tx_packets: usize,
+    pub tx_bytes: usize,
 }
 
 // NB Be aware that the RTL-8139 REALLY likes its buffers to be contiguous physical memory
@@ -64,6 +75,7 @@ impl Rtl8139 {
                 tx_offset: 0,
                 free_tx_buffers: NUM_TX_BUFFERS,
                 rx_offset: 0,
+                stats: Stats::default(),
             };
 
             // Power on the card
@@ -116,6 +128,10 @@ impl Rtl8139 {
         mac
     }
 
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
     fn next_tx_offset(tx_off: u8) -> u8 {
         (tx_off + 1) % NUM_TX_BUFFERS
     }
@@ -140,6 +156,8 @@ impl Rtl8139 {
 
         self.set_tsd(tsd, offset);
         self.tx_offset = Self::next_tx_offset(self.tx_offset);
+        self.stats.tx_packets += 1;
+        self.stats.tx_bytes += size;
 
         // update TX offset to point to next buffer
         //self.tx_offset = Self::next_tx_offset(self.tx_offset);
@@ -180,6 +198,8 @@ impl Rtl8139 {
     fn read(&mut self) -> Option<&[u8]> {
         if !self.rx_empty() && self.get_rx_hdr().contains(RX_OK_) {
             let len = self.get_rx_len() as usize;
+            self.stats.rx_packets += 1;
+            self.stats.rx_bytes += len;
             Some(&self.rx_buffer[self.rx_offset + 4..self.rx_offset + 4 + len])
         } else {
             None
@@ -365,6 +385,40 @@ impl Device for NetworkCard {
     }
 }
 
+/// The card's counters as a device, for /dev/nic.  Reading it gives a few lines of text, as of
+/// when the read happens.
+pub struct StatsDriver;
+
+impl dev::Driver for StatsDriver {
+    fn read(&self, _minor: u16, offset: usize, buffer: &mut [u8]) -> ::io::Result<usize> {
+        let text = match NIC.lock().as_ref() {
+            Some(n) => {
+                let stats = n.stats();
+                let mac = n.mac_address();
+                format!("mac {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\n\
+                         rx_packets {}\nrx_bytes {}\ntx_packets {}\ntx_bytes {}\n",
+                        mac[0],
+                        mac[1],
+                        mac[2],
+                        mac[3],
+                        mac[4],
+                        mac[5],
+                        stats.rx_packets,
+                        stats.rx_bytes,
+                        stats.tx_packets,
+                        stats.tx_bytes)
+            }
+            None => return Err(::io::Error::NoDevice),
+        };
+
+        Ok(dev::read_text(text.as_bytes(), offset, buffer))
+    }
+
+    fn write(&self, _minor: u16, _offset: usize, _buffer: &[u8]) -> ::io::Result<usize> {
+        Err(::io::Error::ReadOnly)
+    }
+}
+
 pub struct EthernetTxBuffer(pub Vec<u8>);
 
 impl AsRef<[u8]> for EthernetTxBuffer {
@@ -1,7 +1,11 @@
 use rtl8139;
 use timer;
-use ide;
-use alloc::borrow::ToOwned;
+use rtc;
+use file;
+use io;
+use vfs;
+use alloc::string::String;
+use alloc::vec::Vec;
 
 pub trait Service {
     fn name() -> &'static str; // service name
@@ -21,27 +25,49 @@ impl Service for UserService {
     fn start() {
         info!("Reading root fs");
 
-        use file;
-        use file::{UnixFileSystem, FileHandle};
-        let fs = file::SimpleFs::new(ide::Ide::init());
-        let mut file = fs.open(b"/", b"README.md");
-
-        let mut buf = vec![0; file.size()];
-        file.read(&mut buf);
-        if let Ok(s) = ::core::str::from_utf8(&buf) {
-            info!("{}", s);
+        match vfs::readdir(b"/") {
+            Ok(items) => {
+                for item in items {
+                    info!("{:>5} {:<9} {}",
+                          item.inumber,
+                          format!("{:?}", item.type_),
+                          String::from_utf8_lossy(item.name()));
+                }
+            }
+            Err(e) => warn!("Could not list /: {}", e),
         }
 
+        match vfs::open(b"/README.md", file::OpenFlags::empty()) {
+            Ok(readme) => {
+                for line in io::BufReader::new(readme).lines() {
+                    match line {
+                        Ok(line) => info!("{}", String::from_utf8_lossy(&line)),
+                        Err(e) => {
+                            warn!("Could not read /README.md: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => warn!("Could not open /README.md: {}", e),
+        }
 
-        use alloc::string::String;
-
-        let mut file = fs.open(b"/", b"index.html");
-        let mut buf = vec![0; file.size()];
-        file.read(&mut buf);
-        let html =
-            String::from_utf8(buf).unwrap().replace("${{VERSION}}", env!("CARGO_PKG_VERSION"));
+        // without the page we can still answer, just not with anything interesting
+        let html = match read_template(b"/index.html") {
+            Ok(html) => html,
+            Err(e) => {
+                warn!("Could not read /index.html: {}", e);
+                String::from("COFFLOS has been up for ${{TIME}}\n")
+            }
+        };
 
-        let header: String = "HTTP/1.1 200 OK\r\n\r\n".to_owned();
+        let header = match vfs::stat(b"/index.html") {
+            Ok(stat) => {
+                format!("HTTP/1.1 200 OK\r\nLast-Modified: {}\r\n\r\n",
+                        http_date(stat.mtime))
+            }
+            Err(_) => String::from("HTTP/1.1 200 OK\r\n\r\n"),
+        };
         let http = header + html.as_str();
 
         loop {
@@ -123,3 +149,33 @@ impl Service for UserService {
         }
     }
 }
+
+// Reads an HTML template a line at a time, filling in the parts that are known at startup
+fn read_template(path: &[u8]) -> io::Result<String> {
+    let mut reader = io::BufReader::new(vfs::open(path, file::OpenFlags::empty())?);
+    let mut page = String::new();
+    let mut line = Vec::new();
+    while reader.read_line(&mut line)? > 0 {
+        page.push_str(&String::from_utf8_lossy(&line)
+            .replace("${{VERSION}}", env!("CARGO_PKG_VERSION")));
+        line.clear();
+    }
+    Ok(page)
+}
+
+// Formats a Unix time the way HTTP headers want it, like "Sun, 06 Nov 1994 08:49:37 GMT"
+fn http_date(time: u32) -> String {
+    const DAYS: [&'static str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug",
+                                        "Sep", "Oct", "Nov", "Dec"];
+
+    let t = rtc::DateTime::from_unix(time);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            DAYS[t.weekday() as usize],
+            t.day,
+            MONTHS[t.month as usize - 1],
+            t.year,
+            t.hour,
+            t.minute,
+            t.second)
+}
@@ -0,0 +1,356 @@
+use fs;
+use io;
+use rtc;
+use file::{FileHandle, OpenFlags, Stat, UnixFileSystem, O_APPEND, O_CREATE, O_TRUNC};
+use alloc::arc::Arc;
+use alloc::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp::min;
+use spinlock::Mutex;
+
+// A file system that lives entirely in the kernel heap, for scratch files that don't need to
+// survive a reboot.  Every file and directory is a node in a table keyed by inode number, and a
+// directory is a list of the names and inode numbers in it.  Inode numbers are never reused, so a
+// handle to a file that has since been unlinked stops finding it rather than finding another one.
+
+const ROOT_INUM: u32 = 0;
+
+enum Contents {
+    File(Vec<u8>),
+    Directory(Vec<(Vec<u8>, u32)>), // (name, inode number), not counting "." and ".."
+}
+
+struct Node {
+    contents: Contents,
+    parent: u32, // the directory this node is in, which ".." names
+    mode: u16,
+    ctime: u32,
+    mtime: u32,
+}
+
+impl Node {
+    fn new(contents: Contents, parent: u32) -> Node {
+        let now = rtc::unix_time();
+        let mode = match contents {
+            Contents::File(_) => fs::DEFAULT_FILE_MODE,
+            Contents::Directory(_) => fs::DEFAULT_DIR_MODE,
+        };
+        Node {
+            contents: contents,
+            parent: parent,
+            mode: mode,
+            ctime: now,
+            mtime: now,
+        }
+    }
+
+    fn type_(&self) -> fs::InodeType {
+        match self.contents {
+            Contents::File(_) => fs::InodeType::File,
+            Contents::Directory(_) => fs::InodeType::Directory,
+        }
+    }
+
+    // bytes of file data; directories don't have any
+    fn size(&self) -> usize {
+        match self.contents {
+            Contents::File(ref data) => data.len(),
+            Contents::Directory(_) => 0,
+        }
+    }
+}
+
+struct Tree {
+    nodes: BTreeMap<u32, Node>,
+    next_inum: u32,
+    used: usize, // bytes of file data, which can't grow past capacity
+    capacity: usize,
+}
+
+pub struct TmpFs {
+    tree: Arc<Mutex<Tree>>,
+}
+
+impl TmpFs {
+    /// An empty file system that can hold up to `capacity` bytes of file data
+    pub fn new(capacity: usize) -> TmpFs {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT_INUM, Node::new(Contents::Directory(Vec::new()), ROOT_INUM));
+        TmpFs {
+            tree: Arc::new(Mutex::new(Tree {
+                nodes: nodes,
+                next_inum: ROOT_INUM + 1,
+                used: 0,
+                capacity: capacity,
+            })),
+        }
+    }
+}
+
+impl UnixFileSystem for TmpFs {
+    type File = TmpFile;
+
+    fn open(&self, path: &[u8], flags: OpenFlags) -> io::Result<TmpFile> {
+        let mut tree = self.tree.lock();
+        let found = tree.lookup(path);
+        let inum = match found {
+            Err(io::Error::Fs(fs::FsError::NotFound)) if flags.contains(O_CREATE) => {
+                tree.add(path, Contents::File(Vec::new()))?
+            }
+            result => result?,
+        };
+
+        if flags.contains(O_TRUNC) {
+            tree.set_len(inum, 0)?;
+        }
+
+        Ok(TmpFile {
+            inum: inum,
+            size: tree.node(inum)?.size(),
+            offset: 0,
+            append: flags.contains(O_APPEND),
+            tree: self.tree.clone(),
+        })
+    }
+
+    fn stat(&self, path: &[u8]) -> io::Result<Stat> {
+        let tree = self.tree.lock();
+        let inum = tree.lookup(path)?;
+        let node = tree.node(inum)?;
+        Ok(Stat {
+            inum: inum,
+            type_: node.type_(),
+            mode: node.mode,
+            nlink: 1,
+            major: 0,
+            minor: 0,
+            size: node.size(),
+            ctime: node.ctime,
+            mtime: node.mtime,
+        })
+    }
+
+    fn readdir(&self, path: &[u8]) -> io::Result<Vec<fs::DirItem>> {
+        let tree = self.tree.lock();
+        let inum = tree.lookup(path)?;
+        let node = tree.node(inum)?;
+        let entries = match node.contents {
+            Contents::Directory(ref entries) => entries,
+            Contents::File(_) => return Err(io::Error::Fs(fs::FsError::NotDir)),
+        };
+
+        let mut items = Vec::with_capacity(entries.len() + 2);
+        items.push(fs::DirItem::new(inum, fs::InodeType::Directory, b".")?);
+        items.push(fs::DirItem::new(node.parent, fs::InodeType::Directory, b"..")?);
+        for &(ref name, child) in entries {
+            items.push(fs::DirItem::new(child, tree.node(child)?.type_(), name)?);
+        }
+        Ok(items)
+    }
+
+    fn mkdir(&self, path: &[u8]) -> io::Result<()> {
+        self.tree.lock().add(path, Contents::Directory(Vec::new()))?;
+        Ok(())
+    }
+
+    fn unlink(&self, path: &[u8]) -> io::Result<()> {
+        self.tree.lock().remove(path)
+    }
+}
+
+impl Tree {
+    fn node(&self, inum: u32) -> io::Result<&Node> {
+        self.nodes.get(&inum).ok_or(io::Error::Fs(fs::FsError::NotFound))
+    }
+
+    fn node_mut(&mut self, inum: u32) -> io::Result<&mut Node> {
+        self.nodes.get_mut(&inum).ok_or(io::Error::Fs(fs::FsError::NotFound))
+    }
+
+    // the inode number of the directory entry `name` in the directory `dir`
+    fn dir_lookup(&self, dir: u32, name: &[u8]) -> io::Result<u32> {
+        let node = self.node(dir)?;
+        match node.contents {
+            Contents::File(_) => Err(io::Error::Fs(fs::FsError::NotDir)),
+            Contents::Directory(_) if name == b"." => Ok(dir),
+            Contents::Directory(_) if name == b".." => Ok(node.parent),
+            Contents::Directory(ref entries) => {
+                entries.iter()
+                    .find(|&&(ref entry, _)| entry.as_slice() == name)
+                    .map(|&(_, inum)| inum)
+                    .ok_or(io::Error::Fs(fs::FsError::NotFound))
+            }
+        }
+    }
+
+    fn lookup(&self, path: &[u8]) -> io::Result<u32> {
+        let mut inum = ROOT_INUM;
+        for name in path.split(|&c| c == b'/').filter(|name| !name.is_empty()) {
+            inum = self.dir_lookup(inum, name)?;
+        }
+        Ok(inum)
+    }
+
+    // splits a path into the inode number of its parent directory and its final component
+    fn lookup_parent<'a>(&self, path: &'a [u8]) -> io::Result<(u32, &'a [u8])> {
+        let end = path.iter().rposition(|&c| c != b'/').map_or(0, |i| i + 1);
+        let start = path[..end].iter().rposition(|&c| c == b'/').map_or(0, |i| i + 1);
+
+        let name = &path[start..end];
+        if name.is_empty() {
+            return Err(io::Error::Fs(fs::FsError::NotFound)); // the root has no parent entry
+        }
+        if name == b"." || name == b".." {
+            return Err(io::Error::Fs(fs::FsError::InvalidPath));
+        }
+        Ok((self.lookup(&path[..start])?, name))
+    }
+
+    // adds a new node at `path`, returning its inode number
+    fn add(&mut self, path: &[u8], contents: Contents) -> io::Result<u32> {
+        let (parent, name) = self.lookup_parent(path)?;
+        if name.len() > fs::DIRNAME_SIZE {
+            return Err(io::Error::Fs(fs::FsError::NameTooLong));
+        }
+        match self.dir_lookup(parent, name) {
+            Err(io::Error::Fs(fs::FsError::NotFound)) => {}
+            Err(e) => return Err(e),
+            Ok(_) => return Err(io::Error::Fs(fs::FsError::EntryExists)),
+        }
+
+        let inum = self.next_inum;
+        self.next_inum += 1;
+        self.nodes.insert(inum, Node::new(contents, parent));
+
+        let dir = self.node_mut(parent)?;
+        if let Contents::Directory(ref mut entries) = dir.contents {
+            entries.push((name.to_vec(), inum));
+        }
+        dir.mtime = rtc::unix_time();
+        Ok(inum)
+    }
+
+    fn remove(&mut self, path: &[u8]) -> io::Result<()> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let inum = self.dir_lookup(parent, name)?;
+        match self.node(inum)?.contents {
+            Contents::Directory(ref entries) if !entries.is_empty() => {
+                return Err(io::Error::Fs(fs::FsError::DirNotEmpty));
+            }
+            _ => {}
+        }
+
+        if let Some(node) = self.nodes.remove(&inum) {
+            self.used -= node.size();
+        }
+        let dir = self.node_mut(parent)?;
+        if let Contents::Directory(ref mut entries) = dir.contents {
+            entries.retain(|&(_, entry)| entry != inum);
+        }
+        dir.mtime = rtc::unix_time();
+        Ok(())
+    }
+
+    // the data of the file `inum`, after checking that it can grow to `len` bytes
+    fn file_data(&mut self, inum: u32, len: usize) -> io::Result<&mut Vec<u8>> {
+        let size = self.node(inum)?.size();
+        if len > size && len - size > self.capacity - self.used {
+            return Err(io::Error::Fs(fs::FsError::ExhaustedBlocks));
+        }
+
+        let node = self.node_mut(inum)?;
+        match node.contents {
+            Contents::File(ref mut data) => {
+                node.mtime = rtc::unix_time();
+                Ok(data)
+            }
+            Contents::Directory(_) => Err(io::Error::Fs(fs::FsError::IsDir)),
+        }
+    }
+
+    fn set_len(&mut self, inum: u32, len: usize) -> io::Result<()> {
+        let old = {
+            let data = self.file_data(inum, len)?;
+            let old = data.len();
+            data.resize(len, 0);
+            old
+        };
+        self.used = self.used + len - old;
+        Ok(())
+    }
+
+    fn write(&mut self, inum: u32, offset: usize, buffer: &[u8]) -> io::Result<()> {
+        let end = offset.checked_add(buffer.len())
+            .ok_or(io::Error::Fs(fs::FsError::WriteTooLarge))?;
+        let old = {
+            let data = self.file_data(inum, end)?;
+            let old = data.len();
+            // writing past the end leaves a gap of zeros
+            if end > old {
+                data.resize(end, 0);
+            }
+            data[offset..end].copy_from_slice(buffer);
+            old
+        };
+        if end > old {
+            self.used += end - old;
+        }
+        Ok(())
+    }
+}
+
+pub struct TmpFile {
+    inum: u32,
+    size: usize, // as of the last read or write; other handles may have changed it since
+    offset: usize,
+    append: bool,
+    tree: Arc<Mutex<Tree>>,
+}
+
+impl FileHandle for TmpFile {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let tree = self.tree.lock();
+        let node = tree.node(self.inum)?;
+        let data = match node.contents {
+            Contents::File(ref data) => data,
+            Contents::Directory(_) => return Err(io::Error::Fs(fs::FsError::IsDir)),
+        };
+        self.size = data.len();
+
+        if self.offset >= data.len() {
+            return Ok(0);
+        }
+        let n = min(buffer.len(), data.len() - self.offset);
+        buffer[..n].copy_from_slice(&data[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let mut tree = self.tree.lock();
+        if self.append {
+            self.offset = tree.node(self.inum)?.size();
+        }
+        tree.write(self.inum, self.offset, buffer)?;
+        self.offset += buffer.len();
+        self.size = tree.node(self.inum)?.size();
+        Ok(buffer.len())
+    }
+
+    fn truncate(&mut self, size: usize) -> io::Result<()> {
+        self.tree.lock().set_len(self.inum, size)?;
+        self.size = size;
+        Ok(())
+    }
+
+    fn seek_absolute(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+}
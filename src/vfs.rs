@@ -0,0 +1,172 @@
+use fs;
+use io;
+use dev;
+use file::{FileHandle, OpenFlags, Stat, UnixFileSystem};
+use alloc::arc::Arc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use spinlock::Mutex;
+
+// The virtual file system: a table of mounted file systems, each of which owns the paths under
+// its mount point.  A path belongs to the file system mounted at its longest matching prefix, so
+// with the initramfs at "/" and another file system at "/tmp", "/tmp/a" is "/a" on the second one
+// and everything else is on the initramfs.  Mount points don't have to exist as directories in the
+// file system underneath them, and ".." never leads out of a mounted file system.
+
+/// The interface every mounted file system has.  Unlike `UnixFileSystem` it hands out boxed
+/// files, so different kinds of file system can sit in the same table; anything that implements
+/// `UnixFileSystem` gets it for free.
+pub trait Vfs: Send + Sync {
+    fn open(&self, path: &[u8], flags: OpenFlags) -> io::Result<Box<FileHandle>>;
+    fn stat(&self, path: &[u8]) -> io::Result<Stat>;
+    fn readdir(&self, path: &[u8]) -> io::Result<Vec<fs::DirItem>>;
+    fn mkdir(&self, path: &[u8]) -> io::Result<()>;
+    fn unlink(&self, path: &[u8]) -> io::Result<()>;
+}
+
+impl<F> Vfs for F
+    where F: UnixFileSystem + Send + Sync,
+          F::File: 'static
+{
+    fn open(&self, path: &[u8], flags: OpenFlags) -> io::Result<Box<FileHandle>> {
+        let file = UnixFileSystem::open(self, path, flags)?;
+        Ok(Box::new(file))
+    }
+
+    fn stat(&self, path: &[u8]) -> io::Result<Stat> {
+        UnixFileSystem::stat(self, path)
+    }
+
+    fn readdir(&self, path: &[u8]) -> io::Result<Vec<fs::DirItem>> {
+        UnixFileSystem::readdir(self, path)
+    }
+
+    fn mkdir(&self, path: &[u8]) -> io::Result<()> {
+        UnixFileSystem::mkdir(self, path)
+    }
+
+    fn unlink(&self, path: &[u8]) -> io::Result<()> {
+        UnixFileSystem::unlink(self, path)
+    }
+}
+
+struct Mount {
+    path: Vec<u8>, // absolute, with no trailing slash unless it's the root
+    fs: Arc<Vfs>,
+}
+
+lazy_static! {
+    static ref MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+}
+
+/// Mounts a file system at `path`, which must be absolute
+pub fn mount(path: &[u8], fs: Arc<Vfs>) -> io::Result<()> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|m| m.path == path) {
+        return Err(io::Error::AlreadyMounted);
+    }
+    mounts.push(Mount {
+        path: path,
+        fs: fs,
+    });
+    Ok(())
+}
+
+/// Removes the file system mounted at `path` from the table, and returns it.  Files that are
+/// already open on it keep working.
+pub fn unmount(path: &[u8]) -> io::Result<Arc<Vfs>> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    match mounts.iter().position(|m| m.path == path) {
+        Some(i) => Ok(mounts.remove(i).fs),
+        None => Err(io::Error::NotMounted),
+    }
+}
+
+/// Opens the file at an absolute path, on whichever file system it belongs to.  Device files go
+/// to their drivers instead, whatever file system they're on.
+pub fn open(path: &[u8], flags: OpenFlags) -> io::Result<Box<FileHandle>> {
+    let (fs, rest) = resolve(path)?;
+    if let Ok(stat) = fs.stat(&rest) {
+        if stat.type_ == fs::InodeType::Device {
+            return Ok(Box::new(dev::open(stat.major, stat.minor)?));
+        }
+    }
+    fs.open(&rest, flags)
+}
+
+pub fn stat(path: &[u8]) -> io::Result<Stat> {
+    let (fs, rest) = resolve(path)?;
+    fs.stat(&rest)
+}
+
+pub fn readdir(path: &[u8]) -> io::Result<Vec<fs::DirItem>> {
+    let (fs, rest) = resolve(path)?;
+    fs.readdir(&rest)
+}
+
+pub fn mkdir(path: &[u8]) -> io::Result<()> {
+    let (fs, rest) = resolve(path)?;
+    fs.mkdir(&rest)
+}
+
+/// Removes a file or an empty directory.  Mount points can't be removed, only unmounted.
+pub fn unlink(path: &[u8]) -> io::Result<()> {
+    let (fs, rest) = resolve(path)?;
+    if rest == b"/" {
+        return Err(io::Error::Fs(fs::FsError::InvalidPath));
+    }
+    fs.unlink(&rest)
+}
+
+// Finds the file system a path belongs to, and the path relative to its root.  The table is only
+// locked long enough to look, so slow disk operations don't hold it.
+fn resolve(path: &[u8]) -> io::Result<(Arc<Vfs>, Vec<u8>)> {
+    let path = normalize(path)?;
+    let mounts = MOUNTS.lock();
+
+    let mut best: Option<&Mount> = None;
+    for m in mounts.iter() {
+        let longer = best.map_or(true, |b| m.path.len() > b.path.len());
+        if longer && is_under(&path, &m.path) {
+            best = Some(m);
+        }
+    }
+
+    match best {
+        Some(m) => {
+            let mut rest = path[m.path.len()..].to_vec();
+            if !rest.starts_with(b"/") {
+                rest.insert(0, b'/');
+            }
+            Ok((m.fs.clone(), rest))
+        }
+        None => Err(io::Error::NotMounted),
+    }
+}
+
+// whether `path` is `mount_point` or somewhere under it.  Both are normalized
+fn is_under(path: &[u8], mount_point: &[u8]) -> bool {
+    mount_point == b"/" ||
+    path.starts_with(mount_point) &&
+    (path.len() == mount_point.len() || path[mount_point.len()] == b'/')
+}
+
+// Collapses repeated slashes and drops a trailing one, so that "//tmp/" and "/tmp" are the same
+// mount point.  Only absolute paths make sense here.
+fn normalize(path: &[u8]) -> io::Result<Vec<u8>> {
+    if !path.starts_with(b"/") {
+        return Err(io::Error::Fs(fs::FsError::InvalidPath));
+    }
+
+    let mut normal = Vec::with_capacity(path.len());
+    for name in path.split(|&c| c == b'/').filter(|name| !name.is_empty()) {
+        normal.push(b'/');
+        normal.extend_from_slice(name);
+    }
+    if normal.is_empty() {
+        normal.push(b'/');
+    }
+    Ok(normal)
+}